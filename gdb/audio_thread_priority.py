@@ -0,0 +1,75 @@
+# GDB support for audio_thread_priority.
+#
+# Load with `source gdb/audio_thread_priority.py`. The program has to be built with the
+# `gdb-support` feature for the `atp-handles` command.
+#
+# - `atp-handles` lists the threads promoted to real-time and not yet demoted, as exported by
+#   `RtPriorityHandle::export_for_gdb_pretty_printer` at promotion time.
+# - `RtPriorityHandleInternal` values are printed with their thread, priority and budget.
+
+import json
+import re
+
+import gdb
+
+
+def _format_handle(handle):
+    lines = []
+    for key, value in handle.items():
+        lines.append("  {} = {}".format(key, "null" if value is None else value))
+    return "\n".join(lines)
+
+
+class AtpHandlesCommand(gdb.Command):
+    """List the threads promoted to real-time by audio_thread_priority."""
+
+    def __init__(self):
+        super(AtpHandlesCommand, self).__init__("atp-handles", gdb.COMMAND_DATA)
+
+    def invoke(self, arg, from_tty):
+        try:
+            output = gdb.parse_and_eval("(char*)atp_gdb_handles()").string()
+        except gdb.error as e:
+            raise gdb.GdbError(
+                "atp_gdb_handles is not available, build with the gdb-support feature: {}".format(e)
+            )
+        handles = json.loads(output)
+        if not handles:
+            print("No real-time thread.")
+            return
+        for handle in handles:
+            print("Thread {}:".format(handle["thread_id"]))
+            print(_format_handle(handle))
+
+
+class RtPriorityHandlePrinter(object):
+    """Print an RtPriorityHandleInternal with its main fields."""
+
+    def __init__(self, value):
+        self.value = value
+
+    def to_string(self):
+        thread_info = self.value["thread_info"]
+        return "RtPriorityHandle {{ thread_id: {}, pid: {}, priority: {}, budget_us: {} }}".format(
+            int(thread_info["thread_id"]),
+            int(thread_info["pid"]),
+            int(self.value["priority"]),
+            int(self.value["budget_us"]),
+        )
+
+    def children(self):
+        for field in ("latency_target_us", "major_faults_at_promotion", "memory_lock_requested"):
+            yield field, self.value[field]
+
+
+def _lookup_printer(value):
+    type_name = value.type.strip_typedefs().tag
+    if type_name is not None and re.match(
+        r"^audio_thread_priority::rt_linux::RtPriorityHandleInternal$", type_name
+    ):
+        return RtPriorityHandlePrinter(value)
+    return None
+
+
+AtpHandlesCommand()
+gdb.pretty_printers.append(_lookup_printer)
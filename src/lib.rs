@@ -47,7 +47,7 @@ pub struct AudioThreadPriorityError {
 
 impl AudioThreadPriorityError {
     cfg_if! {
-        if #[cfg(all(target_os = "linux", feature = "dbus"))] {
+        if #[cfg(target_os = "linux")] {
             fn new_with_inner(message: &str, inner: Box<dyn Error>) -> AudioThreadPriorityError {
                 AudioThreadPriorityError {
                     message: message.into(),
@@ -84,6 +84,12 @@ impl Error for AudioThreadPriorityError {
     }
 }
 
+mod telemetry;
+pub use telemetry::RtTelemetryBuffer;
+
+#[cfg(target_os = "linux")]
+mod rt_linux_direct;
+
 cfg_if! {
     if #[cfg(target_os = "macos")] {
         mod rt_mach;
@@ -93,11 +99,14 @@ cfg_if! {
         extern crate libc;
         use rt_mach::promote_current_thread_to_real_time_internal;
         use rt_mach::demote_current_thread_from_real_time_internal;
+        use rt_mach::demote_current_thread_from_real_time_internal as demote_with_force_internal;
         use rt_mach::RtPriorityHandleInternal;
     } else if #[cfg(target_os = "windows")] {
         mod rt_win;
+        pub use rt_win::WindowsTimerResolutionGuard;
         use rt_win::promote_current_thread_to_real_time_internal;
         use rt_win::demote_current_thread_from_real_time_internal;
+        use rt_win::demote_current_thread_from_real_time_internal as demote_with_force_internal;
         use rt_win::RtPriorityHandleInternal;
     } else if #[cfg(all(target_os = "linux", feature = "dbus"))] {
         mod rt_linux;
@@ -105,18 +114,86 @@ cfg_if! {
         extern crate libc;
         use rt_linux::promote_current_thread_to_real_time_internal;
         use rt_linux::demote_current_thread_from_real_time_internal;
+        use rt_linux::demote_with_force_internal;
         use rt_linux::set_real_time_hard_limit_internal as set_real_time_hard_limit;
         use rt_linux::get_current_thread_info_internal;
         use rt_linux::promote_thread_to_real_time_internal;
+        #[cfg(feature = "pipewire-portal")]
+        use rt_linux::promote_via_pipewire_portal_internal;
         use rt_linux::demote_thread_from_real_time_internal;
         use rt_linux::RtPriorityThreadInfoInternal;
         use rt_linux::RtPriorityHandleInternal;
+        pub use rt_linux::{
+            AdaptiveBudgetHandle, AdaptiveStrategy, AtomicRtPriorityHandle, AudioFormat,
+            CallbackProfile, CpuDmaLatencyGuard, CpuFreqBoostHandle, CpuSet, DbusLatencyStats,
+            DbusPool, DeadlineParams, DryRunResult, ExclusiveCoreHandle, ExtendedSchedulerInfo,
+            FreezeHandle, IoPriorityHandle, IoQos, IoSchedulingClass, IrqPriorityHandle,
+            JitterStats, KernelPreemptionModel, LatencyHistogram, LockedRegion, MemlockStatus,
+            MemorySnapshot, MemQos, MonitorEvent, NetworkQosHandle, OomScoreHandle, PmQosHandle,
+            PolicyViolation, PriorityBand, PriorityCeilingGuard, PromotionMethod, QosClass,
+            QosHandle, RawRtHandle, RtAllocator, RtForkResult, RtkitSession, RtPolicy, RtQos,
+            RtReport, RtTuningGuard, SchedulingPolicy, Semaphore, SuspendedRtHandle,
+            SystemdRtConfig, ThreadPriorityInfo, TimerHandle, UsbTuningHandle, ValidationResult,
+        };
+        pub use rt_linux::MMAP_HANDLE_SIZE;
+        pub use rt_linux::JITTER_SAFETY_MARGIN_US;
+        pub use rt_linux::BATTERY_BUDGET_FRACTION;
+        #[cfg(feature = "numa")]
+        pub use rt_linux::CpuAffinityHandle;
+        #[cfg(feature = "transactions")]
+        pub use rt_linux::Transaction;
+        #[cfg(feature = "fpe-trap")]
+        pub use rt_linux::FpeTrapGuard;
+        #[cfg(feature = "netaudio")]
+        pub use rt_linux::CoalescingHandle;
+        #[cfg(feature = "rdt")]
+        pub use rt_linux::MemoryBandwidthHandle;
+        #[cfg(feature = "cpu-tuning")]
+        pub use rt_linux::TurboBoostHandle;
+        #[cfg(feature = "secure-ipc")]
+        pub use rt_linux::{IpcSecret, IpcToken, IPC_TOKEN_MAX_AGE, IPC_TOKEN_SIZE};
+        #[cfg(feature = "otel")]
+        pub use rt_linux::RtTraceContext;
+        #[cfg(feature = "registry")]
+        pub use rt_linux::RtRegistry;
         #[no_mangle]
         /// Size of a RtPriorityThreadInfo or atp_thread_info struct, for use in FFI.
         pub static ATP_THREAD_INFO_SIZE: usize = std::mem::size_of::<RtPriorityThreadInfo>();
     } else {
         // blanket implementations for Android, Linux Desktop without dbus and others
-        pub struct RtPriorityHandleInternal {}
+        #[derive(Default)]
+        pub struct RtPriorityHandleInternal {
+            #[cfg(target_os = "linux")]
+            direct: Option<rt_linux_direct::DirectPromotion>,
+        }
+        impl RtPriorityHandleInternal {
+            /// Promotion is a no-op on this platform, the thread is still scheduled normally,
+            /// unless it was promoted with `try_promote_without_dbus`.
+            pub fn priority_class(&self) -> PriorityClass {
+                #[cfg(target_os = "linux")]
+                if self.direct.is_some() {
+                    return PriorityClass::Realtime;
+                }
+                PriorityClass::Normal
+            }
+
+            /// Promote the current thread to real-time without going through RTKit, by calling
+            /// `sched_setscheduler` directly with `SCHED_FIFO`. This requires `CAP_SYS_NICE` or a
+            /// high enough `RLIMIT_RTPRIO`. If the promotion isn't permitted, the error message
+            /// contains the `setcap` command to grant `CAP_SYS_NICE` to the executable.
+            ///
+            /// This also sets the timer slack of the thread to 1ns with
+            /// `prctl(PR_SET_TIMERSLACK)`, for more precise wake-ups. Only the buffer size,
+            /// sample-rate and I/O latency of `request` are used.
+            #[cfg(target_os = "linux")]
+            pub fn try_promote_without_dbus(
+                request: &RtPriorityRequest,
+            ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+                Ok(RtPriorityHandleInternal {
+                    direct: Some(rt_linux_direct::promote_current_thread(request)?),
+                })
+            }
+        }
         #[derive(Clone, Copy, PartialEq)]
         pub struct RtPriorityThreadInfoInternal {
             _dummy: u8
@@ -136,21 +213,29 @@ cfg_if! {
                 RtPriorityThreadInfo{_dummy: 0}
             }
         }
-        pub fn promote_current_thread_to_real_time_internal(_: u32, audio_samplerate_hz: u32) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
-            if audio_samplerate_hz == 0 {
+        pub fn promote_current_thread_to_real_time_internal(request: &RtPriorityRequest) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
+            if request.audio_samplerate_hz == 0 {
                 return Err(AudioThreadPriorityError{message: "sample rate is zero".to_string(), inner: None});
             }
             // no-op
-            Ok(RtPriorityHandle{})
+            Ok(RtPriorityHandle::default())
         }
-        pub fn demote_current_thread_from_real_time_internal(_: RtPriorityHandle) -> Result<(), AudioThreadPriorityError> {
+        pub fn demote_current_thread_from_real_time_internal(_handle: RtPriorityHandle) -> Result<(), AudioThreadPriorityError> {
+            #[cfg(target_os = "linux")]
+            if let Some(promotion) = &_handle.direct {
+                return rt_linux_direct::demote(promotion);
+            }
             // no-op
             Ok(())
         }
+        use self::demote_current_thread_from_real_time_internal as demote_with_force_internal;
         pub fn set_real_time_hard_limit(
             _: u32,
-            _: u32,
+            audio_samplerate_hz: u32,
         ) -> Result<(), AudioThreadPriorityError> {
+            if audio_samplerate_hz == 0 {
+                return Err(AudioThreadPriorityError::new("sample rate is zero"));
+            }
             Ok(())
         }
         pub fn get_current_thread_info_internal() -> Result<RtPriorityThreadInfo, AudioThreadPriorityError> {
@@ -158,13 +243,12 @@ cfg_if! {
         }
         pub fn promote_thread_to_real_time_internal(
             _: RtPriorityThreadInfo,
-            _: u32,
-            audio_samplerate_hz: u32,
+            request: &RtPriorityRequest,
         ) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
-            if audio_samplerate_hz == 0 {
+            if request.audio_samplerate_hz == 0 {
                 return Err(AudioThreadPriorityError::new("sample rate is zero"));
             }
-            return Ok(RtPriorityHandle{});
+            return Ok(RtPriorityHandle::default());
         }
 
         pub fn demote_thread_from_real_time_internal(_: RtPriorityThreadInfo) -> Result<(), AudioThreadPriorityError> {
@@ -179,6 +263,32 @@ cfg_if! {
 /// Opaque handle to a thread handle structure.
 pub type RtPriorityHandle = RtPriorityHandleInternal;
 
+/// A platform-independent description of the scheduling class of a thread.
+///
+/// This maps the OS-specific scheduling policies (`SCHED_FIFO` on Linux,
+/// `THREAD_TIME_CONSTRAINT_POLICY` on macOS, MMCSS on Windows) to a small set of semantic
+/// classes, so that code can check whether a thread is real-time without knowing the platform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PriorityClass {
+    /// The thread only runs when nothing else wants to run.
+    Idle,
+    /// The thread is time-shared, with a lower priority than normal threads.
+    BelowNormal,
+    /// The thread is time-shared, with the default priority.
+    Normal,
+    /// The thread is time-shared, with a higher priority than normal threads.
+    AboveNormal,
+    /// The thread is scheduled with a real-time policy.
+    Realtime,
+}
+
+impl PriorityClass {
+    /// Get the current scheduling class of the thread `handle` refers to.
+    pub fn from_handle(handle: &RtPriorityHandle) -> PriorityClass {
+        handle.priority_class()
+    }
+}
+
 cfg_if! {
     if #[cfg(target_os = "linux")] {
 /// Opaque handle to a thread info.
@@ -199,7 +309,8 @@ pub type RtPriorityThreadInfo = RtPriorityThreadInfoInternal;
 /// # Return value
 ///
 /// Ok in case of success, with an opaque structure containing relevant info for the platform, Err
-/// otherwise.
+/// otherwise. The error message contains the location of the call.
+#[track_caller]
 pub fn get_current_thread_info() -> Result<RtPriorityThreadInfo, AudioThreadPriorityError> {
     get_current_thread_info_internal()
 }
@@ -338,16 +449,64 @@ pub fn promote_thread_to_real_time(
     audio_buffer_frames: u32,
     audio_samplerate_hz: u32,
 ) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
-    if audio_samplerate_hz == 0 {
-        return Err(AudioThreadPriorityError::new("sample rate is zero"));
-    }
-    promote_thread_to_real_time_internal(
+    promote_thread_to_real_time_with_request(
         thread_info,
-        audio_buffer_frames,
-        audio_samplerate_hz,
+        &RtPriorityRequest::new(audio_buffer_frames, audio_samplerate_hz),
     )
 }
 
+/// Promote a particular thread thread to real-time priority, with additional options.
+///
+/// This call is useful on Linux desktop only, when the process is sandboxed and
+/// cannot promote itself directly.
+///
+/// # Arguments
+///
+/// * `thread_info` - informations about the thread to promote, gathered using
+///   `get_current_thread_info`.
+/// * `request` - the parameters of the promotion.
+///
+/// # Return value
+///
+/// This function returns a `Result<RtPriorityHandle>`, which is an opaque struct to be passed to
+/// `demote_current_thread_from_real_time` to revert to the previous thread priority.
+pub fn promote_thread_to_real_time_with_request(
+    thread_info: RtPriorityThreadInfo,
+    request: &RtPriorityRequest,
+) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
+    if request.audio_samplerate_hz == 0 {
+        return Err(AudioThreadPriorityError::new("sample rate is zero"));
+    }
+    promote_thread_to_real_time_internal(thread_info, request)
+}
+
+/// Promote a particular thread to real-time priority, via the realtime XDG Desktop Portal.
+///
+/// This is useful in sandboxes (e.g. Flatpak) where the portal, proxied to RTKit by PipeWire, is
+/// available on the session bus, but RTKit on the system bus is not. If the portal is not
+/// available, this falls back to RTKit on the system bus, like `promote_thread_to_real_time`.
+///
+/// # Arguments
+///
+/// * `thread_info` - informations about the thread to promote, gathered using
+///   `get_current_thread_info`.
+/// * `request` - the parameters of the promotion.
+///
+/// # Return value
+///
+/// This function returns a `Result<RtPriorityHandle>`, which is an opaque struct to be passed to
+/// `demote_current_thread_from_real_time` to revert to the previous thread priority.
+#[cfg(feature = "pipewire-portal")]
+pub fn promote_via_pipewire_portal(
+    thread_info: RtPriorityThreadInfo,
+    request: &RtPriorityRequest,
+) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
+    if request.audio_samplerate_hz == 0 {
+        return Err(AudioThreadPriorityError::new("sample rate is zero"));
+    }
+    promote_via_pipewire_portal_internal(thread_info, request)
+}
+
 /// Demotes a thread from real-time priority.
 ///
 /// # Arguments
@@ -449,6 +608,223 @@ pub extern "C" fn atp_set_real_time_limit(audio_buffer_frames: u32,
 }
 }
 
+/// The parameters of a real-time promotion.
+///
+/// This allows tweaking the promotion beyond the audio buffer size and sample-rate. Options that
+/// don't apply to the current platform are ignored: `high_resolution_timer` is only used on
+/// Windows, and the other options are only used on Linux.
+#[derive(Clone, Debug)]
+pub struct RtPriorityRequest {
+    pub(crate) audio_buffer_frames: u32,
+    pub(crate) audio_samplerate_hz: u32,
+    pub(crate) high_resolution_timer: bool,
+    pub(crate) in_container: bool,
+    pub(crate) io_latency_ms: Option<f64>,
+    pub(crate) cache_warm_size_bytes: usize,
+    pub(crate) memory_lock: bool,
+    pub(crate) budget_timer: bool,
+    pub(crate) max_load: Option<f64>,
+    pub(crate) min_stack_bytes: Option<usize>,
+    pub(crate) dmb_on_entry: bool,
+    pub(crate) dry_run: bool,
+    pub(crate) measure_initial_jitter: bool,
+    #[cfg(feature = "sched-deadline")]
+    pub(crate) prefer_deadline: bool,
+    #[cfg(feature = "cat")]
+    pub(crate) cat_cos_id: Option<u32>,
+    #[cfg(feature = "audit")]
+    pub(crate) audit_log_path: Option<std::path::PathBuf>,
+    #[cfg(all(target_os = "linux", feature = "dbus"))]
+    pub(crate) socket_priorities: Vec<(std::os::unix::io::RawFd, i32)>,
+    #[cfg(all(target_os = "linux", feature = "dbus"))]
+    pub(crate) pam_group: Option<String>,
+    #[cfg(all(target_os = "linux", feature = "secure-ipc"))]
+    pub(crate) ipc_token: Option<IpcToken>,
+    #[cfg(feature = "statsd")]
+    pub(crate) statsd: Option<(std::net::SocketAddr, String)>,
+    #[cfg(all(target_os = "linux", feature = "otel"))]
+    pub(crate) trace_parent: Option<RtTraceContext>,
+}
+
+impl RtPriorityRequest {
+    /// Create a request with default options.
+    ///
+    /// # Arguments
+    ///
+    /// * `audio_buffer_frames` - the exact or an upper limit on the number of frames that have to
+    ///   be rendered each callback, or 0 for a sensible default value.
+    /// * `audio_samplerate_hz` - the sample-rate for this audio stream, in Hz.
+    pub fn new(audio_buffer_frames: u32, audio_samplerate_hz: u32) -> RtPriorityRequest {
+        RtPriorityRequest {
+            audio_buffer_frames,
+            audio_samplerate_hz,
+            high_resolution_timer: false,
+            in_container: false,
+            io_latency_ms: None,
+            cache_warm_size_bytes: 0,
+            memory_lock: false,
+            budget_timer: false,
+            max_load: None,
+            min_stack_bytes: None,
+            dmb_on_entry: false,
+            dry_run: false,
+            measure_initial_jitter: false,
+            #[cfg(feature = "sched-deadline")]
+            prefer_deadline: false,
+            #[cfg(feature = "cat")]
+            cat_cos_id: None,
+            #[cfg(feature = "audit")]
+            audit_log_path: None,
+            #[cfg(all(target_os = "linux", feature = "dbus"))]
+            socket_priorities: Vec::new(),
+            #[cfg(all(target_os = "linux", feature = "dbus"))]
+            pam_group: None,
+            #[cfg(all(target_os = "linux", feature = "secure-ipc"))]
+            ipc_token: None,
+            #[cfg(feature = "statsd")]
+            statsd: None,
+            #[cfg(all(target_os = "linux", feature = "otel"))]
+            trace_parent: None,
+        }
+    }
+
+    /// The number of frames rendered each callback, or 0 if unknown.
+    pub fn audio_buffer_frames(&self) -> u32 {
+        self.audio_buffer_frames
+    }
+
+    /// The sample-rate of the audio stream, in Hz.
+    pub fn audio_samplerate_hz(&self) -> u32 {
+        self.audio_samplerate_hz
+    }
+
+    /// Also request a 1ms system timer resolution, see `WindowsTimerResolutionGuard`.
+    pub fn high_resolution_timer(mut self, enabled: bool) -> RtPriorityRequest {
+        self.high_resolution_timer = enabled;
+        self
+    }
+
+    /// Set when the thread runs in a PID namespace (e.g. a container) and RTKit runs outside of it.
+    pub fn in_container(mut self, in_container: bool) -> RtPriorityRequest {
+        self.in_container = in_container;
+        self
+    }
+
+    /// Derive the real-time budget from the audio I/O latency, in milliseconds, minus a margin.
+    pub fn with_io_latency_ms(mut self, io_latency_ms: f64) -> RtPriorityRequest {
+        self.io_latency_ms = Some(io_latency_ms);
+        self
+    }
+
+    /// Touch `warm_size_bytes` of memory when promoting the current thread, to prime the caches.
+    pub fn with_cache_warm(mut self, warm_size_bytes: usize) -> RtPriorityRequest {
+        self.cache_warm_size_bytes = warm_size_bytes;
+        self
+    }
+
+    /// Lock the memory of the process with `mlockall` while a thread of the process is promoted.
+    pub fn with_memory_lock(mut self, memory_lock: bool) -> RtPriorityRequest {
+        self.memory_lock = memory_lock;
+        self
+    }
+
+    /// Arm a timer for `RtPriorityHandle::budget_timer_expired`, at 90% of the budget.
+    pub fn use_timer_for_budget_monitoring(mut self, enabled: bool) -> RtPriorityRequest {
+        self.budget_timer = enabled;
+        self
+    }
+
+    /// Fail the promotion if the load average per online CPU is above `load`.
+    pub fn skip_if_load_above(mut self, load: f64) -> RtPriorityRequest {
+        self.max_load = Some(load);
+        self
+    }
+
+    /// Fail the promotion if the stack of the thread is smaller than `min_stack_bytes`.
+    pub fn check_stack_size(mut self, min_stack_bytes: usize) -> RtPriorityRequest {
+        self.min_stack_bytes = Some(min_stack_bytes);
+        self
+    }
+
+    /// Issue a full memory barrier around `RtPriorityHandle::profile_one_callback`.
+    pub fn install_dmb_on_entry(mut self, enabled: bool) -> RtPriorityRequest {
+        self.dmb_on_entry = enabled;
+        self
+    }
+
+    /// Only simulate the promotion via RTKit, see `RtPriorityHandle::dry_run`.
+    pub fn dry_run(mut self, enabled: bool) -> RtPriorityRequest {
+        self.dry_run = enabled;
+        self
+    }
+
+    /// Measure the scheduling jitter for about 10ms after the promotion.
+    pub fn measure_initial_jitter(mut self, enabled: bool) -> RtPriorityRequest {
+        self.measure_initial_jitter = enabled;
+        self
+    }
+
+    /// Try `SCHED_DEADLINE` first in `RtPriorityHandle::promote_with_fallback_chain`.
+    #[cfg(feature = "sched-deadline")]
+    pub fn prefer_deadline(mut self, enabled: bool) -> RtPriorityRequest {
+        self.prefer_deadline = enabled;
+        self
+    }
+
+    /// Move the thread to the `resctrl` group `COS<cos_id>` while it is promoted.
+    #[cfg(feature = "cat")]
+    pub fn with_cat_cos_id(mut self, cos_id: u32) -> RtPriorityRequest {
+        self.cat_cos_id = Some(cos_id);
+        self
+    }
+
+    /// Append a JSON line to the file at `path` for each promotion and demotion.
+    #[cfg(feature = "audit")]
+    pub fn with_audit_log(mut self, path: std::path::PathBuf) -> RtPriorityRequest {
+        self.audit_log_path = Some(path);
+        self
+    }
+
+    /// Report promotions and demotions to the StatsD server at `addr`, prefixed with `prefix`.
+    #[cfg(feature = "statsd")]
+    pub fn with_statsd(mut self, addr: std::net::SocketAddr, prefix: &str) -> RtPriorityRequest {
+        self.statsd = Some((addr, prefix.to_owned()));
+        self
+    }
+
+    /// Set the `SO_PRIORITY` of socket `fd` to `priority` while the thread is promoted.
+    #[cfg(all(target_os = "linux", feature = "dbus"))]
+    pub fn with_socket_priority(
+        mut self,
+        fd: std::os::unix::io::RawFd,
+        priority: i32,
+    ) -> RtPriorityRequest {
+        self.socket_priorities.push((fd, priority));
+        self
+    }
+
+    /// Send `group_name` to RTKit as `X-Group`, which requires a patched RTKit.
+    #[cfg(all(target_os = "linux", feature = "dbus"))]
+    pub fn for_pam_group(mut self, group_name: &str) -> RtPriorityRequest {
+        self.pam_group = Some(group_name.to_owned());
+        self
+    }
+
+    /// Only promote the thread if `token` is valid, see `IpcSecret`.
+    #[cfg(all(target_os = "linux", feature = "secure-ipc"))]
+    pub fn with_ipc_token(mut self, token: IpcToken) -> RtPriorityRequest {
+        self.ipc_token = Some(token);
+        self
+    }
+
+    /// Make the span of the promotion a child of `parent`.
+    #[cfg(all(target_os = "linux", feature = "otel"))]
+    pub fn with_trace_parent(mut self, parent: RtTraceContext) -> RtPriorityRequest {
+        self.trace_parent = Some(parent);
+        self
+    }
+}
+
 /// Promote the calling thread thread to real-time priority.
 ///
 /// # Arguments
@@ -465,10 +841,29 @@ pub fn promote_current_thread_to_real_time(
     audio_buffer_frames: u32,
     audio_samplerate_hz: u32,
 ) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
-    if audio_samplerate_hz == 0 {
+    promote_current_thread_to_real_time_with_request(&RtPriorityRequest::new(
+        audio_buffer_frames,
+        audio_samplerate_hz,
+    ))
+}
+
+/// Promote the calling thread thread to real-time priority, with additional options.
+///
+/// # Arguments
+///
+/// * `request` - the parameters of the promotion.
+///
+/// # Return value
+///
+/// This function returns a `Result<RtPriorityHandle>`, which is an opaque struct to be passed to
+/// `demote_current_thread_from_real_time` to revert to the previous thread priority.
+pub fn promote_current_thread_to_real_time_with_request(
+    request: &RtPriorityRequest,
+) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
+    if request.audio_samplerate_hz == 0 {
         return Err(AudioThreadPriorityError::new("sample rate is zero"));
     }
-    promote_current_thread_to_real_time_internal(audio_buffer_frames, audio_samplerate_hz)
+    promote_current_thread_to_real_time_internal(request)
 }
 
 /// Demotes the calling thread from real-time priority.
@@ -487,6 +882,54 @@ pub fn demote_current_thread_from_real_time(
     demote_current_thread_from_real_time_internal(handle)
 }
 
+/// Demotes the thread `handle` refers to from real-time priority, without checking that it is the
+/// calling thread.
+///
+/// This is meant for emergency cleanup, e.g. when the thread that was promoted can't demote itself
+/// anymore. On Linux, `demote_current_thread_from_real_time` panics if `handle` doesn't refer to
+/// the calling thread. On other platforms, this is the same as
+/// `demote_current_thread_from_real_time`.
+///
+/// # Arguments
+///
+/// * `handle` - An opaque struct returned from a successful call to
+///   `promote_current_thread_to_real_time`.
+///
+/// # Return value
+///
+/// `Ok` in case of success, `Err` otherwise.
+pub fn demote_with_force(handle: RtPriorityHandle) -> Result<(), AudioThreadPriorityError> {
+    demote_with_force_internal(handle)
+}
+
+/// A thread that was promoted to real-time priority, and that is meant to stay real-time until it
+/// exits.
+///
+/// This is returned by `RtPriorityHandle::detach`, and makes it explicit that the thread is never
+/// going to be demoted, instead of calling `std::mem::forget` on the handle.
+///
+/// Detaching a thread leaks the resources associated with the promotion until the thread exits:
+/// the MMCSS task on Windows, and the real-time scheduling policy and `RLIMIT_RTTIME` budget on
+/// Linux. The thread can be demoted later by calling `reattach` and demoting the resulting handle.
+pub struct DetachedRtThread {
+    handle: RtPriorityHandle,
+}
+
+impl DetachedRtThread {
+    /// Get back a handle to the thread, that can be passed to
+    /// `demote_current_thread_from_real_time`.
+    pub fn reattach(self) -> RtPriorityHandle {
+        self.handle
+    }
+}
+
+impl RtPriorityHandle {
+    /// Keep the thread at real-time priority, without ever demoting it.
+    pub fn detach(self) -> DetachedRtThread {
+        DetachedRtThread { handle: self }
+    }
+}
+
 /// Opaque handle for the C API
 #[allow(non_camel_case_types)]
 pub struct atp_handle(RtPriorityHandle);
@@ -634,6 +1077,12 @@ mod tests {
                 }
             }
         }
+        {
+            let detached = promote_current_thread_to_real_time(512, 44100)
+                .unwrap()
+                .detach();
+            demote_current_thread_from_real_time(detached.reattach()).unwrap();
+        }
         {
             match promote_current_thread_to_real_time(512, 44100) {
                 Ok(_) => {
@@ -647,6 +1096,18 @@ mod tests {
             // automatically deallocated, but not demoted until the thread exits.
         }
     }
+    #[test]
+    fn test_telemetry_buffer() {
+        let buffer = RtTelemetryBuffer::new(2);
+        assert!(buffer.write_lock_free_sample(1.0));
+        assert!(buffer.write_lock_free_sample(2.0));
+        // Full, the sample is dropped.
+        assert!(!buffer.write_lock_free_sample(3.0));
+        assert_eq!(buffer.drain_samples().collect::<Vec<f32>>(), vec![1.0, 2.0]);
+        assert!(buffer.write_lock_free_sample(4.0));
+        assert_eq!(buffer.drain_samples().collect::<Vec<f32>>(), vec![4.0]);
+        assert_eq!(buffer.drain_samples().count(), 0);
+    }
     cfg_if! {
         if #[cfg(target_os = "linux")] {
             use nix::unistd::*;
@@ -680,6 +1141,27 @@ mod tests {
                 }
             }
             #[test]
+            fn test_zero_samplerate() {
+                // This used to divide by zero when computing the real-time budget.
+                assert!(set_real_time_hard_limit(512, 0).is_err());
+                assert_eq!(atp_set_real_time_limit(512, 0), 1);
+                let request = RtPriorityRequest::new(512, 0);
+                assert!(promote_current_thread_to_real_time_with_request(&request).is_err());
+                let info = get_current_thread_info().unwrap();
+                assert!(promote_thread_to_real_time_with_request(info, &request).is_err());
+            }
+            #[test]
+            #[cfg(feature = "dbus")]
+            fn test_handle_file() {
+                let path = std::env::temp_dir().join(format!("atp-handle-{}", getpid()));
+                let handle = promote_current_thread_to_real_time(512, 44100).unwrap();
+                handle.save_to_file(&path).unwrap();
+                let loaded = RtPriorityHandle::load_from_file(&path).unwrap();
+                std::fs::remove_file(&path).unwrap();
+                // Demoting asserts that the handle refers to the calling thread.
+                demote_current_thread_from_real_time(loaded).unwrap();
+            }
+            #[test]
             fn test_remote_promotion() {
                 let (rd, wr) = pipe().unwrap();
 
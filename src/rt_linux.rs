@@ -7,17 +7,153 @@
 extern crate dbus;
 extern crate libc;
 
+use std::alloc::Layout;
+use std::cell::{RefCell, UnsafeCell};
 use std::cmp;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::error::Error;
+#[cfg(feature = "syslog")]
+use std::ffi::{CStr, CString};
+use std::fs::{self, OpenOptions};
 use std::io::Error as OSError;
+use std::io::{Read, Write};
+#[cfg(feature = "statsd")]
+use std::net::{SocketAddr, UdpSocket};
+use std::ops::RangeInclusive;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+use std::sync::atomic::{
+    AtomicBool, AtomicI32, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering,
+};
+#[cfg(feature = "registry")]
+use std::sync::RwLock;
+use std::sync::{Arc, Condvar, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
 
 use dbus::{BusType, Connection, Message, MessageItem, Props};
+#[cfg(feature = "secure-ipc")]
+use hmac::{Hmac, Mac};
+#[cfg(feature = "otel")]
+use log::info;
+use log::warn;
+#[cfg(feature = "secure-ipc")]
+use sha2::Sha256;
 
+use crate::rt_linux_direct::{
+    buffer_duration_us, request_budget_us, restore_rttime_limit, rttime_limit, set_limits,
+    set_min_timer_slack, set_sched_fifo, RT_PRIO_DEFAULT, SCHED_RESET_ON_FORK,
+};
 use crate::AudioThreadPriorityError;
+use crate::PriorityClass;
+use crate::RtPriorityRequest;
+use crate::RtTelemetryBuffer;
 
 const DBUS_SOCKET_TIMEOUT: i32 = 10_000;
-const RT_PRIO_DEFAULT: u32 = 10;
+/// How long an `IpcToken` is valid after it has been created.
+#[cfg(feature = "secure-ipc")]
+pub const IPC_TOKEN_MAX_AGE: Duration = Duration::from_secs(5);
+/// The size of a serialized `IpcToken`.
+#[cfg(feature = "secure-ipc")]
+pub const IPC_TOKEN_SIZE: usize = 52;
+/// The fraction of the real-time budget kept by `set_power_aware_mode` when running on battery.
+pub const BATTERY_BUDGET_FRACTION: f64 = 0.8;
+/// The fraction of the real-time budget after which the timer created with
+/// `RtPriorityRequest::use_timer_for_budget_monitoring` expires.
+const BUDGET_TIMER_FRACTION: f64 = 0.9;
+/// The margin added to the scheduling jitter by `jitter_compensation_offset_us`, in
+/// microseconds.
+pub const JITTER_SAFETY_MARGIN_US: i64 = 20;
+/// The default fraction of an audio buffer's duration that `stealable_budget_us` leaves to a
+/// thread.
+const MIN_SAFE_BUDGET_FRACTION_DEFAULT: f64 = 0.25;
+/// Magic bytes at the start of a file written by `RtPriorityHandleInternal::save_to_file`.
+const HANDLE_FILE_MAGIC: [u8; 4] = *b"ATPH";
+/// Version of the on-disk format written by `RtPriorityHandleInternal::save_to_file`.
+const HANDLE_FILE_VERSION: u32 = 1;
+/// Size of a handle written by `RtPriorityHandle::serialize_for_mmap`: thread id and budget (8
+/// bytes each), pid, policy and priority (4 bytes each), and a 4-byte sequence number.
+pub const MMAP_HANDLE_SIZE: usize = 32;
+/// The size of the encoding of a handle by `RtPriorityHandle::to_bincode_bytes`.
+#[cfg(feature = "bincode-layout")]
+const BINCODE_HANDLE_SIZE: usize = 28;
+/// Alignment required by `RtPriorityHandle::serialize_for_mmap` for its atomic stores.
+const MMAP_HANDLE_ALIGN: usize = 8;
+/// Sequence number of a handle laid out at once by `serialize_atomic_snapshot`. The sequence
+/// number of a handle written by `RtPriorityHandle::serialize_for_mmap` is odd while it is being
+/// written, and 0 if nothing has been written.
+const MMAP_HANDLE_SNAPSHOT_SEQUENCE: u32 = 2;
+// From linux/perf_event.h.
+#[cfg(feature = "perf-events")]
+const PERF_TYPE_HARDWARE: u32 = 0;
+#[cfg(feature = "perf-events")]
+const PERF_COUNT_HW_CACHE_REFERENCES: u64 = 2;
+#[cfg(feature = "perf-events")]
+const PERF_COUNT_HW_CACHE_MISSES: u64 = 3;
+#[cfg(feature = "perf-events")]
+const PERF_ATTR_FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+#[cfg(feature = "perf-events")]
+const PERF_ATTR_FLAG_EXCLUDE_HV: u64 = 1 << 6;
+#[cfg(feature = "perf-events")]
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+// From linux/bpf.h.
+#[cfg(feature = "bpf")]
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+#[cfg(feature = "bpf")]
+const BPF_ANY: u64 = 0;
+// From linux/ethtool.h.
+#[cfg(feature = "netaudio")]
+const ETHTOOL_GCOALESCE: u32 = 0x0e;
+#[cfg(feature = "netaudio")]
+const ETHTOOL_SCOALESCE: u32 = 0x0f;
+/// The PM QoS interface to limit the wake-up latency of the CPUs.
+const CPU_DMA_LATENCY: &str = "/dev/cpu_dma_latency";
+/// The name of the systemd credential used by `to_systemd_cred_spec`.
+const SYSTEMD_CREDENTIAL_NAME: &str = "audio-rt";
+/// The traffic class set by `request_low_latency_network_qos`: DSCP Expedited Forwarding (46),
+/// in the upper 6 bits.
+const DSCP_EF_TRAFFIC_CLASS: i32 = 46 << 2;
+/// The `SO_PRIORITY` set by `request_low_latency_network_qos`, the highest one that doesn't
+/// require `CAP_NET_ADMIN`.
+const NETWORK_AUDIO_SOCKET_PRIORITY: i32 = 6;
+// From linux/ioprio.h, not exposed by libc.
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+const IOPRIO_CLASS_RT: libc::c_long = 1;
+const IOPRIO_CLASS_BE: libc::c_long = 2;
+const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+/// Default minimum scheduling granularity of the kernel, used when it can't be read.
+const DEFAULT_SCHED_MIN_GRANULARITY_NS: u64 = 750_000;
+/// Where the cgroup v1 `cpuset` hierarchy is mounted.
+const CPUSET_ROOT: &str = "/sys/fs/cgroup/cpuset";
+/// The fraction of the duration of an audio buffer used as the `SCHED_DEADLINE` runtime by
+/// `promote_with_fallback_chain`.
+#[cfg(feature = "sched-deadline")]
+const DEADLINE_RUNTIME_FRACTION: f64 = 0.5;
+/// The throughput assumed for the DMA transfer of an audio buffer by
+/// `compute_audio_buffer_budget_us`, in bytes per microsecond (100MB/s, conservative for HDA and
+/// I2S controllers).
+const DMA_BYTES_PER_US: u64 = 100;
+/// The default fraction of the duration of an audio buffer kept as a safety margin by
+/// `compute_audio_buffer_budget_us`.
+const BUDGET_SAFETY_FRACTION_DEFAULT: f64 = 0.2;
+/// Number of buckets of a `LatencyHistogram`.
+const LATENCY_HISTOGRAM_BUCKETS: usize = 32;
+/// Number of telemetry samples that can be written before being drained.
+const TELEMETRY_BUFFER_CAPACITY: usize = 1024;
+/// Not exposed by libc.
+const SCHED_DEADLINE: libc::c_int = 6;
+const SCHED_FLAG_RESET_ON_FORK: u64 = 0x01;
+#[cfg(feature = "numa")]
+const SCHED_FLAG_KEEP_ALL: u64 = 0x18;
+#[cfg(feature = "numa")]
+const SCHED_FLAG_UTIL_CLAMP_MAX: u64 = 0x40;
+/// The maximum utilization of the threads sharing a core after `add_co_located_thread_hint`, half
+/// of `SCHED_CAPACITY_SCALE`.
+#[cfg(feature = "numa")]
+const CO_LOCATED_UTIL_CLAMP_MAX: u32 = 512;
 // This is different from libc::pid_t, which is 32 bits, and is defined in sys/types.h.
 #[allow(non_camel_case_types)]
 type kernel_pid_t = libc::c_long;
@@ -53,26 +189,6837 @@ pub struct RtPriorityThreadInfoInternal {
     policy: libc::c_int,
 }
 
+/// Scheduler statistics of a thread, from `/proc/<pid>/task/<tid>/sched`. This is only available
+/// if the kernel has been built with `CONFIG_SCHED_DEBUG`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExtendedSchedulerInfo {
+    /// The CPU time consumed by the thread, in nanoseconds (`se.sum_exec_runtime`).
+    pub sum_exec_runtime_ns: u64,
+    /// The number of context switches of the thread (`nr_switches`).
+    pub nr_switches: u64,
+    /// The kernel priority of the thread (`prio`), 120 for a thread with a nice value of 0.
+    pub prio: i64,
+}
+
+impl ExtendedSchedulerInfo {
+    /// Parse the `sched` file of the thread `thread_info` refers to.
+    fn read(
+        thread_info: &RtPriorityThreadInfoInternal,
+    ) -> Result<ExtendedSchedulerInfo, AudioThreadPriorityError> {
+        let sched = fs::read_to_string(task_proc_path(thread_info).join("sched"))
+            .map_err(|e| AudioThreadPriorityError::new_with_inner("read sched", Box::new(e)))?;
+        let field = |key: &str| {
+            sched
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(k, _)| k.trim() == key)
+                .map(|(_, v)| v.trim())
+                .ok_or_else(|| AudioThreadPriorityError::new(&format!("no {} in sched", key)))
+        };
+        let invalid = |key: &str| AudioThreadPriorityError::new(&format!("invalid {}", key));
+        // In milliseconds, with a fractional part in nanoseconds.
+        let sum_exec_runtime = field("se.sum_exec_runtime")?;
+        let (ms, ns) = sum_exec_runtime
+            .split_once('.')
+            .unwrap_or((sum_exec_runtime, "0"));
+        let sum_exec_runtime_ns = ms
+            .parse::<u64>()
+            .ok()
+            .zip(format!("{:0<6}", ns).parse::<u64>().ok())
+            .map(|(ms, ns)| ms * 1_000_000 + ns)
+            .ok_or_else(|| invalid("se.sum_exec_runtime"))?;
+        Ok(ExtendedSchedulerInfo {
+            sum_exec_runtime_ns,
+            nr_switches: field("nr_switches")?
+                .parse()
+                .map_err(|_| invalid("nr_switches"))?,
+            prio: field("prio")?.parse().map_err(|_| invalid("prio"))?,
+        })
+    }
+}
+
 impl RtPriorityThreadInfoInternal {
     /// Serialize a RtPriorityThreadInfoInternal to a byte buffer.
     pub fn serialize(&self) -> [u8; std::mem::size_of::<Self>()] {
         unsafe { std::mem::transmute::<Self, [u8; std::mem::size_of::<Self>()]>(*self) }
     }
-    /// Get an RtPriorityThreadInfoInternal from a byte buffer.
-    pub fn deserialize(bytes: [u8; std::mem::size_of::<Self>()]) -> Self {
-        unsafe { std::mem::transmute::<[u8; std::mem::size_of::<Self>()], Self>(bytes) }
+    /// Get an RtPriorityThreadInfoInternal from a byte buffer.
+    pub fn deserialize(bytes: [u8; std::mem::size_of::<Self>()]) -> Self {
+        unsafe { std::mem::transmute::<[u8; std::mem::size_of::<Self>()], Self>(bytes) }
+    }
+}
+
+impl PartialEq for RtPriorityThreadInfoInternal {
+    fn eq(&self, other: &Self) -> bool {
+        self.thread_id == other.thread_id && self.pthread_id == other.pthread_id
+    }
+}
+
+/// A C-compatible description of a `RtPriorityHandle`, that can be passed over FFI without
+/// knowledge of the layout of `RtPriorityHandle`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawRtHandle {
+    /// The PID of the process containing the thread.
+    pub pid: i32,
+    /// The system-wide thread id of the thread.
+    pub thread_id: i64,
+    /// The scheduling policy of the thread before promotion, restored on demotion.
+    pub policy: i32,
+    /// The real-time priority requested at promotion time.
+    pub priority: i32,
+    /// The soft real-time budget set at promotion time, in microseconds.
+    pub budget_us: u64,
+}
+
+/*#[derive(Debug)]*/
+pub struct RtPriorityHandleInternal {
+    thread_info: RtPriorityThreadInfoInternal,
+    /// The duration of an audio buffer at promotion time, in microseconds.
+    latency_target_us: u64,
+    /// The soft RLIMIT_RTTIME limit set at promotion time, in microseconds.
+    budget_us: u64,
+    /// The real-time priority requested at promotion time.
+    priority: u32,
+    /// The number of major page faults of the thread at promotion time.
+    major_faults_at_promotion: u64,
+    /// The number of CPU migrations of the thread at promotion time.
+    migrations_at_promotion: u64,
+    /// The scheduler statistics of the thread at promotion time, if available.
+    extended_info_at_promotion: Option<ExtendedSchedulerInfo>,
+    /// Whether `profile_one_callback` issues memory barriers around the callback, see
+    /// `RtPriorityRequest::install_dmb_on_entry`.
+    dmb_on_entry: bool,
+    /// When the thread was promoted, `None` if this handle wasn't created by promoting a thread.
+    promoted_at: Option<Instant>,
+    /// The request the thread was promoted with, `None` if this handle wasn't created by
+    /// promoting a thread.
+    request: Option<RtPriorityRequest>,
+    /// The CPU affinity of the thread at promotion time, or before it was first changed via this
+    /// handle if it wasn't promoted by this process, restored on demotion.
+    #[cfg(feature = "numa")]
+    original_affinity: Option<Vec<usize>>,
+    /// The maximum utilization of the thread before it was first clamped by
+    /// `add_co_located_thread_hint`, restored on demotion.
+    #[cfg(feature = "numa")]
+    original_util_clamp_max: Option<u32>,
+    /// The CPU affinity last set via this handle.
+    affinity: Option<Vec<usize>>,
+    /// The CPU affinity of the thread at promotion time.
+    affinity_at_promotion: Option<Vec<usize>>,
+    /// Set by `stop_monitoring` to make `monitor_loop` return.
+    stop_monitoring: AtomicBool,
+    /// Set when the thread is demoted. The threads started by `freeze_thread_priority` hold this
+    /// lock while checking and promoting the thread, and exit once it is set or this handle is
+    /// dropped.
+    demoted: Arc<Mutex<bool>>,
+    /// The mutex created by `set_priority_ceiling`.
+    priority_ceiling_mutex: Option<PriorityCeilingMutex>,
+    /// The runtime, deadline and period set with `set_deadline_scheduling`, in microseconds.
+    deadline_params: Option<(u64, u64, u64)>,
+    /// The registration of this handle with the watchdog, if any.
+    watchdog_entry: Option<Arc<WatchdogEntry>>,
+    /// Telemetry samples written by the real-time thread.
+    telemetry: RtTelemetryBuffer<f32>,
+    /// The latencies recorded with `record_callback_latency_us`.
+    callback_latency: LatencyHistogram,
+    /// The bits of the `budget_utilization` last measured by `profile_one_callback`.
+    #[cfg(feature = "prometheus-text")]
+    last_budget_utilization: AtomicU64,
+    /// The resctrl group the thread was in before being moved to another Intel CAT class of
+    /// service, if `RtPriorityRequest::with_cat_cos_id` was used.
+    #[cfg(feature = "cat")]
+    previous_cat_group: Option<PathBuf>,
+    /// The file promotions and demotions are logged to, if
+    /// `RtPriorityRequest::with_audit_log` was used.
+    #[cfg(feature = "audit")]
+    audit_log_path: Option<PathBuf>,
+    /// The sockets whose priority was set at promotion time, with their original priority.
+    original_socket_priorities: Vec<(RawFd, i32)>,
+    /// Whether `RtPriorityRequest::with_memory_lock` was used.
+    memory_lock_requested: bool,
+    /// Whether the memory of the process was locked at promotion time, and wasn't locked before.
+    /// It is unlocked on demotion.
+    memory_locked_by_promotion: bool,
+    /// The fraction of `latency_target_us` below which `stealable_budget_us` doesn't let the
+    /// budget of the thread drop.
+    min_safe_budget_fraction: f64,
+    /// The offset set by `set_jitter_compensation_enabled`, in microseconds.
+    jitter_compensation_us: Option<i64>,
+    /// The fraction of the duration of an audio buffer kept as a safety margin by
+    /// `compute_audio_buffer_budget_us`.
+    budget_safety_fraction: f64,
+    /// The budget of the thread before it was reduced by `set_power_aware_mode`, in
+    /// microseconds.
+    ac_budget_us: Option<u64>,
+    /// The mutexes registered with `register_mutex`.
+    #[cfg(debug_assertions)]
+    registered_mutexes: Vec<usize>,
+    /// The timer created if `RtPriorityRequest::use_timer_for_budget_monitoring` was used.
+    budget_timer: Option<BudgetTimer>,
+    /// The 99th percentile of the scheduling jitter measured at promotion time, in microseconds,
+    /// if `RtPriorityRequest::measure_initial_jitter` was used.
+    initial_jitter_p99_us: Option<u64>,
+    /// The semaphore posted by `signal_callback_done`.
+    attached_semaphore: Option<Arc<Semaphore>>,
+    /// The StatsD server and metric prefix demotions are reported to, if
+    /// `RtPriorityRequest::with_statsd` was used.
+    #[cfg(feature = "statsd")]
+    statsd: Option<(SocketAddr, String)>,
+    /// The span of the promotion, ended on demotion or when the handle is dropped.
+    #[cfg(feature = "otel")]
+    trace_span: Option<TraceSpan>,
+    /// The cache counters opened at promotion time, if possible.
+    #[cfg(feature = "perf-events")]
+    cache_counters: Option<CacheCounters>,
+    /// The registration made by `register_with_rt_registry`, removed when the handle is dropped.
+    #[cfg(feature = "registry")]
+    registry_entry: Option<RegistryEntry>,
+}
+
+impl RtPriorityHandleInternal {
+    fn new(thread_info: RtPriorityThreadInfoInternal) -> RtPriorityHandleInternal {
+        RtPriorityHandleInternal {
+            thread_info,
+            latency_target_us: 0,
+            budget_us: 0,
+            priority: 0,
+            major_faults_at_promotion: 0,
+            migrations_at_promotion: 0,
+            extended_info_at_promotion: None,
+            dmb_on_entry: false,
+            promoted_at: None,
+            request: None,
+            #[cfg(feature = "numa")]
+            original_affinity: None,
+            #[cfg(feature = "numa")]
+            original_util_clamp_max: None,
+            affinity: None,
+            affinity_at_promotion: None,
+            stop_monitoring: AtomicBool::new(false),
+            demoted: Arc::new(Mutex::new(false)),
+            priority_ceiling_mutex: None,
+            deadline_params: None,
+            watchdog_entry: None,
+            telemetry: RtTelemetryBuffer::new(TELEMETRY_BUFFER_CAPACITY),
+            callback_latency: LatencyHistogram::new(),
+            #[cfg(feature = "prometheus-text")]
+            last_budget_utilization: AtomicU64::new(0),
+            #[cfg(feature = "cat")]
+            previous_cat_group: None,
+            #[cfg(feature = "audit")]
+            audit_log_path: None,
+            original_socket_priorities: Vec::new(),
+            memory_lock_requested: false,
+            memory_locked_by_promotion: false,
+            min_safe_budget_fraction: MIN_SAFE_BUDGET_FRACTION_DEFAULT,
+            jitter_compensation_us: None,
+            budget_safety_fraction: BUDGET_SAFETY_FRACTION_DEFAULT,
+            ac_budget_us: None,
+            #[cfg(debug_assertions)]
+            registered_mutexes: Vec::new(),
+            budget_timer: None,
+            initial_jitter_p99_us: None,
+            attached_semaphore: None,
+            #[cfg(feature = "statsd")]
+            statsd: None,
+            #[cfg(feature = "otel")]
+            trace_span: None,
+            #[cfg(feature = "perf-events")]
+            cache_counters: None,
+            #[cfg(feature = "registry")]
+            registry_entry: None,
+        }
+    }
+
+    /// Write this handle to `path`, so that it can be picked up by another process sharing a
+    /// filesystem (e.g. `/dev/shm` or a tmpfs), with `load_from_file`.
+    ///
+    /// The file starts with a magic number and a format version, followed by the serialized thread
+    /// info. It is created (or truncated) with permissions 0600.
+    pub fn save_to_file(&self, path: &Path) -> Result<(), AudioThreadPriorityError> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| AudioThreadPriorityError::new_with_inner("open", Box::new(e)))?;
+        // `mode` only applies when the file is created, make sure an existing file isn't left
+        // readable by others.
+        file.set_permissions(fs::Permissions::from_mode(0o600))
+            .map_err(|e| {
+                AudioThreadPriorityError::new_with_inner("set_permissions", Box::new(e))
+            })?;
+
+        let mut bytes = Vec::with_capacity(
+            HANDLE_FILE_MAGIC.len()
+                + std::mem::size_of::<u32>()
+                + std::mem::size_of::<RtPriorityThreadInfoInternal>(),
+        );
+        bytes.extend_from_slice(&HANDLE_FILE_MAGIC);
+        bytes.extend_from_slice(&HANDLE_FILE_VERSION.to_ne_bytes());
+        bytes.extend_from_slice(&self.thread_info.serialize());
+
+        file.write_all(&bytes)
+            .map_err(|e| AudioThreadPriorityError::new_with_inner("write", Box::new(e)))?;
+
+        Ok(())
+    }
+
+    /// Check that `token` has been signed with the secret installed with `IpcSecret::install`,
+    /// and that it isn't older than `IPC_TOKEN_MAX_AGE`.
+    ///
+    /// This is done by `promote_thread_to_real_time_with_request` when the request has a token,
+    /// see `RtPriorityRequest::with_ipc_token`, so that a sandboxed process can't get another
+    /// process promoted by forging its thread info.
+    #[cfg(feature = "secure-ipc")]
+    pub fn validate_ipc_token(token: &IpcToken) -> Result<(), AudioThreadPriorityError> {
+        let secret = IPC_SECRET
+            .get()
+            .ok_or_else(|| AudioThreadPriorityError::new("no IPC secret installed"))?;
+        // This compares the MACs in constant time.
+        if secret.mac(token).verify_slice(&token.mac).is_err() {
+            return Err(AudioThreadPriorityError::new("invalid IPC token signature"));
+        }
+        let age_ns = monotonic_now_ns().saturating_sub(token.timestamp_ns);
+        if age_ns > IPC_TOKEN_MAX_AGE.as_nanos() as u64 {
+            return Err(AudioThreadPriorityError::new("expired IPC token"));
+        }
+        Ok(())
+    }
+
+    /// The duration of an audio buffer, in microseconds, as derived from the buffer size and
+    /// sample-rate passed when promoting the thread. The real-time budget of the thread should be
+    /// lower than this.
+    ///
+    /// This is 0 for handles that weren't created by promoting a thread, e.g. with
+    /// `load_from_file`.
+    pub fn latency_target_us(&self) -> u64 {
+        self.latency_target_us
+    }
+
+    /// How much time is left in each audio buffer once the thread has used its soft real-time
+    /// budget, in microseconds. This is `latency_target_us` minus the soft `RLIMIT_RTTIME` limit
+    /// set when promoting the thread.
+    pub fn budget_margin_us(&self) -> u64 {
+        self.latency_target_us.saturating_sub(self.budget_us)
+    }
+
+    /// The soft real-time budget of the thread, converted to a number of frames at
+    /// `samplerate_hz`, rounded down. This is the inverse of the conversion done at promotion.
+    pub fn budget_as_frames(&self, samplerate_hz: u32) -> u32 {
+        // u128, so that large budgets don't overflow.
+        let frames = self.budget_us as u128 * samplerate_hz as u128 / 1_000_000;
+        cmp::min(frames, u32::MAX as u128) as u32
+    }
+
+    /// The soft real-time budget of the thread, converted to a fractional number of frames at
+    /// `samplerate_hz`.
+    pub fn budget_as_samples_f64(&self, samplerate_hz: f64) -> f64 {
+        self.budget_us as f64 * samplerate_hz / 1_000_000.0
+    }
+
+    /// How much of the remaining real-time budget of the thread can be taken by
+    /// `steal_budget_from` while leaving it at least `min_safe_budget_fraction` of
+    /// `latency_target_us`, in microseconds.
+    ///
+    /// The remaining budget is the budget minus the CPU time the thread has used since it was
+    /// promoted. The kernel resets its `RLIMIT_RTTIME` accounting when the thread blocks, so this
+    /// underestimates what is left. This is 0 if the CPU time used can't be read, e.g. for handles
+    /// obtained with `from_raw_handle`.
+    pub fn stealable_budget_us(&self) -> u64 {
+        let at_promotion = match &self.extended_info_at_promotion {
+            Some(info) => info.sum_exec_runtime_ns,
+            None => return 0,
+        };
+        let used_us = match task_cpu_time(&self.thread_info) {
+            Ok(cpu_time) => (cpu_time.as_nanos() as u64).saturating_sub(at_promotion) / 1000,
+            Err(_) => return 0,
+        };
+        let min_safe_budget_us =
+            (self.latency_target_us as f64 * self.min_safe_budget_fraction) as u64;
+        self.budget_us
+            .saturating_sub(used_us)
+            .saturating_sub(min_safe_budget_us)
+    }
+
+    /// Set the fraction of `latency_target_us` that `stealable_budget_us` leaves to the thread,
+    /// clamped between 0.0 and 1.0. This is 0.25 by default.
+    pub fn set_min_safe_budget_fraction(&mut self, fraction: f64) {
+        self.min_safe_budget_fraction = fraction.clamp(0.0, 1.0);
+    }
+
+    /// Compute a real-time budget for audio buffers in `format`, in microseconds: the duration of
+    /// a buffer, minus a safety margin (see `set_budget_safety_fraction`), minus the time needed
+    /// to transfer the buffer by DMA, estimated from its size in bytes.
+    ///
+    /// This is 0 if the sample-rate is 0, or if the margins take the whole buffer.
+    pub fn compute_audio_buffer_budget_us(&self, format: &AudioFormat) -> u64 {
+        if format.sample_rate == 0 {
+            return 0;
+        }
+        let buffer_us = buffer_duration_us(format.buffer_frames, format.sample_rate);
+        let buffer_frames = buffer_us * format.sample_rate as u64 / 1_000_000;
+        let buffer_bytes =
+            buffer_frames * format.channels as u64 * (format.bit_depth as u64).div_ceil(8);
+        let dma_us = buffer_bytes.div_ceil(DMA_BYTES_PER_US);
+        let safety_margin_us = (buffer_us as f64 * self.budget_safety_fraction) as u64;
+        buffer_us
+            .saturating_sub(safety_margin_us)
+            .saturating_sub(dma_us)
+    }
+
+    /// Set the fraction of the duration of an audio buffer kept as a safety margin by
+    /// `compute_audio_buffer_budget_us`, clamped between 0.0 and 1.0. This is 0.2 by default.
+    pub fn set_budget_safety_fraction(&mut self, fraction: f64) {
+        self.budget_safety_fraction = fraction.clamp(0.0, 1.0);
+    }
+
+    /// Set the soft real-time budget of the thread to `budget_us`, clamped to the hard
+    /// `RLIMIT_RTTIME` limit, and return the budget that was set, in microseconds.
+    ///
+    /// The thread must be in the calling process. `RLIMIT_RTTIME` is a per-process limit: this
+    /// changes the budget of all the real-time threads of the process.
+    pub fn set_soft_budget_us(&mut self, budget_us: u64) -> Result<u64, AudioThreadPriorityError> {
+        if self.thread_info.pid != unsafe { libc::getpid() } {
+            return Err(AudioThreadPriorityError::new(
+                "can only set the budget of threads of the calling process",
+            ));
+        }
+        let max_us = rttime_hard_limit_us()?;
+        let budget_us = cmp::min(budget_us, max_us);
+        set_limits(budget_us, max_us)?;
+        self.budget_us = budget_us;
+        Ok(budget_us)
+    }
+
+    /// When `active` is true and the system runs on battery, reduce the soft real-time budget of
+    /// the thread to `BATTERY_BUDGET_FRACTION` of its current budget with `set_soft_budget_us`,
+    /// so that the CPU can run at a lower frequency. The application is expected to use larger
+    /// audio buffers in the meantime.
+    ///
+    /// The budget is restored when this is called with `active` false, or when the system runs
+    /// on AC power again. The power source is read from `/sys/class/power_supply/*/status`: this
+    /// should be called again when it changes.
+    pub fn set_power_aware_mode(&mut self, active: bool) -> Result<(), AudioThreadPriorityError> {
+        if active && on_battery() {
+            if self.ac_budget_us.is_none() {
+                let ac_budget_us = self.budget_us;
+                self.set_soft_budget_us((ac_budget_us as f64 * BATTERY_BUDGET_FRACTION) as u64)?;
+                self.ac_budget_us = Some(ac_budget_us);
+            }
+        } else if let Some(ac_budget_us) = self.ac_budget_us {
+            self.set_soft_budget_us(ac_budget_us)?;
+            self.ac_budget_us = None;
+        }
+        Ok(())
+    }
+
+    /// A JSON object with the fields of this handle, for debuggers, e.g. the GDB script
+    /// `gdb/audio_thread_priority.py`. Fields holding resources (timers, mutexes, watchdog
+    /// entries) are only reported as present or not.
+    pub fn export_for_gdb_pretty_printer(&self) -> String {
+        fn optional<T: std::fmt::Display>(value: Option<T>) -> String {
+            value.map_or_else(|| "null".to_string(), |v| v.to_string())
+        }
+        fn cpus(cpus: &Option<Vec<usize>>) -> String {
+            optional(cpus.as_ref().map(|cpus| {
+                let cpus: Vec<String> = cpus.iter().map(|cpu| cpu.to_string()).collect();
+                format!("[{}]", cpus.join(","))
+            }))
+        }
+        format!(
+            "{{\"thread_id\":{},\"pthread_id\":{},\"pid\":{},\"policy\":{},\"priority\":{},\
+             \"budget_us\":{},\"latency_target_us\":{},\"major_faults_at_promotion\":{},\
+             \"time_since_promotion_us\":{},\"affinity\":{},\"affinity_at_promotion\":{},\
+             \"deadline_params_us\":{},\"memory_lock_requested\":{},\
+             \"min_safe_budget_fraction\":{},\"jitter_compensation_us\":{},\
+             \"budget_safety_fraction\":{},\"ac_budget_us\":{},\"socket_priorities\":{},\
+             \"priority_ceiling_mutex\":{},\"watchdog\":{},\"budget_timer\":{}}}",
+            self.thread_info.thread_id,
+            self.thread_info.pthread_id,
+            self.thread_info.pid,
+            self.thread_info.policy,
+            self.priority,
+            self.budget_us,
+            self.latency_target_us,
+            self.major_faults_at_promotion,
+            optional(self.promoted_at.map(|t| t.elapsed().as_micros())),
+            cpus(&self.affinity),
+            cpus(&self.affinity_at_promotion),
+            optional(
+                self.deadline_params
+                    .map(|(runtime, deadline, period)| format!(
+                        "[{},{},{}]",
+                        runtime, deadline, period
+                    ))
+            ),
+            self.memory_lock_requested,
+            self.min_safe_budget_fraction,
+            optional(self.jitter_compensation_us),
+            self.budget_safety_fraction,
+            optional(self.ac_budget_us),
+            self.original_socket_priorities.len(),
+            self.priority_ceiling_mutex.is_some(),
+            self.watchdog_entry.is_some(),
+            self.budget_timer.is_some(),
+        )
+    }
+
+    /// Write the fields of this handle, as returned by `export_for_gdb_pretty_printer`, to
+    /// `audio_rt_<pid>_<tid>` in `$XDG_RUNTIME_DIR` (or `/run/user/<uid>` if it isn't set), so
+    /// that the user-space context of a real-time thread can be correlated with a kernel panic or
+    /// oops it caused, e.g. by a tool collecting it from the kdump capture kernel. Use
+    /// `read_kdump_annotation` to read it back.
+    ///
+    /// The file is created with permissions 0600, and synced to disk before returning. Call this
+    /// again when the handle changes, e.g. after changing the budget: the file is replaced. It is
+    /// removed when the thread is demoted.
+    #[cfg(feature = "kdump-support")]
+    pub fn to_kdump_annotation(&self) -> Result<(), AudioThreadPriorityError> {
+        let error = |e| AudioThreadPriorityError::new_with_inner("kdump annotation", Box::new(e));
+        let path = kdump_annotation_path(self.thread_info.pid, self.thread_info.thread_id as u64);
+        // The path is predictable: remove a previous annotation, or whatever is there, and only
+        // write to a new file (O_CREAT | O_EXCL), without following symlinks.
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(error(e));
+            }
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)
+            .map_err(error)?;
+        file.write_all(self.export_for_gdb_pretty_printer().as_bytes())
+            .map_err(error)?;
+        file.sync_all().map_err(error)
+    }
+
+    /// Read the annotation written by `to_kdump_annotation` for the thread `tid` of the process
+    /// `pid`.
+    #[cfg(feature = "kdump-support")]
+    pub fn read_kdump_annotation(
+        pid: libc::pid_t,
+        tid: u64,
+    ) -> Result<String, AudioThreadPriorityError> {
+        fs::read_to_string(kdump_annotation_path(pid, tid))
+            .map_err(|e| AudioThreadPriorityError::new_with_inner("kdump annotation", Box::new(e)))
+    }
+
+    /// Check that the stack of the calling thread, that has to be the thread this handle refers
+    /// to, is at least `min_stack_bytes` large. A warning recommending a larger stack size is
+    /// logged if it's not.
+    pub fn enforce_stack_size(
+        &self,
+        min_stack_bytes: usize,
+    ) -> Result<(), AudioThreadPriorityError> {
+        if unsafe { libc::syscall(libc::SYS_gettid) } != self.thread_info.thread_id {
+            return Err(AudioThreadPriorityError::new(
+                "enforce_stack_size called on another thread",
+            ));
+        }
+        enforce_current_thread_stack_size(min_stack_bytes)
+    }
+
+    /// The W3C Trace Context of the span of the promotion of the thread this handle refers to,
+    /// to attach the spans of the audio processing to it. The span starts when the thread is
+    /// promoted and ends when it is demoted, both are logged with the `traceparent` of the span.
+    /// This is `None` for handles that weren't returned by a promotion, e.g. deserialized handles.
+    ///
+    /// The span is a child of the context passed to `RtPriorityRequest::with_trace_parent`, or
+    /// the root of a new trace.
+    #[cfg(feature = "otel")]
+    pub fn trace_context(&self) -> Option<RtTraceContext> {
+        self.trace_span.as_ref().map(|span| span.context)
+    }
+
+    /// Whether 90% of the real-time budget of the thread has been used since the budget timer
+    /// was last armed, see `RtPriorityRequest::use_timer_for_budget_monitoring`. This doesn't
+    /// block, and is always `false` if the timer wasn't requested.
+    pub fn budget_timer_expired(&self) -> bool {
+        self.budget_timer
+            .as_ref()
+            .is_some_and(|timer| timer.expired())
+    }
+
+    /// Arm the budget timer again, e.g. at the start of each audio callback: `RLIMIT_RTTIME`
+    /// counts the CPU time since the thread last blocked, while the timer counts the CPU time
+    /// since it was armed. This must be called on the thread this handle refers to, and does
+    /// nothing if the timer wasn't requested.
+    pub fn rearm_budget_timer(&self) -> Result<(), AudioThreadPriorityError> {
+        match &self.budget_timer {
+            Some(timer) => timer.arm(),
+            None => Ok(()),
+        }
+    }
+
+    /// Transfer up to `steal_us` microseconds of real-time budget from `other` to the thread this
+    /// handle refers to, e.g. from an idle input thread to an overloaded DSP thread.
+    ///
+    /// Both threads must be in the calling process. The budget of this thread is clamped to the
+    /// hard `RLIMIT_RTTIME` limit.
+    ///
+    /// This only changes the budgets recorded in the handles, e.g. as returned by
+    /// `budget_margin_us`: the kernel can't enforce a different budget for each thread.
+    /// `RLIMIT_RTTIME` is a per-process limit, that each thread is checked against: the soft limit
+    /// is set to the largest of the two budgets after the transfer, so `other` is not limited to
+    /// its reduced budget.
+    ///
+    /// Returns the amount of budget that was transferred, in microseconds.
+    pub fn steal_budget_from(
+        &mut self,
+        other: &mut RtPriorityHandleInternal,
+        steal_us: u64,
+    ) -> Result<u64, AudioThreadPriorityError> {
+        let pid = unsafe { libc::getpid() };
+        if self.thread_info.pid != pid || other.thread_info.pid != pid {
+            return Err(AudioThreadPriorityError::new(
+                "can only transfer budget between threads of the calling process",
+            ));
+        }
+
+        let max_us = rttime_hard_limit_us()?;
+
+        let stolen = steal_us
+            .min(other.budget_us)
+            .min(max_us.saturating_sub(self.budget_us));
+        let budget_us = self.budget_us + stolen;
+        let other_budget_us = other.budget_us - stolen;
+
+        set_limits(cmp::max(budget_us, other_budget_us), max_us)?;
+        self.budget_us = budget_us;
+        other.budget_us = other_budget_us;
+
+        Ok(stolen)
+    }
+
+    /// Create a controller recommending how much DSP work to do on this thread, depending on how
+    /// close it gets to its real-time budget. The controller also scales the soft
+    /// `RLIMIT_RTTIME` limit with the recommended fraction, see `AdaptiveBudgetHandle::sample`.
+    ///
+    /// This must be called on the thread this handle refers to.
+    pub fn adaptive_budget_controller(
+        &self,
+        strategy: AdaptiveStrategy,
+    ) -> Result<AdaptiveBudgetHandle, AudioThreadPriorityError> {
+        let limit = rttime_limit()?;
+        // rlim_t is not 64 bits on all platforms.
+        #[allow(clippy::unnecessary_cast)]
+        let budget_us = if limit.rlim_cur == libc::RLIM_INFINITY {
+            None
+        } else {
+            Some(limit.rlim_cur as u64)
+        };
+        let max_fraction = match strategy {
+            AdaptiveStrategy::LinearReduction { max_fraction, .. } => max_fraction,
+        };
+
+        Ok(AdaptiveBudgetHandle {
+            strategy,
+            budget_us,
+            // rlim_t is not 64 bits on all platforms.
+            #[allow(clippy::unnecessary_cast)]
+            max_rttime_us: limit.rlim_max as u64,
+            scaled_budget_us: budget_us,
+            last_cpu_time: thread_cpu_time()?,
+            current_fraction: max_fraction.clamp(0.0, 1.0),
+        })
+    }
+
+    /// The buffer telemetry samples are written to, by `write_lock_free_sample`. Samples can be
+    /// drained from another thread.
+    pub fn telemetry_buffer(&self) -> &RtTelemetryBuffer<f32> {
+        &self.telemetry
+    }
+
+    /// Write a telemetry sample without blocking, from the real-time thread. Returns `false` if the
+    /// sample was dropped because the telemetry buffer is full.
+    pub fn write_lock_free_sample(&self, value: f32) -> bool {
+        self.telemetry.write_lock_free_sample(value)
+    }
+
+    /// Record the latency of an audio callback, in microseconds, in the histogram returned by
+    /// `sample_callback_latency_histogram`. This is a single atomic increment, and can be called
+    /// from the real-time thread.
+    pub fn record_callback_latency_us(&self, duration_us: u64) {
+        self.callback_latency.record(duration_us);
+    }
+
+    /// A copy of the histogram of the callback latencies recorded with
+    /// `record_callback_latency_us`.
+    pub fn sample_callback_latency_histogram(&self) -> LatencyHistogram {
+        self.callback_latency.clone()
+    }
+
+    /// Estimate the probability that the next callback exceeds the real-time budget set at
+    /// promotion time, causing an xrun or a `SIGXCPU`, from the empirical distribution of the CPU
+    /// time of past callbacks, recorded in microseconds in `callback_time_distribution`, e.g.
+    /// `CallbackProfile::cpu_time_us` from `profile_one_callback`. Audio engines can increase
+    /// their buffer size when this goes above a threshold, such as 0.001.
+    ///
+    /// The times are assumed to be uniformly distributed within each bucket of the histogram.
+    /// This returns 0.0 if there is no budget or nothing has been recorded.
+    pub fn compute_missed_deadline_probability(
+        &self,
+        callback_time_distribution: &LatencyHistogram,
+    ) -> f64 {
+        let total = callback_time_distribution.count();
+        if self.budget_us == 0 || total == 0 {
+            return 0.0;
+        }
+        let budget_us = self.budget_us as f64;
+        let mut missed = 0.0;
+        for (bucket, count) in callback_time_distribution.buckets.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed) as f64;
+            let upper_us = (1u64 << bucket) as f64;
+            let lower_us = if bucket == 0 { 0.0 } else { upper_us / 2.0 };
+            if budget_us <= lower_us {
+                missed += count;
+            } else if budget_us < upper_us {
+                missed += count * (upper_us - budget_us) / (upper_us - lower_us);
+            }
+        }
+        (missed / total as f64).clamp(0.0, 1.0)
+    }
+
+    /// Get a C-compatible description of this handle.
+    // kernel_pid_t is not 64 bits on all platforms.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn as_raw_handle(&self) -> RawRtHandle {
+        RawRtHandle {
+            pid: self.thread_info.pid,
+            thread_id: self.thread_info.thread_id as i64,
+            policy: self.thread_info.policy,
+            priority: self.priority as i32,
+            budget_us: self.budget_us,
+        }
+    }
+
+    /// Reconstruct a handle from a description obtained with `as_raw_handle`.
+    ///
+    /// `RawRtHandle` doesn't contain the process-local thread id: if `raw` doesn't describe the
+    /// calling thread, the resulting handle cannot be passed to
+    /// `demote_current_thread_from_real_time`.
+    pub fn from_raw_handle(
+        raw: RawRtHandle,
+    ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+        if raw.pid <= 0 {
+            return Err(AudioThreadPriorityError::new("invalid pid"));
+        }
+        if raw.thread_id <= 0 {
+            return Err(AudioThreadPriorityError::new("invalid thread id"));
+        }
+        match raw.policy & !SCHED_RESET_ON_FORK {
+            libc::SCHED_OTHER
+            | libc::SCHED_FIFO
+            | libc::SCHED_RR
+            | libc::SCHED_BATCH
+            | libc::SCHED_IDLE => {}
+            _ => return Err(AudioThreadPriorityError::new("invalid scheduling policy")),
+        }
+        let max_priority = unsafe { libc::sched_get_priority_max(libc::SCHED_FIFO) };
+        if raw.priority < 0 || raw.priority > max_priority {
+            return Err(AudioThreadPriorityError::new("invalid priority"));
+        }
+
+        let is_current_thread = raw.pid == unsafe { libc::getpid() }
+            && raw.thread_id as kernel_pid_t == unsafe { libc::syscall(libc::SYS_gettid) };
+        let pthread_id = if is_current_thread {
+            unsafe { libc::pthread_self() }
+        } else {
+            0
+        };
+
+        let mut handle = RtPriorityHandleInternal::new(RtPriorityThreadInfoInternal {
+            thread_id: raw.thread_id as kernel_pid_t,
+            pthread_id,
+            pid: raw.pid,
+            policy: raw.policy,
+        });
+        handle.priority = raw.priority as u32;
+        handle.budget_us = raw.budget_us;
+
+        Ok(handle)
+    }
+
+    /// Describe this handle as environment variables, e.g. to pass it to a child process or a
+    /// shell script: `AUDIO_RT_PID`, `AUDIO_RT_TID`, `AUDIO_RT_POLICY`, `AUDIO_RT_PRIORITY` and
+    /// `AUDIO_RT_BUDGET_US`, all as decimal numbers.
+    pub fn export_as_env_vars(&self) -> HashMap<String, String> {
+        vec![
+            ("AUDIO_RT_PID", self.thread_info.pid.to_string()),
+            ("AUDIO_RT_TID", self.thread_info.thread_id.to_string()),
+            ("AUDIO_RT_POLICY", self.thread_info.policy.to_string()),
+            ("AUDIO_RT_PRIORITY", self.priority.to_string()),
+            ("AUDIO_RT_BUDGET_US", self.budget_us.to_string()),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+    }
+
+    /// Reconstruct a handle from the environment variables set from `export_as_env_vars`, with
+    /// the same validation as `from_raw_handle`.
+    ///
+    /// The environment can't be trusted to hold a valid pthread id, so like with `from_raw_handle`,
+    /// the resulting handle has none, and cannot be passed to
+    /// `demote_current_thread_from_real_time` unless it describes the calling thread. It can be
+    /// demoted with `demote_with_force`, which only uses the thread id.
+    pub fn from_env_vars() -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+        fn var<T: std::str::FromStr>(name: &str) -> Result<T, AudioThreadPriorityError> {
+            std::env::var(name)
+                .map_err(|_| AudioThreadPriorityError::new(&format!("{} not set", name)))?
+                .parse()
+                .map_err(|_| AudioThreadPriorityError::new(&format!("invalid {}", name)))
+        }
+        RtPriorityHandleInternal::from_raw_handle(RawRtHandle {
+            pid: var("AUDIO_RT_PID")?,
+            thread_id: var("AUDIO_RT_TID")?,
+            policy: var("AUDIO_RT_POLICY")?,
+            priority: var("AUDIO_RT_PRIORITY")?,
+            budget_us: var("AUDIO_RT_BUDGET_US")?,
+        })
+    }
+
+    /// Encode the fields of `as_raw_handle` in the layout of the `bincode` crate with its legacy
+    /// configuration: in declaration order, as little-endian fixed-size integers. The
+    /// `bincode-layout` feature doesn't depend on the `bincode` crate, it only reproduces its
+    /// layout, so that the bytes can be decoded by a peer that uses it, or by
+    /// `from_bincode_bytes`.
+    #[cfg(feature = "bincode-layout")]
+    pub fn to_bincode_bytes(&self) -> Result<Vec<u8>, AudioThreadPriorityError> {
+        let raw = self.as_raw_handle();
+        let mut bytes = Vec::with_capacity(BINCODE_HANDLE_SIZE);
+        bytes.extend_from_slice(&raw.pid.to_le_bytes());
+        bytes.extend_from_slice(&raw.thread_id.to_le_bytes());
+        bytes.extend_from_slice(&raw.policy.to_le_bytes());
+        bytes.extend_from_slice(&raw.priority.to_le_bytes());
+        bytes.extend_from_slice(&raw.budget_us.to_le_bytes());
+        Ok(bytes)
+    }
+
+    /// Reconstruct a handle from the bytes written by `to_bincode_bytes`, with the same
+    /// validation and limitations as `from_raw_handle`.
+    #[cfg(feature = "bincode-layout")]
+    pub fn from_bincode_bytes(
+        bytes: &[u8],
+    ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+        if bytes.len() != BINCODE_HANDLE_SIZE {
+            return Err(AudioThreadPriorityError::new("invalid handle size"));
+        }
+        RtPriorityHandleInternal::from_raw_handle(RawRtHandle {
+            pid: i32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            thread_id: i64::from_le_bytes(bytes[4..12].try_into().unwrap()),
+            policy: i32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            priority: i32::from_le_bytes(bytes[16..20].try_into().unwrap()),
+            budget_us: u64::from_le_bytes(bytes[20..28].try_into().unwrap()),
+        })
+    }
+
+    /// Log `action` (e.g. `promote`) to syslog at `level` (e.g. `libc::LOG_NOTICE`), with the
+    /// process id, thread id, real-time priority and budget of this handle, to keep an audit trail
+    /// on systems without journald.
+    #[cfg(feature = "syslog")]
+    pub fn log_to_syslog(&self, level: libc::c_int, action: &str) {
+        let message = format!(
+            "{}: pid {} tid {} priority {} budget {}us",
+            action, self.thread_info.pid, self.thread_info.thread_id, self.priority, self.budget_us
+        );
+        // Interior NULs would truncate the message.
+        let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+        unsafe {
+            libc::syslog(
+                level,
+                b"%s\0".as_ptr() as *const libc::c_char,
+                message.as_ptr(),
+            )
+        };
+    }
+
+    /// Open the connection to syslog used by `log_to_syslog`, with `ident` prepended to each
+    /// message, along with the process id. The connection is opened immediately, so that logging
+    /// works after a `chroot`.
+    ///
+    /// `ident` has to outlive the connection, since syslog keeps a pointer to it.
+    #[cfg(feature = "syslog")]
+    pub fn openlog_for_audio_rt(ident: &'static CStr) {
+        unsafe {
+            libc::openlog(
+                ident.as_ptr(),
+                libc::LOG_NDELAY | libc::LOG_PID,
+                libc::LOG_USER,
+            )
+        };
+    }
+
+    /// Pack the process id, thread id, real-time priority and budget (in microseconds) of this
+    /// handle into 16 bytes, as four `u32` in native byte order, in this order. This is meant to
+    /// be used as a value in an eBPF map, read by an eBPF program monitoring scheduler events.
+    ///
+    /// Budgets that don't fit in a `u32` are saturated.
+    #[cfg(feature = "bpf")]
+    pub fn to_bpf_map_entry(&self) -> [u8; 16] {
+        let fields = [
+            self.thread_info.pid as u32,
+            self.thread_info.thread_id as u32,
+            self.priority,
+            cmp::min(self.budget_us, u32::MAX as u64) as u32,
+        ];
+        let mut entry = [0; 16];
+        for (chunk, field) in entry.chunks_exact_mut(4).zip(fields.iter()) {
+            chunk.copy_from_slice(&field.to_ne_bytes());
+        }
+        entry
+    }
+
+    /// Insert or update the entry of this handle in the eBPF hash map `map_fd`, with the thread
+    /// id as a `u32` key, and `to_bpf_map_entry` as value. The map must have been created with a
+    /// 4-byte key and a 16-byte value.
+    #[cfg(feature = "bpf")]
+    pub fn write_to_bpf_map(&self, map_fd: RawFd) -> Result<(), AudioThreadPriorityError> {
+        let key = self.thread_info.thread_id as u32;
+        let value = self.to_bpf_map_entry();
+        let attr = BpfMapElemAttr {
+            map_fd: map_fd as u32,
+            key: &key as *const u32 as u64,
+            value: value.as_ptr() as u64,
+            flags: BPF_ANY,
+            ..Default::default()
+        };
+        if unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                BPF_MAP_UPDATE_ELEM,
+                &attr as *const BpfMapElemAttr,
+                std::mem::size_of::<BpfMapElemAttr>(),
+            )
+        } < 0
+        {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "bpf(BPF_MAP_UPDATE_ELEM)",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Write a description of this handle to shared memory at `shm_ptr + offset`, so that it can
+    /// be read by another process with `deserialize_from_mmap`. Returns the number of bytes
+    /// written, `MMAP_HANDLE_SIZE`, or 0 if `shm_ptr + offset` is not 8-byte aligned.
+    ///
+    /// The record is a sequence lock: its sequence number is made odd, the fields are written with
+    /// atomic stores, and the sequence number is made even again, so that a reader never accepts a
+    /// partially written handle, without a lock. There must be a single writer at a time for a
+    /// given location.
+    ///
+    /// # Safety
+    ///
+    /// `shm_ptr + offset` must point to at least `MMAP_HANDLE_SIZE` writable bytes.
+    pub unsafe fn serialize_for_mmap(&self, shm_ptr: *mut u8, offset: usize) -> usize {
+        let ptr = shm_ptr.add(offset);
+        if !(ptr as usize).is_multiple_of(MMAP_HANDLE_ALIGN) {
+            return 0;
+        }
+        let raw = self.as_raw_handle();
+        let sequence = &*(ptr.add(28) as *const AtomicU32);
+        // Odd while writing. 0 means that nothing has been written, skip it when wrapping around.
+        let writing = sequence.load(Ordering::Relaxed) | 1;
+        let written = match writing.wrapping_add(1) {
+            0 => MMAP_HANDLE_SNAPSHOT_SEQUENCE,
+            written => written,
+        };
+        sequence.store(writing, Ordering::Relaxed);
+        std::sync::atomic::fence(Ordering::Release);
+        (*(ptr as *const AtomicI64)).store(raw.thread_id, Ordering::Relaxed);
+        (*(ptr.add(8) as *const AtomicU64)).store(raw.budget_us, Ordering::Relaxed);
+        (*(ptr.add(16) as *const AtomicI32)).store(raw.pid, Ordering::Relaxed);
+        (*(ptr.add(20) as *const AtomicI32)).store(raw.policy, Ordering::Relaxed);
+        (*(ptr.add(24) as *const AtomicI32)).store(raw.priority, Ordering::Relaxed);
+        sequence.store(written, Ordering::Release);
+        MMAP_HANDLE_SIZE
+    }
+
+    /// Get a description of this handle in the format written by `serialize_for_mmap`, with an
+    /// even sequence number, e.g. to copy it to shared memory at once. A `SeqCst` fence is issued
+    /// first, so that all the changes made to the handle by the current thread before the call are
+    /// included.
+    ///
+    /// To take snapshots of a handle that is updated by another thread, use
+    /// `AtomicRtPriorityHandle`.
+    pub fn serialize_atomic_snapshot(&self) -> [u8; MMAP_HANDLE_SIZE] {
+        std::sync::atomic::fence(Ordering::SeqCst);
+        raw_handle_to_bytes(&self.as_raw_handle())
+    }
+
+    /// Read a handle written to shared memory with `serialize_for_mmap`, at `shm_ptr + offset`.
+    ///
+    /// This fails if no handle has been completely written there, if it is being written, or if
+    /// `shm_ptr + offset` is not 8-byte aligned. The caller can retry in the second case. As with
+    /// `from_raw_handle`, the resulting handle cannot be passed to
+    /// `demote_current_thread_from_real_time` unless it describes the calling thread.
+    ///
+    /// # Safety
+    ///
+    /// `shm_ptr + offset` must point to at least `MMAP_HANDLE_SIZE` readable bytes.
+    pub unsafe fn deserialize_from_mmap(
+        shm_ptr: *const u8,
+        offset: usize,
+    ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+        let ptr = shm_ptr.add(offset);
+        if !(ptr as usize).is_multiple_of(MMAP_HANDLE_ALIGN) {
+            return Err(AudioThreadPriorityError::new(
+                "misaligned shared memory handle",
+            ));
+        }
+        let sequence = &*(ptr.add(28) as *const AtomicU32);
+        let before = sequence.load(Ordering::Acquire);
+        if before == 0 {
+            return Err(AudioThreadPriorityError::new("no handle in shared memory"));
+        }
+        if before % 2 == 1 {
+            return Err(AudioThreadPriorityError::new(
+                "handle modified while being read",
+            ));
+        }
+        let raw = RawRtHandle {
+            thread_id: (*(ptr as *const AtomicI64)).load(Ordering::Relaxed),
+            budget_us: (*(ptr.add(8) as *const AtomicU64)).load(Ordering::Relaxed),
+            pid: (*(ptr.add(16) as *const AtomicI32)).load(Ordering::Relaxed),
+            policy: (*(ptr.add(20) as *const AtomicI32)).load(Ordering::Relaxed),
+            priority: (*(ptr.add(24) as *const AtomicI32)).load(Ordering::Relaxed),
+        };
+        // The writer could have started overwriting the handle while it was being read. The
+        // sequence number changes with each write, so this also catches complete rewrites.
+        std::sync::atomic::fence(Ordering::Acquire);
+        if sequence.load(Ordering::Relaxed) != before {
+            return Err(AudioThreadPriorityError::new(
+                "handle modified while being read",
+            ));
+        }
+        RtPriorityHandleInternal::from_raw_handle(raw)
+    }
+
+    /// Get how much memory of the process containing the thread this handle refers to is locked
+    /// in RAM, and how much can be locked.
+    ///
+    /// All the memory is considered locked if the locked memory covers the resident set of the
+    /// process, which is the case after a successful `mlockall(MCL_CURRENT | MCL_FUTURE)`.
+    pub fn memory_locking_status(&self) -> Result<MemlockStatus, AudioThreadPriorityError> {
+        let current_locked_kb = task_status_field(&self.thread_info, "VmLck")?;
+        let rss_kb = task_status_field(&self.thread_info, "VmRSS")?;
+
+        let mut limits = unsafe { std::mem::zeroed::<libc::rlimit>() };
+        if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limits) } < 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "getrlimit",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        let max_lockable_kb = if limits.rlim_cur == libc::RLIM_INFINITY {
+            u64::MAX
+        } else {
+            limits.rlim_cur / 1024
+        };
+
+        Ok(MemlockStatus {
+            all_locked: current_locked_kb > 0 && current_locked_kb >= rss_kb,
+            current_locked_kb,
+            max_lockable_kb,
+        })
+    }
+
+    /// Go through the steps of promoting the current thread with `request` without changing
+    /// anything: the limits of RTKit are queried, the priority and budget are computed and
+    /// clamped like they would be, and `RLIMIT_RTTIME` is checked, but RTKit is not asked to
+    /// promote the thread and no resource limit is changed.
+    ///
+    /// This is useful to diagnose why promotions fail on a given system.
+    pub fn dry_run(request: &RtPriorityRequest) -> DryRunResult {
+        simulate_promotion(&RTKIT, request)
+    }
+
+    /// Check the thread this handle refers to against a real-time scheduling policy, e.g. in CI
+    /// to verify that the real-time parameters of an audio engine comply with the policy of the
+    /// systems it is deployed on. The scheduling policy and priority are queried from the kernel,
+    /// the budget is the one set at promotion time.
+    ///
+    /// If the scheduling or the memory locking status can't be queried, the corresponding rules
+    /// are reported as violated.
+    pub fn validate_against_policy(&self, policy: &RtPolicy) -> ValidationResult {
+        let mut violations = Vec::new();
+        let tid = self.thread_info.thread_id as libc::pid_t;
+        let raw_policy = unsafe { libc::sched_getscheduler(tid) };
+        let mut param = libc::sched_param { sched_priority: 0 };
+        if raw_policy < 0 || unsafe { libc::sched_getparam(tid, &mut param) } < 0 {
+            violations.push(PolicyViolation::SchedulingUnknown);
+        } else {
+            let priority = param.sched_priority as u32;
+            if priority > policy.max_priority {
+                violations.push(PolicyViolation::PriorityTooHigh {
+                    priority,
+                    max_priority: policy.max_priority,
+                });
+            }
+            match SchedulingPolicy::from_raw(raw_policy & !SCHED_RESET_ON_FORK) {
+                Some(scheduling) if policy.allowed_policies.contains(&scheduling) => {}
+                scheduling => violations.push(PolicyViolation::PolicyNotAllowed(scheduling)),
+            }
+        }
+        if self.budget_us > policy.max_budget_us {
+            violations.push(PolicyViolation::BudgetTooLarge {
+                budget_us: self.budget_us,
+                max_budget_us: policy.max_budget_us,
+            });
+        }
+        if policy.require_mlockall
+            && !self
+                .memory_locking_status()
+                .is_ok_and(|status| status.all_locked)
+        {
+            violations.push(PolicyViolation::MemoryNotLocked);
+        }
+        ValidationResult {
+            passed: violations.is_empty(),
+            violations,
+        }
+    }
+
+    /// Check that the memory of the process has been locked, if
+    /// `RtPriorityRequest::with_memory_lock` was used to promote the thread this handle refers
+    /// to. This always succeeds otherwise.
+    pub fn verify_memory_locked(&self) -> Result<(), AudioThreadPriorityError> {
+        if !self.memory_lock_requested {
+            return Ok(());
+        }
+        let status = self.memory_locking_status()?;
+        if !status.all_locked {
+            return Err(AudioThreadPriorityError::new(&format!(
+                "memory not locked: {}kB locked, {}kB lockable",
+                status.current_locked_kb, status.max_lockable_kb
+            )));
+        }
+        Ok(())
+    }
+
+    /// Get the memory usage and the page fault counts of the thread this handle refers to.
+    pub fn memory_usage_snapshot(&self) -> Result<MemorySnapshot, AudioThreadPriorityError> {
+        let fields = read_task_stat(&self.thread_info)?;
+        let minor_faults = task_stat_field(&fields, 10)?;
+        let major_faults = task_stat_field(&fields, 12)?;
+
+        let smaps = fs::read_to_string(task_proc_path(&self.thread_info).join("smaps_rollup"))
+            .map_err(|e| {
+                AudioThreadPriorityError::new_with_inner("read smaps_rollup", Box::new(e))
+            })?;
+        let rss_kb = smaps
+            .lines()
+            .find_map(|line| line.strip_prefix("Rss:"))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+            .ok_or_else(|| AudioThreadPriorityError::new("no Rss in smaps_rollup"))?;
+
+        Ok(MemorySnapshot {
+            rss_kb,
+            minor_faults,
+            major_faults,
+        })
+    }
+
+    /// List the memory mappings of the process containing the thread this handle refers to that
+    /// are at least partially locked in RAM, from `/proc/<pid>/task/<tid>/smaps`, e.g. to check
+    /// that the working set of the thread is locked after `mlockall` before the first callback.
+    pub fn get_mmap_locked_regions(&self) -> Result<Vec<LockedRegion>, AudioThreadPriorityError> {
+        let smaps = fs::read_to_string(task_proc_path(&self.thread_info).join("smaps"))
+            .map_err(|e| AudioThreadPriorityError::new_with_inner("read smaps", Box::new(e)))?;
+        parse_smaps_locked_regions(&smaps)
+    }
+
+    /// Call `callback` (e.g. a single audio callback), and measure what it cost.
+    ///
+    /// This has to be called on the thread this handle refers to. `budget_utilization` is the CPU
+    /// time used by `callback` divided by the soft real-time budget set at promotion time, or 0.0
+    /// if there is no budget.
+    ///
+    /// If `RtPriorityRequest::install_dmb_on_entry` was used, a full memory barrier is issued
+    /// before and after `callback`.
+    pub fn profile_one_callback<F: FnOnce()>(
+        &self,
+        callback: F,
+    ) -> Result<CallbackProfile, AudioThreadPriorityError> {
+        if unsafe { libc::syscall(libc::SYS_gettid) } != self.thread_info.thread_id {
+            return Err(AudioThreadPriorityError::new(
+                "profile_one_callback called on another thread",
+            ));
+        }
+        let switches_before = task_status_field(&self.thread_info, "nonvoluntary_ctxt_switches")?;
+        let faults_before = task_stat_field(&read_task_stat(&self.thread_info)?, 10)?;
+
+        let cpu_time_before = thread_cpu_time()?;
+        let start = Instant::now();
+        if self.dmb_on_entry {
+            std::sync::atomic::fence(Ordering::SeqCst);
+        }
+        callback();
+        if self.dmb_on_entry {
+            std::sync::atomic::fence(Ordering::SeqCst);
+        }
+        let wall_time = start.elapsed();
+        let cpu_time = thread_cpu_time()?.saturating_sub(cpu_time_before);
+
+        let switches_after = task_status_field(&self.thread_info, "nonvoluntary_ctxt_switches")?;
+        let faults_after = task_stat_field(&read_task_stat(&self.thread_info)?, 10)?;
+
+        let cpu_time_us = cpu_time.as_micros() as u64;
+        let budget_utilization = if self.budget_us > 0 {
+            cpu_time_us as f64 / self.budget_us as f64
+        } else {
+            0.0
+        };
+        #[cfg(feature = "prometheus-text")]
+        self.last_budget_utilization
+            .store(budget_utilization.to_bits(), Ordering::Relaxed);
+        Ok(CallbackProfile {
+            wall_time_us: wall_time.as_micros() as u64,
+            cpu_time_us,
+            involuntary_switches: switches_after.saturating_sub(switches_before),
+            minor_faults: faults_after.saturating_sub(faults_before),
+            budget_utilization,
+        })
+    }
+
+    /// The metrics of real-time audio threads, in the Prometheus text exposition format, e.g. to
+    /// be served on a `/metrics` endpoint:
+    ///
+    /// - `audio_rt_promotion_count_total` and `audio_rt_demotion_count_total`: the number of
+    ///   threads promoted and demoted by this process.
+    /// - `audio_rt_jitter_microseconds`: the histogram of the callback latencies of this thread,
+    ///   see `record_callback_latency_us`.
+    /// - `audio_rt_budget_utilization_ratio`: the budget utilization last measured by
+    ///   `profile_one_callback` on this thread.
+    ///
+    /// The metrics are updated atomically on every promotion, demotion, recorded latency and
+    /// profiled callback, this only reads them. The `prometheus-text` feature only produces the
+    /// exposition text, and doesn't depend on the `prometheus` crate: the caller serves it or
+    /// pushes it to a gateway.
+    #[cfg(feature = "prometheus-text")]
+    pub fn latency_report_to_prometheus(&self) -> String {
+        let mut text = String::new();
+        for (name, help, count) in [
+            (
+                "audio_rt_promotion_count_total",
+                "Number of threads promoted to real-time.",
+                &PROMOTION_COUNT,
+            ),
+            (
+                "audio_rt_demotion_count_total",
+                "Number of threads demoted from real-time.",
+                &DEMOTION_COUNT,
+            ),
+        ] {
+            text.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+                name,
+                help,
+                name,
+                name,
+                count.load(Ordering::Relaxed)
+            ));
+        }
+        text.push_str(&self.callback_latency.prometheus_text(
+            "audio_rt_jitter_microseconds",
+            "Latency of the audio callbacks, in microseconds.",
+        ));
+        const UTILIZATION: &str = "audio_rt_budget_utilization_ratio";
+        text.push_str(&format!(
+            "# HELP {} Fraction of the real-time budget used by the last profiled callback.\n\
+             # TYPE {} gauge\n{} {}\n",
+            UTILIZATION,
+            UTILIZATION,
+            UTILIZATION,
+            f64::from_bits(self.last_budget_utilization.load(Ordering::Relaxed))
+        ));
+        text
+    }
+
+    /// The scheduler statistics of the thread at the time it was promoted, if they were available,
+    /// see `ExtendedSchedulerInfo`. This is `None` for handles that weren't created by promoting a
+    /// thread.
+    pub fn extended_scheduler_info(&self) -> Option<ExtendedSchedulerInfo> {
+        self.extended_info_at_promotion
+    }
+
+    /// The number of major page faults of the thread since it was promoted. Real-time threads
+    /// should not cause major page faults (i.e. wait for disk I/O): a non-zero value indicates
+    /// a serious problem.
+    pub fn major_faults_since_promotion(&self) -> Result<u64, AudioThreadPriorityError> {
+        Ok(major_faults(&self.thread_info)?.saturating_sub(self.major_faults_at_promotion))
+    }
+
+    /// The fraction of the last-level cache references of the thread this handle refers to that
+    /// missed the cache, since it was promoted, from hardware performance counters. Preemptions by
+    /// other threads evict the data of the real-time thread, and increase its cache miss rate.
+    ///
+    /// The counters are opened when the thread is promoted with `perf_event_open`, which requires
+    /// `CAP_PERFMON` (Linux 5.8+) or `kernel.perf_event_paranoid` to be at most 1, and a CPU with
+    /// performance counters that the kernel supports: this fails otherwise.
+    #[cfg(feature = "perf-events")]
+    pub fn estimate_cache_miss_rate(&self) -> Result<f64, AudioThreadPriorityError> {
+        let counters = self.cache_counters.as_ref().ok_or_else(|| {
+            AudioThreadPriorityError::new("the cache performance counters are not available")
+        })?;
+        let references = read_perf_counter(counters.references)?;
+        let misses = read_perf_counter(counters.misses)?;
+        if references == 0 {
+            return Ok(0.0);
+        }
+        Ok(misses as f64 / references as f64)
+    }
+
+    /// The number of times the thread this handle refers to has been migrated to another CPU,
+    /// read from `/proc/<pid>/task/<tid>/sched`, that requires a kernel built with
+    /// `CONFIG_SCHED_DEBUG`. Migrations cause cache misses and latency spikes.
+    pub fn cpu_migration_count(&self) -> Result<u64, AudioThreadPriorityError> {
+        cpu_migrations(&self.thread_info)
+    }
+
+    /// The number of CPU migrations of the thread since it was promoted, see
+    /// `cpu_migration_count`. A high value indicates that the CPU affinity of the thread is not
+    /// enforced, or that it runs on CPUs outside of its affinity mask.
+    pub fn migrations_since_promotion(&self) -> Result<u64, AudioThreadPriorityError> {
+        Ok(cpu_migrations(&self.thread_info)?.saturating_sub(self.migrations_at_promotion))
+    }
+
+    /// The CPUs the thread this handle refers to can actually run on: the intersection of its
+    /// CPU affinity, of the CPUs of its cpuset cgroup, and of the online CPUs. This can be
+    /// smaller than the affinity set with `set_numa_affinity` or a `Transaction`.
+    ///
+    /// Both cgroup v1 and v2 are supported. The cgroup is ignored if it has no cpuset, or if it
+    /// can't be read.
+    pub fn get_effective_cpu_set(&self) -> Result<CpuSet, AudioThreadPriorityError> {
+        let mut cpus = get_thread_affinity(self.thread_info.thread_id)?;
+        if let Some(cgroup_cpus) = cgroup_cpus(&self.thread_info) {
+            cpus.retain(|cpu| cgroup_cpus.contains(cpu));
+        }
+        let online = fs::read_to_string("/sys/devices/system/cpu/online").map_err(|e| {
+            AudioThreadPriorityError::new_with_inner("read online cpus", Box::new(e))
+        })?;
+        let online = parse_cpu_list(&online)?;
+        cpus.retain(|cpu| online.contains(cpu));
+        Ok(CpuSet { cpus })
+    }
+
+    /// Only allow the thread this handle refers to to run on the CPUs of NUMA node `node`.
+    ///
+    /// This can be called again when the NUMA topology changes. When the thread is demoted, its
+    /// CPU affinity is restored to what it was at promotion time, not before the last call.
+    #[cfg(feature = "numa")]
+    pub fn set_numa_affinity(
+        &mut self,
+        node: u32,
+    ) -> Result<CpuAffinityHandle, AudioThreadPriorityError> {
+        let tid = self.thread_info.thread_id;
+        let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+        let cpus = parse_cpu_list(&fs::read_to_string(&path).map_err(|e| {
+            AudioThreadPriorityError::new_with_inner(&format!("read {}", path), Box::new(e))
+        })?)?;
+        if cpus.is_empty() {
+            return Err(AudioThreadPriorityError::new(&format!(
+                "NUMA node {} has no cpu",
+                node
+            )));
+        }
+
+        if self.original_affinity.is_none() {
+            self.original_affinity = Some(get_thread_affinity(tid)?);
+        }
+        set_thread_affinity(tid, &cpus)?;
+        self.affinity = Some(cpus.clone());
+
+        Ok(CpuAffinityHandle { cpus })
+    }
+
+    /// Hint that the thread this handle refers to and the thread `other` refers to work together
+    /// (e.g. a producer and a consumer of the same audio buffers) and should share a physical
+    /// core and its L1 cache: both threads are only allowed to run on the logical CPUs of the
+    /// physical core this thread last ran on, from
+    /// `/sys/devices/system/cpu/cpu<N>/topology/thread_siblings_list`. If the affinity of `other`
+    /// can't be set, the affinity of this thread is restored.
+    ///
+    /// The maximum utilization of both threads is also clamped to half the capacity of a CPU
+    /// (`SCHED_FLAG_UTIL_CLAMP_MAX`), so that they don't overload the core they share. This
+    /// requires a kernel built with `CONFIG_UCLAMP_TASK`: a warning is logged if it fails. With the
+    /// `schedutil` cpufreq governor, this also caps the frequency of the core when only these
+    /// threads run on it, which makes their callbacks slower: don't use this for threads that
+    /// need more than half the capacity of a CPU.
+    ///
+    /// As with `set_numa_affinity`, the CPU affinity and the utilization clamp of each thread are
+    /// restored when it is demoted.
+    #[cfg(feature = "numa")]
+    pub fn add_co_located_thread_hint(
+        &mut self,
+        other: &mut RtPriorityHandleInternal,
+    ) -> Result<(CpuAffinityHandle, CpuAffinityHandle), AudioThreadPriorityError> {
+        let cpu = task_stat_field(&read_task_stat(&self.thread_info)?, 39)?;
+        let path = format!(
+            "/sys/devices/system/cpu/cpu{}/topology/thread_siblings_list",
+            cpu
+        );
+        let cpus = parse_cpu_list(&fs::read_to_string(&path).map_err(|e| {
+            AudioThreadPriorityError::new_with_inner(&format!("read {}", path), Box::new(e))
+        })?)?;
+        if cpus.is_empty() {
+            return Err(AudioThreadPriorityError::new(&format!(
+                "cpu {} has no sibling",
+                cpu
+            )));
+        }
+
+        let tid = self.thread_info.thread_id;
+        let affinity = get_thread_affinity(tid)?;
+        set_thread_affinity(tid, &cpus)?;
+        let other_tid = other.thread_info.thread_id;
+        let r = get_thread_affinity(other_tid).and_then(|other_affinity| {
+            set_thread_affinity(other_tid, &cpus)?;
+            Ok(other_affinity)
+        });
+        let other_affinity = match r {
+            Ok(other_affinity) => other_affinity,
+            Err(e) => {
+                if let Err(e) = set_thread_affinity(tid, &affinity) {
+                    warn!("Could not restore the CPU affinity: {}", e);
+                }
+                return Err(e);
+            }
+        };
+
+        for (handle, affinity) in [(&mut *self, affinity), (&mut *other, other_affinity)] {
+            let tid = handle.thread_info.thread_id as libc::pid_t;
+            handle.original_affinity.get_or_insert(affinity);
+            handle.affinity = Some(cpus.clone());
+            let r = util_clamp_max(tid).and_then(|util_max| {
+                set_util_clamp_max(tid, CO_LOCATED_UTIL_CLAMP_MAX)?;
+                Ok(util_max)
+            });
+            match r {
+                Ok(util_max) => {
+                    handle.original_util_clamp_max.get_or_insert(util_max);
+                }
+                Err(e) => warn!("Could not clamp the utilization of thread {}: {}", tid, e),
+            }
+        }
+
+        Ok((
+            CpuAffinityHandle { cpus: cpus.clone() },
+            CpuAffinityHandle { cpus },
+        ))
+    }
+
+    /// Fork the calling process, and promote the thread of the child process to real-time, with the
+    /// same priority and budget as the thread this handle refers to.
+    ///
+    /// In the parent, this returns `RtForkResult::Parent`, with this handle and a handle to the
+    /// thread of the child, that can be used to demote it. In the child, this returns
+    /// `RtForkResult::Child`, with a handle to its own thread: the thread this handle refers to
+    /// doesn't exist there.
+    ///
+    /// The child promotes itself via RTKit before returning, on a new D-Bus connection: the
+    /// connections of the parent, including the ones of an installed `DbusPool`, are not used in
+    /// the child. If this fails, the child sends the error to the parent and exits with status 1,
+    /// and the parent reaps it and returns the error, so only the parent returns. As with any
+    /// `fork`, this is only reliable if the calling process has a single thread.
+    pub fn fork(self) -> Result<RtForkResult, AudioThreadPriorityError> {
+        const INFO_SIZE: usize = std::mem::size_of::<RtPriorityThreadInfoInternal>();
+        let os_error = |what: &str| {
+            AudioThreadPriorityError::new_with_inner(what, Box::new(OSError::last_os_error()))
+        };
+
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(os_error("pipe2"));
+        }
+        let [read_fd, write_fd] = fds;
+
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            let e = os_error("fork");
+            unsafe {
+                libc::close(read_fd);
+                libc::close(write_fd);
+            }
+            return Err(e);
+        }
+
+        if pid == 0 {
+            // Child: promote the only thread, and send 0 and its thread info to the parent, or 1
+            // and the error.
+            unsafe { libc::close(read_fd) };
+            let mut pipe = unsafe { fs::File::from_raw_fd(write_fd) };
+            let child = get_current_thread_info_internal().and_then(|info| {
+                let mut child = RtPriorityHandleInternal::new(info);
+                child.latency_target_us = self.latency_target_us;
+                child.budget_us = set_real_time_limit(&RTKIT, self.budget_us)?;
+                child.priority = self.priority;
+                child.request = self.request.clone();
+                rtkit_set_realtime(
+                    &RTKIT,
+                    info.thread_id as u64,
+                    info.pid as u64,
+                    self.priority,
+                )
+                .map_err(|e| {
+                    AudioThreadPriorityError::new_with_inner("Thread promotion error", e)
+                })?;
+                child.promoted_at = Some(Instant::now());
+                let mut message = vec![0];
+                message.extend_from_slice(&info.serialize());
+                pipe.write_all(&message)
+                    .map_err(|e| AudioThreadPriorityError::new_with_inner("write", Box::new(e)))?;
+                Ok(child)
+            });
+            // The resources of this handle (timers, counters, registrations) belong to the parent.
+            std::mem::forget(self);
+            match child {
+                Ok(child) => return Ok(RtForkResult::Child(child)),
+                Err(e) => {
+                    let _ = pipe.write_all(&[&[1], e.to_string().as_bytes()].concat());
+                    unsafe { libc::_exit(1) };
+                }
+            }
+        }
+
+        // Parent: wait for the result of the promotion in the child.
+        unsafe { libc::close(write_fd) };
+        let mut message = Vec::new();
+        let read = unsafe { fs::File::from_raw_fd(read_fd) }.read_to_end(&mut message);
+        if read.is_err() || message.len() != 1 + INFO_SIZE || message[0] != 0 {
+            // The child failed, or is in an unknown state: make sure it doesn't keep running the
+            // code of the caller, and reap it.
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
+                libc::waitpid(pid, std::ptr::null_mut(), 0);
+            }
+            let reason = match message.split_first() {
+                Some((1, error)) => String::from_utf8_lossy(error).into_owned(),
+                _ => "no thread info received".to_string(),
+            };
+            return Err(AudioThreadPriorityError::new(&format!(
+                "could not promote the thread of child process {}: {}",
+                pid, reason
+            )));
+        }
+
+        let mut info = [0u8; INFO_SIZE];
+        info.copy_from_slice(&message[1..]);
+        let mut child =
+            RtPriorityHandleInternal::new(RtPriorityThreadInfoInternal::deserialize(info));
+        child.latency_target_us = self.latency_target_us;
+        child.budget_us = self.budget_us;
+        child.priority = self.priority;
+        child.request = self.request.clone();
+        child.promoted_at = Some(Instant::now());
+        Ok(RtForkResult::Parent {
+            parent: self,
+            child,
+        })
+    }
+
+    /// Apply the changes queued by `f` on a `Transaction` to the thread this handle refers to, in
+    /// order. If one of them fails, the changes already applied are reverted, in reverse order, so
+    /// that either all the changes are applied or none are.
+    ///
+    /// Priorities and budgets are changed via RTKit, and are still subject to its limits. Rolling
+    /// back a change from priority 0 demotes the thread with `sched_setscheduler`, and
+    /// `RLIMIT_RTTIME` is rolled back to exactly what it was, even if it wasn't set.
+    #[cfg(feature = "transactions")]
+    pub fn transaction<F: FnOnce(&mut Transaction)>(
+        &mut self,
+        f: F,
+    ) -> Result<(), AudioThreadPriorityError> {
+        let mut transaction = Transaction {
+            changes: Vec::new(),
+        };
+        f(&mut transaction);
+
+        let mut applied: Vec<TransactionChange> = Vec::new();
+        for change in transaction.changes {
+            match self.apply_change(&change) {
+                Ok(previous) => applied.push(previous),
+                Err(e) => {
+                    for previous in applied.iter().rev() {
+                        if let Err(e) = self.apply_change(previous) {
+                            warn!("Could not roll back {:?}: {}", previous, e);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply `change`, and return the change that reverts it.
+    #[cfg(feature = "transactions")]
+    fn apply_change(
+        &mut self,
+        change: &TransactionChange,
+    ) -> Result<TransactionChange, AudioThreadPriorityError> {
+        let tid = self.thread_info.thread_id;
+        match change {
+            // RTKit can't demote a thread, e.g. when rolling back a promotion.
+            TransactionChange::Priority(0) => {
+                reset_scheduler(tid)?;
+                let previous = self.priority;
+                self.priority = 0;
+                Ok(TransactionChange::Priority(previous))
+            }
+            TransactionChange::Priority(priority) => {
+                rtkit_set_realtime(&RTKIT, tid as u64, self.thread_info.pid as u64, *priority)
+                    .map_err(|e| {
+                        AudioThreadPriorityError::new_with_inner("Thread promotion error", e)
+                    })?;
+                let previous = self.priority;
+                self.priority = *priority;
+                Ok(TransactionChange::Priority(previous))
+            }
+            TransactionChange::BudgetUs(budget_us) => {
+                let previous = self.rttime_limit_change()?;
+                self.budget_us = set_real_time_limit(&RTKIT, *budget_us)?;
+                Ok(previous)
+            }
+            TransactionChange::RttimeLimit {
+                limit: (rlim_cur, rlim_max),
+                budget_us,
+            } => {
+                let previous = self.rttime_limit_change()?;
+                // `set_limits` converts from u64, that can hold any rlim_t.
+                set_limits(*rlim_cur, *rlim_max)?;
+                self.budget_us = *budget_us;
+                Ok(previous)
+            }
+            TransactionChange::Affinity(cpus) => {
+                let previous = get_thread_affinity(tid)?;
+                set_thread_affinity(tid, cpus)?;
+                self.affinity = Some(cpus.clone());
+                Ok(TransactionChange::Affinity(previous))
+            }
+        }
+    }
+
+    /// The change that restores the current `RLIMIT_RTTIME` and budget of the handle.
+    #[cfg(feature = "transactions")]
+    fn rttime_limit_change(&self) -> Result<TransactionChange, AudioThreadPriorityError> {
+        let limit = rttime_limit()?;
+        // rlim_t is not 64 bits on all platforms.
+        #[allow(clippy::unnecessary_cast)]
+        Ok(TransactionChange::RttimeLimit {
+            limit: (limit.rlim_cur as u64, limit.rlim_max as u64),
+            budget_us: self.budget_us,
+        })
+    }
+
+    /// Raise the real-time priority of the thread this handle refers to to `new_priority`, e.g. to
+    /// handle a high-urgency callback. `new_priority` is clamped to the maximum priority allowed by
+    /// RTKit.
+    ///
+    /// This consumes the handle and returns a new one, that still restores the scheduling policy
+    /// the thread had before it was first promoted when demoted.
+    pub fn upgrade_to_higher_priority(
+        mut self,
+        new_priority: u32,
+    ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+        let (max_prio, _, _) = get_limits(&RTKIT)?;
+        let new_priority = cmp::min(new_priority as i64, max_prio) as u32;
+        if new_priority <= self.priority {
+            return Err(AudioThreadPriorityError::new(&format!(
+                "priority {} is not higher than the current priority {}",
+                new_priority, self.priority
+            )));
+        }
+        rtkit_set_realtime(
+            &RTKIT,
+            self.thread_info.thread_id as u64,
+            self.thread_info.pid as u64,
+            new_priority,
+        )
+        .map_err(|e| AudioThreadPriorityError::new_with_inner("Thread promotion error", e))?;
+        self.priority = new_priority;
+        Ok(self)
+    }
+
+    /// Promote the current thread to real-time like `promote_current_thread_to_real_time`, making
+    /// sure that the promotions of the process made with this function don't exceed
+    /// `rate_per_sec` per second, e.g. to stay under the per-user rate limit of RTKit when a
+    /// thread pool is started.
+    ///
+    /// The limit is a token bucket holding up to `rate_per_sec` tokens, shared by all the calls
+    /// in the process. When it is empty, the calling thread sleeps, still with its normal
+    /// priority, until a token is available.
+    pub fn promote_with_token_bucket(
+        request: &RtPriorityRequest,
+        rate_per_sec: u32,
+    ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+        /// The time at which the bucket is full again, in nanoseconds of `CLOCK_MONOTONIC`.
+        static BUCKET_FULL_AT_NS: AtomicU64 = AtomicU64::new(0);
+
+        if rate_per_sec == 0 {
+            return Err(AudioThreadPriorityError::new("promotion rate is zero"));
+        }
+        let interval_ns = 1_000_000_000 / rate_per_sec as u64;
+        let capacity_ns = interval_ns * rate_per_sec as u64;
+        loop {
+            let now = monotonic_now_ns();
+            let current = BUCKET_FULL_AT_NS.load(Ordering::Relaxed);
+            let full_at = current.max(now);
+            // Taking a token delays the time the bucket is full by one interval, a token is
+            // available as long as that's less than a full bucket away.
+            let wait_ns = (full_at + interval_ns).saturating_sub(now + capacity_ns);
+            if wait_ns > 0 {
+                std::thread::sleep(Duration::from_nanos(wait_ns));
+                continue;
+            }
+            if BUCKET_FULL_AT_NS
+                .compare_exchange_weak(
+                    current,
+                    full_at + interval_ns,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                break;
+            }
+        }
+        promote_current_thread_to_real_time_internal(request)
+    }
+
+    /// Promote the current thread to real-time without going through RTKit, by calling
+    /// `sched_setscheduler` directly with `SCHED_FIFO`. This is useful on systems without D-Bus,
+    /// and requires `CAP_SYS_NICE` or a high enough `RLIMIT_RTPRIO`. If the promotion isn't
+    /// permitted, the error message contains the `setcap` command to grant `CAP_SYS_NICE` to the
+    /// executable.
+    ///
+    /// This also sets the timer slack of the thread to 1ns with `prctl(PR_SET_TIMERSLACK)`, for
+    /// more precise wake-ups. Only the buffer size and sample-rate of `request` are used.
+    pub fn try_promote_without_dbus(
+        request: &RtPriorityRequest,
+    ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+        Self::promote_current_thread_with(request, |handle| {
+            set_sched_fifo(handle.thread_info.thread_id as libc::pid_t, handle.priority)
+        })
+    }
+
+    /// Promote the current thread like `try_promote_without_dbus`, with `set_scheduler` switching
+    /// the thread of the handle to a real-time policy. `RLIMIT_RTTIME` is restored if
+    /// `set_scheduler` fails, and nothing else has been changed at that point.
+    fn promote_current_thread_with<F>(
+        request: &RtPriorityRequest,
+        set_scheduler: F,
+    ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError>
+    where
+        F: FnOnce(&RtPriorityHandleInternal) -> Result<(), AudioThreadPriorityError>,
+    {
+        if request.audio_samplerate_hz == 0 {
+            return Err(AudioThreadPriorityError::new("sample rate is zero"));
+        }
+        let thread_info = get_current_thread_info_internal()?;
+        let mut handle = RtPriorityHandleInternal::new(thread_info);
+        handle.latency_target_us =
+            buffer_duration_us(request.audio_buffer_frames, request.audio_samplerate_hz);
+        handle.dmb_on_entry = request.dmb_on_entry;
+        handle.request = Some(request.clone());
+
+        let original_rttime_limit = rttime_limit()?;
+        #[allow(clippy::useless_conversion)]
+        let max_rttime: u64 = original_rttime_limit.rlim_max.into();
+        let budget_us = cmp::min(request_budget_us(request)?, max_rttime);
+        set_limits(budget_us, max_rttime)?;
+        handle.budget_us = budget_us;
+
+        handle.major_faults_at_promotion = major_faults(&thread_info).unwrap_or(0);
+        handle.migrations_at_promotion = cpu_migrations(&thread_info).unwrap_or(0);
+        // Only available with CONFIG_SCHED_DEBUG.
+        handle.extended_info_at_promotion = ExtendedSchedulerInfo::read(&thread_info).ok();
+        handle.affinity_at_promotion = get_thread_affinity(thread_info.thread_id).ok();
+        #[cfg(feature = "numa")]
+        {
+            handle.original_affinity = handle.affinity_at_promotion.clone();
+        }
+        handle.priority = RT_PRIO_DEFAULT;
+        if let Err(e) = set_scheduler(&handle) {
+            restore_rttime_limit(&original_rttime_limit);
+            return Err(e);
+        }
+        handle.promoted_at = Some(Instant::now());
+        if request.measure_initial_jitter {
+            handle.initial_jitter_p99_us = Some(measure_scheduling_jitter_p99_us());
+        }
+
+        set_min_timer_slack();
+        #[cfg(feature = "prometheus-text")]
+        PROMOTION_COUNT.fetch_add(1, Ordering::Relaxed);
+        Ok(handle)
+    }
+
+    /// Promote the current thread with the first method that works, in this order, and return
+    /// the method used:
+    ///
+    /// - `SCHED_DEADLINE` with `sched_setattr`, if the `sched-deadline` feature is enabled and
+    ///   `RtPriorityRequest::prefer_deadline` was used. The period and the deadline are the
+    ///   duration of an audio buffer, and the runtime half of it. This requires `CAP_SYS_NICE`.
+    /// - `SCHED_RR` via RTKit, see `promote_current_thread_to_real_time`.
+    /// - `SCHED_FIFO` with `sched_setscheduler`, see `try_promote_without_dbus`.
+    /// - `SCHED_FIFO` with `chrt`, run with `pkexec`: polkit usually asks the user to
+    ///   authenticate.
+    ///
+    /// A warning is logged for each method that fails, and the error of the last one is returned
+    /// if they all fail.
+    pub fn promote_with_fallback_chain(
+        request: &RtPriorityRequest,
+    ) -> Result<(RtPriorityHandleInternal, PromotionMethod), AudioThreadPriorityError> {
+        #[cfg(feature = "sched-deadline")]
+        if request.prefer_deadline {
+            // This is done as the scheduler step of the promotion, so that `RLIMIT_RTTIME` is
+            // restored if it fails, before anything else is changed.
+            let deadline_params = |handle: &RtPriorityHandleInternal| {
+                let period_us = handle.latency_target_us;
+                let runtime_us = cmp::max((period_us as f64 * DEADLINE_RUNTIME_FRACTION) as u64, 1);
+                (runtime_us, period_us, period_us)
+            };
+            let r = Self::promote_current_thread_with(request, |handle| {
+                let (runtime_us, deadline_us, period_us) = deadline_params(handle);
+                set_sched_deadline(
+                    handle.thread_info.thread_id,
+                    runtime_us,
+                    deadline_us,
+                    period_us,
+                )
+            });
+            match r {
+                Ok(mut handle) => {
+                    handle.deadline_params = Some(deadline_params(&handle));
+                    return Ok((handle, PromotionMethod::Deadline));
+                }
+                Err(e) => warn!("Could not use SCHED_DEADLINE: {}", e),
+            }
+        }
+        match promote_current_thread_to_real_time_internal(request) {
+            Ok(handle) => return Ok((handle, PromotionMethod::Rtkit)),
+            Err(e) => warn!("Could not promote via RTKit: {}", e),
+        }
+        match Self::try_promote_without_dbus(request) {
+            Ok(handle) => return Ok((handle, PromotionMethod::SchedSetscheduler)),
+            Err(e) => warn!("Could not promote with sched_setscheduler: {}", e),
+        }
+        let handle = Self::promote_current_thread_with(request, |handle| {
+            let status = std::process::Command::new("pkexec")
+                .args(["chrt", "--reset-on-fork", "--fifo", "--pid"])
+                .arg(handle.priority.to_string())
+                .arg(handle.thread_info.thread_id.to_string())
+                .status()
+                .map_err(|e| AudioThreadPriorityError::new_with_inner("pkexec", Box::new(e)))?;
+            if !status.success() {
+                return Err(AudioThreadPriorityError::new(&format!(
+                    "pkexec chrt failed: {}",
+                    status
+                )));
+            }
+            Ok(())
+        })?;
+        Ok((handle, PromotionMethod::Pkexec))
+    }
+
+    /// Apply all the recommended tuning for real-time audio to the current thread, and promote it
+    /// to real-time. This is the simplest way to get the lowest latency on a dedicated machine.
+    /// The steps are, in order:
+    ///
+    /// - lock the memory of the process in RAM with `mlockall`, so that the thread doesn't cause
+    ///   page faults that need disk I/O. This requires a high enough `RLIMIT_MEMLOCK` or
+    ///   `CAP_IPC_LOCK`.
+    /// - prevent the CPUs from entering deep sleep states, by writing `0` to
+    ///   `/dev/cpu_dma_latency`, see `with_cpu_dma_latency_hint`. This usually requires running as
+    ///   root.
+    /// - only allow the thread to run on the performance cores of hybrid CPUs, or on the CPUs
+    ///   with the highest maximum frequency otherwise. This doesn't require any privilege.
+    /// - promote the thread to `SCHED_FIFO` and set its timer slack to 1ns, see
+    ///   `try_promote_without_dbus`. This requires `CAP_SYS_NICE` or a high enough
+    ///   `RLIMIT_RTPRIO`.
+    ///
+    /// Only the promotion is required to succeed, a warning is logged for the other steps that
+    /// fail. All the tuning is undone when the returned guard is dropped, on whichever thread it
+    /// is dropped. The memory is only unlocked if no memory of the process was locked before.
+    pub fn tune_for_audio_workload(
+        request: &RtPriorityRequest,
+    ) -> Result<RtTuningGuard, AudioThreadPriorityError> {
+        let thread_info = get_current_thread_info_internal()?;
+        let tid = thread_info.thread_id;
+        let mut guard = RtTuningGuard {
+            tid,
+            handle: None,
+            cpu_dma_latency: None,
+            memory_locked: false,
+            original_affinity: None,
+            original_timer_slack: unsafe { libc::prctl(libc::PR_GET_TIMERSLACK) },
+        };
+
+        // `VmLck` is for the whole process: if something else locked memory, it's left locked.
+        let was_locked = task_status_field(&thread_info, "VmLck").unwrap_or(0) > 0;
+        if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } < 0 {
+            warn!("mlockall failed: {}", OSError::last_os_error());
+        } else {
+            guard.memory_locked = !was_locked;
+        }
+
+        let error = |e| AudioThreadPriorityError::new_with_inner(CPU_DMA_LATENCY, Box::new(e));
+        match OpenOptions::new()
+            .write(true)
+            .open(CPU_DMA_LATENCY)
+            .map_err(error)
+            .and_then(|mut file| {
+                file.write_all(&0u32.to_ne_bytes()).map_err(error)?;
+                Ok(CpuDmaLatencyGuard { _file: file })
+            }) {
+            Ok(cpu_dma_latency) => guard.cpu_dma_latency = Some(cpu_dma_latency),
+            Err(e) => warn!("Could not limit the CPU wake-up latency: {}", e),
+        }
+
+        let r = get_thread_affinity(tid).and_then(|affinity| {
+            let performance_cpus: Vec<usize> = performance_cpus()?
+                .into_iter()
+                .filter(|cpu| affinity.contains(cpu))
+                .collect();
+            if !performance_cpus.is_empty() && performance_cpus.len() < affinity.len() {
+                set_thread_affinity(tid, &performance_cpus)?;
+                guard.original_affinity = Some(affinity);
+            }
+            Ok(())
+        });
+        if let Err(e) = r {
+            warn!("Could not pin the thread to the performance cores: {}", e);
+        }
+
+        guard.handle = Some(RtPriorityHandleInternal::try_promote_without_dbus(request)?);
+        Ok(guard)
+    }
+
+    /// Apply CPU, I/O and memory quality of service settings to the current thread at once. The
+    /// settings are applied in this order:
+    ///
+    /// - `qos.mem`: lock the memory of the process in RAM with `mlockall`, and ask the kernel not
+    ///   to back the stack of the thread with transparent huge pages.
+    /// - `qos.io`: set the I/O priority of the thread, see `set_io_priority`.
+    /// - `qos.cpu`: promote the thread to real-time, see `promote_current_thread_to_real_time`.
+    ///
+    /// If a step fails, the settings already applied are undone and the error is returned. All the
+    /// settings are undone when the returned handle is dropped, except for the huge page advice,
+    /// that can't be reverted, and the memory lock if memory was already locked.
+    pub fn set_thread_qos(qos: &QosClass) -> Result<QosHandle, AudioThreadPriorityError> {
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as kernel_pid_t;
+        let mut handle = QosHandle {
+            handle: None,
+            io_priority: None,
+            memory_locked: false,
+        };
+        if let MemQos::Locked = qos.mem {
+            // `VmLck` is for the whole process: if something else locked memory, it's left locked.
+            let thread_info = get_current_thread_info_internal()?;
+            let was_locked = task_status_field(&thread_info, "VmLck").unwrap_or(0) > 0;
+            if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } < 0 {
+                return Err(AudioThreadPriorityError::new_with_inner(
+                    "mlockall",
+                    Box::new(OSError::last_os_error()),
+                ));
+            }
+            handle.memory_locked = !was_locked;
+            let (stack_addr, stack_size) = current_thread_stack()?;
+            madvise_no_hugepages(stack_addr, stack_size)?;
+        }
+        if let IoQos::Priority(class, level) = qos.io {
+            handle.io_priority = Some(set_thread_io_priority(tid, class, level)?);
+        }
+        if let RtQos::RealTime(request) = &qos.cpu {
+            handle.handle = Some(promote_current_thread_to_real_time_internal(request)?);
+        }
+        Ok(handle)
+    }
+
+    /// Hand the real-time priority of the thread `other` refers to over to the calling thread:
+    /// the calling thread is promoted with the same priority and budget, and the thread `other`
+    /// refers to is then demoted. This is useful when a new thread takes over the processing from
+    /// a real-time thread, e.g. at a block boundary.
+    ///
+    /// `other` must not refer to the calling thread. This returns a handle for the calling thread.
+    pub fn swap_with_current_thread(
+        other: RtPriorityHandleInternal,
+    ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+        if other.thread_info.pthread_id == unsafe { libc::pthread_self() } {
+            return Err(AudioThreadPriorityError::new(
+                "cannot swap priorities with the current thread",
+            ));
+        }
+        let thread_info = get_current_thread_info_internal()?;
+        let mut handle = RtPriorityHandleInternal::new(thread_info);
+        handle.latency_target_us = other.latency_target_us;
+        handle.budget_us = other.budget_us;
+        handle.priority = other.priority;
+        handle.request = other.request.clone();
+        handle.major_faults_at_promotion = major_faults(&thread_info).unwrap_or(0);
+        handle.migrations_at_promotion = cpu_migrations(&thread_info).unwrap_or(0);
+        // Only available with CONFIG_SCHED_DEBUG.
+        handle.extended_info_at_promotion = ExtendedSchedulerInfo::read(&thread_info).ok();
+        handle.affinity_at_promotion = get_thread_affinity(thread_info.thread_id).ok();
+        #[cfg(feature = "numa")]
+        {
+            handle.original_affinity = handle.affinity_at_promotion.clone();
+        }
+        rtkit_set_realtime(
+            &RTKIT,
+            thread_info.thread_id as u64,
+            thread_info.pid as u64,
+            handle.priority,
+        )
+        .map_err(|e| AudioThreadPriorityError::new_with_inner("Thread promotion error", e))?;
+        handle.promoted_at = Some(Instant::now());
+
+        if let Err(e) = demote_with_force_internal(other) {
+            // Don't leave both threads real-time.
+            if let Err(e) = demote_with_force_internal(handle) {
+                warn!(
+                    "Could not demote the current thread after a failed swap: {}",
+                    e
+                );
+            }
+            return Err(e);
+        }
+        Ok(handle)
+    }
+
+    /// Switch the thread this handle refers to to `SCHED_DEADLINE`, with the given runtime,
+    /// deadline and period in microseconds, by calling `sched_setattr` directly instead of going
+    /// through RTKit. This requires `CAP_SYS_NICE`.
+    ///
+    /// This consumes the handle and returns a new one. Demoting it restores the scheduling policy
+    /// the thread had before it was first promoted.
+    pub fn set_deadline_scheduling(
+        mut self,
+        runtime_us: u64,
+        deadline_us: u64,
+        period_us: u64,
+    ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+        set_sched_deadline(
+            self.thread_info.thread_id,
+            runtime_us,
+            deadline_us,
+            period_us,
+        )?;
+        self.deadline_params = Some((runtime_us, deadline_us, period_us));
+        Ok(self)
+    }
+
+    /// The runtime, deadline and period set with `set_deadline_scheduling`, in microseconds, if
+    /// any.
+    pub fn deadline_scheduling(&self) -> Option<(u64, u64, u64)> {
+        self.deadline_params
+    }
+
+    /// Let the threads created by the thread this handle refers to (e.g. the internal threads of
+    /// JACK2 or of a plugin) inherit its real-time scheduling policy and priority, by clearing
+    /// the reset-on-fork flag set at promotion time, with `sched_setattr`.
+    ///
+    /// Only the flag matters here: `SCHED_FLAG_KEEP_POLICY` only makes a `sched_setattr` call
+    /// keep the current policy, and keeps the reset-on-fork flag as it is. Clearing the flag
+    /// requires `CAP_SYS_NICE`. This fails for `SCHED_DEADLINE` threads, that can't create
+    /// threads without it.
+    #[cfg(feature = "linux-sched-flags")]
+    pub fn set_keep_policy_flag(&self) -> Result<(), AudioThreadPriorityError> {
+        let tid = self.thread_info.thread_id as libc::pid_t;
+        let current = sched_getattr(tid)?;
+        if current.sched_policy as libc::c_int == SCHED_DEADLINE {
+            return Err(AudioThreadPriorityError::new(
+                "SCHED_DEADLINE threads have to reset their policy on fork",
+            ));
+        }
+        let attr = SchedAttr {
+            size: std::mem::size_of::<SchedAttr>() as u32,
+            sched_flags: current.sched_flags & !SCHED_FLAG_RESET_ON_FORK,
+            ..current
+        };
+        if unsafe { libc::syscall(libc::SYS_sched_setattr, tid, &attr, 0) } < 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "sched_setattr",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The current `SCHED_DEADLINE` parameters of the thread this handle refers to, queried from
+    /// the kernel with `sched_getattr`, whoever set them. This is `None` if the thread doesn't use
+    /// `SCHED_DEADLINE`, or if its scheduling can't be queried.
+    pub fn get_scheduler_deadline_params(&self) -> Option<DeadlineParams> {
+        let attr = sched_getattr(self.thread_info.thread_id as libc::pid_t).ok()?;
+        if attr.sched_policy as libc::c_int != SCHED_DEADLINE {
+            return None;
+        }
+        Some(DeadlineParams {
+            runtime_ns: attr.sched_runtime,
+            deadline_ns: attr.sched_deadline,
+            period_ns: attr.sched_period,
+        })
+    }
+
+    /// Check that this handle is consistent, to catch handles corrupted e.g. by a serialization
+    /// bug. Only the fields of the handle are checked, the thread and the system are not queried.
+    ///
+    /// This is called automatically after promoting a thread in debug builds, and a warning is
+    /// logged if it fails.
+    pub fn assert_invariants(&self) -> Result<(), AudioThreadPriorityError> {
+        let info = &self.thread_info;
+        let error = |message: String| Err(AudioThreadPriorityError::new(&message));
+        if info.thread_id <= 0 {
+            return error(format!("invalid thread id {}", info.thread_id));
+        }
+        if info.pthread_id == 0 {
+            return error("invalid pthread id".to_string());
+        }
+        if info.pid <= 0 {
+            return error(format!("invalid pid {}", info.pid));
+        }
+        let policy = info.policy & !SCHED_RESET_ON_FORK;
+        if ![
+            libc::SCHED_OTHER,
+            libc::SCHED_FIFO,
+            libc::SCHED_RR,
+            libc::SCHED_BATCH,
+            libc::SCHED_IDLE,
+            SCHED_DEADLINE,
+        ]
+        .contains(&policy)
+        {
+            return error(format!("invalid scheduling policy {}", info.policy));
+        }
+        // RTKit always uses SCHED_RR. The range is fixed by the kernel.
+        if self.priority != 0 {
+            let min = unsafe { libc::sched_get_priority_min(libc::SCHED_RR) };
+            let max = unsafe { libc::sched_get_priority_max(libc::SCHED_RR) };
+            if !(min as i64..=max as i64).contains(&(self.priority as i64)) {
+                return error(format!("invalid real-time priority {}", self.priority));
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask the kernel to keep the CPUs in idle states they can wake up from in less than
+    /// `latency_us`, via the PM QoS interface at `/dev/cpu_dma_latency`. `0` prevents the CPUs
+    /// from entering any deep sleep state, that can otherwise delay the wake-up of the real-time
+    /// thread by several milliseconds, at the cost of a higher power consumption.
+    ///
+    /// This applies to all the CPUs, for as long as the returned guard is alive. Opening
+    /// `/dev/cpu_dma_latency` usually requires running as root.
+    pub fn with_cpu_dma_latency_hint(
+        &self,
+        latency_us: u32,
+    ) -> Result<CpuDmaLatencyGuard, AudioThreadPriorityError> {
+        let error = |e| AudioThreadPriorityError::new_with_inner(CPU_DMA_LATENCY, Box::new(e));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(CPU_DMA_LATENCY)
+            .map_err(error)?;
+        file.write_all(&latency_us.to_ne_bytes()).map_err(error)?;
+        Ok(CpuDmaLatencyGuard { _file: file })
+    }
+
+    /// Limit the wake-up latency of the CPUs the thread this handle refers to can run on, to
+    /// `latency_ns`, so that the cpuidle governor doesn't put them in idle states that are too
+    /// deep. Unlike `with_cpu_dma_latency_hint`, this doesn't affect the other CPUs.
+    ///
+    /// The kernel has no netlink API for PM QoS: this uses the per-CPU PM QoS sysfs interface,
+    /// `/sys/devices/system/cpu/cpu<N>/power/pm_qos_resume_latency_us`, which has a microsecond
+    /// resolution, so the latency is rounded up to the next microsecond. `0` prevents the CPUs from
+    /// entering any idle state. This requires write access to sysfs, which usually means running
+    /// as root. The original settings are restored when the returned handle is dropped.
+    pub fn request_governor_latency_netlink(
+        &self,
+        latency_ns: u32,
+    ) -> Result<PmQosHandle, AudioThreadPriorityError> {
+        let latency_us = latency_ns.div_ceil(1000);
+        // 0 means "no constraint" for this interface, "n/a" means "no idle state".
+        let value = if latency_us == 0 {
+            "n/a".to_string()
+        } else {
+            latency_us.to_string()
+        };
+        let mut handle = PmQosHandle {
+            original_settings: Vec::new(),
+        };
+        for cpu in get_thread_affinity(self.thread_info.thread_id)? {
+            let path = PathBuf::from(format!(
+                "/sys/devices/system/cpu/cpu{}/power/pm_qos_resume_latency_us",
+                cpu
+            ));
+            let error = |e| {
+                AudioThreadPriorityError::new_with_inner(
+                    &format!("{}", path.display()),
+                    Box::new(e),
+                )
+            };
+            let original = fs::read_to_string(&path).map_err(error)?;
+            fs::write(&path, &value).map_err(error)?;
+            handle
+                .original_settings
+                .push((path.clone(), original.trim_end().to_string()));
+        }
+        Ok(handle)
+    }
+
+    /// Enable or disable the opportunistic frequency boost (e.g. Intel Turbo Boost) of the CPUs
+    /// the thread this handle refers to can run on. Disabling it makes the CPU frequency, and so
+    /// the duration of the audio callbacks, more predictable: a boosting core can force its
+    /// neighbours to run slower.
+    ///
+    /// With the `intel_pstate` driver, this can only be set for all the CPUs at once, via
+    /// `/sys/devices/system/cpu/intel_pstate/no_turbo`. Otherwise, the per-CPU `cpufreq/boost`
+    /// file is used, or the global one if the cpufreq driver doesn't have per-CPU settings. This
+    /// requires write access to sysfs, which usually means running as root. The original settings
+    /// are restored when the returned handle is dropped.
+    #[cfg(feature = "cpu-tuning")]
+    pub fn set_turbo_boost_hint(
+        &self,
+        enabled: bool,
+    ) -> Result<TurboBoostHandle, AudioThreadPriorityError> {
+        let mut handle = TurboBoostHandle {
+            original_settings: Vec::new(),
+        };
+        let no_turbo = PathBuf::from("/sys/devices/system/cpu/intel_pstate/no_turbo");
+        if no_turbo.exists() {
+            handle.set(no_turbo, if enabled { "0" } else { "1" })?;
+            return Ok(handle);
+        }
+        let value = if enabled { "1" } else { "0" };
+        for cpu in get_thread_affinity(self.thread_info.thread_id)? {
+            let boost = PathBuf::from(format!("/sys/devices/system/cpu/cpu{}/cpufreq/boost", cpu));
+            if !boost.exists() {
+                // acpi-cpufreq only has a global setting.
+                handle.set(
+                    PathBuf::from("/sys/devices/system/cpu/cpufreq/boost"),
+                    value,
+                )?;
+                break;
+            }
+            handle.set(boost, value)?;
+        }
+        Ok(handle)
+    }
+
+    /// Ask for the CPUs the thread this handle refers to can run on to run at their maximum
+    /// frequency, by switching their cpufreq governor to `performance`. On CPUs that support it,
+    /// the energy/performance preference is also set to `performance` (equivalent to `0`).
+    ///
+    /// The original settings are restored when the returned handle is dropped. This requires
+    /// write access to `/sys/devices/system/cpu`, which usually means running as root.
+    pub fn request_cpu_frequency_boost(
+        &self,
+    ) -> Result<CpuFreqBoostHandle, AudioThreadPriorityError> {
+        let cpus = match &self.affinity {
+            Some(cpus) => cpus.clone(),
+            None => get_thread_affinity(self.thread_info.thread_id)?,
+        };
+        // If a write fails, the handle restores what has been changed so far when dropped.
+        let mut boost = CpuFreqBoostHandle {
+            original_settings: Vec::new(),
+        };
+        for cpu in cpus {
+            let cpufreq = PathBuf::from(format!("/sys/devices/system/cpu/cpu{}/cpufreq", cpu));
+            // Only available with some drivers (e.g. intel_pstate and amd-pstate), and it can't
+            // be changed with some governors: this is best effort. It has to be set before the
+            // governor, intel_pstate doesn't allow changing it with the performance governor.
+            let epp = cpufreq.join("energy_performance_preference");
+            if epp.exists() {
+                if let Err(e) = boost.boost_setting(epp) {
+                    warn!("Could not set the energy/performance preference: {}", e);
+                }
+            }
+            boost.boost_setting(cpufreq.join("scaling_governor"))?;
+        }
+        Ok(boost)
+    }
+
+    /// The current frequency of the CPU the calling thread runs on, in MHz, from
+    /// `/sys/devices/system/cpu/cpu<N>/cpufreq/scaling_cur_freq`.
+    ///
+    /// This must be called on the thread this handle refers to.
+    pub fn cpu_freq_current_mhz(&self) -> Result<u32, AudioThreadPriorityError> {
+        cpufreq_mhz("scaling_cur_freq")
+    }
+
+    /// The minimum frequency the cpufreq governor can pick for the CPU the calling thread runs
+    /// on, in MHz, from `scaling_min_freq`.
+    ///
+    /// This must be called on the thread this handle refers to.
+    pub fn cpu_freq_min_mhz(&self) -> Result<u32, AudioThreadPriorityError> {
+        cpufreq_mhz("scaling_min_freq")
+    }
+
+    /// The maximum frequency the cpufreq governor can pick for the CPU the calling thread runs
+    /// on, in MHz, from `scaling_max_freq`.
+    ///
+    /// This must be called on the thread this handle refers to.
+    pub fn cpu_freq_max_mhz(&self) -> Result<u32, AudioThreadPriorityError> {
+        cpufreq_mhz("scaling_max_freq")
+    }
+
+    /// The highest temperature of the CPU thermal zones, in degrees Celsius, from
+    /// `/sys/class/thermal/thermal_zone*/temp`.
+    ///
+    /// sysfs doesn't say which CPUs a thermal zone covers: all the zones that look like they
+    /// measure a CPU (e.g. `x86_pkg_temp` or `cpu-thermal`) are considered to cover the CPUs the
+    /// thread this handle refers to can run on. If there are none, all the thermal zones are used.
+    #[cfg(feature = "thermal")]
+    pub fn core_temp_celsius(&self) -> Result<f32, AudioThreadPriorityError> {
+        cpu_thermal_zones()?
+            .iter()
+            .filter_map(|zone| read_millicelsius(&zone.join("temp")))
+            .max()
+            .map(|temp| temp as f32 / 1000.0)
+            .ok_or_else(|| AudioThreadPriorityError::new("no thermal zone temperature"))
+    }
+
+    /// Whether the CPU thermal zones used by `core_temp_celsius` are at or above their lowest
+    /// passive trip point, i.e. the temperature at which the kernel starts reducing the CPU
+    /// frequency. Zones without a passive trip point are compared to their hot or critical one.
+    ///
+    /// This is `false` if the temperatures or trip points can't be read.
+    #[cfg(feature = "thermal")]
+    pub fn is_thermally_throttled(&self) -> bool {
+        let zones = match cpu_thermal_zones() {
+            Ok(zones) => zones,
+            Err(_) => return false,
+        };
+        zones.iter().any(|zone| {
+            let temp = match read_millicelsius(&zone.join("temp")) {
+                Some(temp) => temp,
+                None => return false,
+            };
+            let mut trip_points = Vec::new();
+            for n in 0.. {
+                let trip_type = zone.join(format!("trip_point_{}_type", n));
+                let trip_type = match fs::read_to_string(trip_type) {
+                    Ok(trip_type) => trip_type,
+                    Err(_) => break,
+                };
+                if let Some(trip_temp) =
+                    read_millicelsius(&zone.join(format!("trip_point_{}_temp", n)))
+                {
+                    trip_points.push((trip_type.trim().to_string(), trip_temp));
+                }
+            }
+            let lowest = |kinds: &[&str]| {
+                trip_points
+                    .iter()
+                    .filter(|(kind, temp)| kinds.contains(&kind.as_str()) && *temp > 0)
+                    .map(|(_, temp)| *temp)
+                    .min()
+            };
+            lowest(&["passive"])
+                .or_else(|| lowest(&["hot", "critical"]))
+                .is_some_and(|threshold| temp >= threshold)
+        })
+    }
+
+    /// Recommend a duration under which waiting should be done by spinning rather than by
+    /// sleeping, in microseconds: the minimum scheduling granularity of the kernel, plus the 99th
+    /// percentile of the scheduling jitter of the calling thread.
+    ///
+    /// The jitter is measured by sleeping a few times, which takes a few milliseconds: this should
+    /// be called on the thread this handle refers to, before it starts processing audio. This is
+    /// only advisory, and the kernel default is used if the granularity can't be read.
+    pub fn recommended_spin_wait_threshold_us(&self) -> u64 {
+        // The sysctl has been moved to debugfs in Linux 5.13, and replaced by base_slice_ns in
+        // Linux 6.6.
+        let granularity_ns = [
+            "/proc/sys/kernel/sched_min_granularity_ns",
+            "/sys/kernel/debug/sched/min_granularity_ns",
+            "/sys/kernel/debug/sched/base_slice_ns",
+        ]
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok()?.trim().parse::<u64>().ok())
+        .unwrap_or(DEFAULT_SCHED_MIN_GRANULARITY_NS);
+        granularity_ns / 1000 + measure_scheduling_jitter_p99_us()
+    }
+
+    /// Measure the jitter of the interrupts of an audio device: wait `samples` times for
+    /// `device_fd` to become readable with `poll`, and compute statistics on the
+    /// intervals between the wake-ups, timestamped with `CLOCK_MONOTONIC`. This complements the
+    /// scheduling jitter measured by `jitter_compensation_offset_us`, and should be called from the
+    /// thread this handle refers to, once it is real-time.
+    ///
+    /// `device_fd` must become readable once per period, e.g. a timerfd, or an eventfd signalled
+    /// when a DMA transfer completes: 8 bytes are read from it after each wake-up, which resets
+    /// the counter of a timerfd or an eventfd, so that it isn't readable until the next period.
+    /// At least 2 samples are needed.
+    pub fn measure_dma_transfer_jitter(
+        &self,
+        device_fd: RawFd,
+        samples: u32,
+    ) -> Result<JitterStats, AudioThreadPriorityError> {
+        if samples < 2 {
+            return Err(AudioThreadPriorityError::new(
+                "at least 2 samples are needed to measure the jitter",
+            ));
+        }
+        let mut timestamps = Vec::with_capacity(samples as usize);
+        while timestamps.len() < samples as usize {
+            let mut pollfd = libc::pollfd {
+                fd: device_fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+                let e = OSError::last_os_error();
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(AudioThreadPriorityError::new_with_inner(
+                    "poll",
+                    Box::new(e),
+                ));
+            }
+            let now = Instant::now();
+            if pollfd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+                return Err(AudioThreadPriorityError::new(&format!(
+                    "poll error on file descriptor {}",
+                    device_fd
+                )));
+            }
+            let mut buf = [0u8; 8];
+            let read =
+                unsafe { libc::read(device_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if read <= 0 {
+                let e = OSError::last_os_error();
+                if read < 0 && e.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                // Otherwise the file descriptor would stay readable, and the next polls would
+                // return immediately.
+                return Err(AudioThreadPriorityError::new_with_inner(
+                    "read",
+                    Box::new(if read == 0 {
+                        OSError::from(std::io::ErrorKind::UnexpectedEof)
+                    } else {
+                        e
+                    }),
+                ));
+            }
+            timestamps.push(now);
+        }
+
+        let mut intervals_us: Vec<u64> = timestamps
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).as_micros() as u64)
+            .collect();
+        intervals_us.sort_unstable();
+        let count = intervals_us.len() as f64;
+        let mean = intervals_us.iter().sum::<u64>() as f64 / count;
+        let variance = intervals_us
+            .iter()
+            .map(|&interval| (interval as f64 - mean).powi(2))
+            .sum::<f64>()
+            / count;
+        let p99_index = (intervals_us.len() * 99).div_ceil(100) - 1;
+        Ok(JitterStats {
+            min_us: intervals_us[0],
+            max_us: intervals_us[intervals_us.len() - 1],
+            p99_us: intervals_us[p99_index],
+            stddev_us: variance.sqrt(),
+        })
+    }
+
+    /// Create a timer that expires every `interval_us` microseconds, for real-time threads driven
+    /// by a high-resolution timer rather than by the callbacks of an audio device. The timer is a
+    /// `timerfd` on `CLOCK_MONOTONIC`: wait for it with `TimerHandle::wait_for_tick`, or add
+    /// `TimerHandle::fd` to a `poll` set. It is disarmed on drop.
+    pub fn request_dedicated_timer_interrupt(
+        &self,
+        interval_us: u64,
+    ) -> Result<TimerHandle, AudioThreadPriorityError> {
+        if interval_us == 0 {
+            return Err(AudioThreadPriorityError::new("the timer interval is zero"));
+        }
+        let fd = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "timerfd_create",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        // From now on, the timer is closed on error.
+        let timer = TimerHandle { fd };
+        let interval = libc::timespec {
+            tv_sec: (interval_us / 1_000_000) as libc::time_t,
+            tv_nsec: ((interval_us % 1_000_000) * 1000) as libc::c_long,
+        };
+        let spec = libc::itimerspec {
+            it_interval: interval,
+            it_value: interval,
+        };
+        if unsafe { libc::timerfd_settime(fd, 0, &spec, std::ptr::null_mut()) } < 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "timerfd_settime",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        Ok(timer)
+    }
+
+    /// Measure the round-trip time of `samples` calls to `org.freedesktop.DBus.Peer.Ping` on the
+    /// system bus, to check that the D-Bus setup is fast enough to promote threads via RTKit. A
+    /// warning is logged if the 99th percentile is close to the D-Bus timeout used for promotions.
+    pub fn benchmark_dbus_round_trip(
+        samples: u32,
+    ) -> Result<DbusLatencyStats, AudioThreadPriorityError> {
+        if samples == 0 {
+            return Err(AudioThreadPriorityError::new("no samples requested"));
+        }
+        let mut round_trips_us = with_dbus_connection(BusType::System, |c| {
+            let mut round_trips_us = Vec::with_capacity(samples as usize);
+            for _ in 0..samples {
+                let m = Message::new_method_call(
+                    "org.freedesktop.DBus",
+                    "/org/freedesktop/DBus",
+                    "org.freedesktop.DBus.Peer",
+                    "Ping",
+                )
+                .map_err(|e| AudioThreadPriorityError::new(&e))?;
+                let start = Instant::now();
+                c.send_with_reply_and_block(m, DBUS_SOCKET_TIMEOUT)?;
+                round_trips_us.push(start.elapsed().as_micros() as u64);
+            }
+            Ok::<_, AudioThreadPriorityError>(round_trips_us)
+        })?;
+        round_trips_us.sort_unstable();
+
+        let p99_index = (round_trips_us.len() * 99).div_ceil(100) - 1;
+        let stats = DbusLatencyStats {
+            min_us: round_trips_us[0],
+            max_us: round_trips_us[round_trips_us.len() - 1],
+            mean_us: round_trips_us.iter().sum::<u64>() / round_trips_us.len() as u64,
+            p99_us: round_trips_us[p99_index],
+        };
+        if stats.p99_us as f64 > DBUS_SOCKET_TIMEOUT as f64 * 1000.0 * 0.9 {
+            warn!(
+                "The D-Bus round-trip time ({}us at the 99th percentile) is close to the {}ms \
+                 timeout.",
+                stats.p99_us, DBUS_SOCKET_TIMEOUT
+            );
+        }
+        Ok(stats)
+    }
+
+    /// Check that the resolution of the system timer, as reported by
+    /// `clock_getres(CLOCK_MONOTONIC)`, is at most `required_us`, e.g. the duration of an audio
+    /// buffer. This fails on systems without high-resolution timers, where the resolution is the
+    /// duration of a scheduler tick (1 to 10ms).
+    pub fn verify_timer_resolution(
+        &self,
+        required_us: u64,
+    ) -> Result<(), AudioThreadPriorityError> {
+        let mut resolution = unsafe { std::mem::zeroed::<libc::timespec>() };
+        if unsafe { libc::clock_getres(libc::CLOCK_MONOTONIC, &mut resolution) } < 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "clock_getres",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        let available_ns = resolution.tv_sec as u64 * 1_000_000_000 + resolution.tv_nsec as u64;
+        if available_ns > required_us.saturating_mul(1000) {
+            return Err(AudioThreadPriorityError::new(&format!(
+                "insufficient timer resolution: {}us available, {}us required",
+                available_ns as f64 / 1000.0,
+                required_us
+            )));
+        }
+        Ok(())
+    }
+
+    /// Get the preemption model of the running kernel, that bounds how long a real-time thread can
+    /// wait for the CPU after it becomes runnable.
+    ///
+    /// `/sys/kernel/realtime` identifies `PREEMPT_RT` kernels. Otherwise, the model is read from
+    /// `/sys/kernel/debug/sched/preempt` on kernels with `PREEMPT_DYNAMIC`, if debugfs is
+    /// accessible, and from `uname()` if not: the release of `PREEMPT_RT` kernels usually has an
+    /// `-rt` suffix, and the version lists the preemption flags the kernel was built with, e.g.
+    /// `#1 SMP PREEMPT Tue Jan 1 00:00:00 UTC 2030`. The latter doesn't distinguish voluntary
+    /// preemption from no preemption, nor the default model of `PREEMPT_DYNAMIC` kernels, `None`
+    /// is returned in those cases.
+    pub fn get_kernel_preemption_model() -> KernelPreemptionModel {
+        if fs::read_to_string("/sys/kernel/realtime").is_ok_and(|r| r.trim() == "1") {
+            return KernelPreemptionModel::Realtime;
+        }
+        // The current model is in parentheses, e.g. `none voluntary (full) lazy`.
+        if let Ok(preempt) = fs::read_to_string("/sys/kernel/debug/sched/preempt") {
+            let current = preempt
+                .split_whitespace()
+                .find_map(|model| model.strip_prefix('(')?.strip_suffix(')'));
+            match current {
+                Some("none") => return KernelPreemptionModel::None,
+                Some("voluntary") => return KernelPreemptionModel::Voluntary,
+                Some("full") | Some("lazy") => return KernelPreemptionModel::Full,
+                _ => {}
+            }
+        }
+        let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+        if unsafe { libc::uname(&mut name) } < 0 {
+            return KernelPreemptionModel::None;
+        }
+        let release = unsafe { std::ffi::CStr::from_ptr(name.release.as_ptr()) }.to_string_lossy();
+        let version = unsafe { std::ffi::CStr::from_ptr(name.version.as_ptr()) }.to_string_lossy();
+        preemption_model_from_uname(&release, &version)
+    }
+
+    /// Check that the running kernel is a `PREEMPT_RT` kernel, by looking for `PREEMPT_RT` or
+    /// `PREEMPT RT` in `/proc/version`. The error contains the version string otherwise.
+    ///
+    /// This is a diagnostic tool: real-time promotion works on any kernel, `PREEMPT_RT` only
+    /// makes the scheduling latency lower and more predictable.
+    pub fn assert_running_on_rt_kernel() -> Result<(), AudioThreadPriorityError> {
+        let version = fs::read_to_string("/proc/version").map_err(|e| {
+            AudioThreadPriorityError::new_with_inner("read /proc/version", Box::new(e))
+        })?;
+        if version.contains("PREEMPT_RT") || version.contains("PREEMPT RT") {
+            Ok(())
+        } else {
+            Err(AudioThreadPriorityError::new(&format!(
+                "not a real-time kernel: {}",
+                version.trim_end()
+            )))
+        }
+    }
+
+    /// Whether the running kernel is a `PREEMPT_RT` kernel, see `assert_running_on_rt_kernel`.
+    /// This is `false` if `/proc/version` can't be read.
+    pub fn is_running_on_rt_kernel() -> bool {
+        Self::assert_running_on_rt_kernel().is_ok()
+    }
+
+    /// Set the I/O scheduling class and level of the thread this handle refers to, so that its
+    /// disk reads (e.g. streaming samples) don't wait behind the I/O of other threads. `level` is
+    /// between 0 (highest priority) and 7, and is ignored for `IoSchedulingClass::Idle`.
+    ///
+    /// `IoSchedulingClass::RealTime` requires `CAP_SYS_ADMIN`. The original I/O priority is
+    /// restored when the returned handle is dropped.
+    pub fn set_io_priority(
+        &self,
+        class: IoSchedulingClass,
+        level: u8,
+    ) -> Result<IoPriorityHandle, AudioThreadPriorityError> {
+        set_thread_io_priority(self.thread_info.thread_id, class, level)
+    }
+
+    /// Mark the traffic of socket `socket_fd` for low latency, e.g. for network audio (AES67,
+    /// RTSP): the DSCP of the packets is set to Expedited Forwarding (`IP_TOS`, or `IPV6_TCLASS`
+    /// for IPv6 sockets), and their priority in the queues of the network stack to 6
+    /// (`SO_PRIORITY`, which is what `IP_PRIORITY` is on Linux). The original values are restored
+    /// when the returned handle is dropped.
+    ///
+    /// Priorities above 6 require `CAP_NET_ADMIN`. Routers are free to ignore or rewrite the DSCP.
+    pub fn request_low_latency_network_qos(
+        &self,
+        socket_fd: RawFd,
+    ) -> Result<NetworkQosHandle, AudioThreadPriorityError> {
+        let (level, option, name) = traffic_class_option(socket_fd)?;
+        let handle = NetworkQosHandle {
+            fd: socket_fd,
+            traffic_class_option: (level, option, name),
+            original_traffic_class: get_socket_option(socket_fd, level, option, name)?,
+            original_priority: get_socket_priority(socket_fd)?,
+        };
+        // On error, the handle restores what has already been changed.
+        set_socket_option(socket_fd, level, option, name, DSCP_EF_TRAFFIC_CLASS)?;
+        set_socket_priority(socket_fd, NETWORK_AUDIO_SOCKET_PRIORITY)?;
+        Ok(handle)
+    }
+
+    /// Set the OOM killer score adjustment of the process of the thread this handle refers to, so
+    /// that it is killed last when the system runs out of memory: a real-time thread should be
+    /// both scheduled first and killed last. `score` is between -1000 (never killed) and 1000
+    /// (killed first). This applies to the whole process, not only this thread.
+    ///
+    /// Lowering the score below its original value requires `CAP_SYS_RESOURCE`. The original score
+    /// is restored when the returned handle is dropped.
+    pub fn set_oom_score_adj(
+        &self,
+        score: i32,
+    ) -> Result<OomScoreHandle, AudioThreadPriorityError> {
+        if !(-1000..=1000).contains(&score) {
+            return Err(AudioThreadPriorityError::new(&format!(
+                "invalid OOM score adjustment {}",
+                score
+            )));
+        }
+        let path = PathBuf::from(format!("/proc/{}/oom_score_adj", self.thread_info.pid));
+        let original = fs::read_to_string(&path).map_err(|e| {
+            AudioThreadPriorityError::new_with_inner("read oom_score_adj", Box::new(e))
+        })?;
+        fs::write(&path, score.to_string()).map_err(|e| {
+            AudioThreadPriorityError::new_with_inner("write oom_score_adj", Box::new(e))
+        })?;
+        Ok(OomScoreHandle {
+            path,
+            original: original.trim().to_string(),
+        })
+    }
+
+    /// Schedule the threaded interrupt handler of the device `irq_name` (e.g. `snd_hda_intel`)
+    /// with `SCHED_FIFO` at `priority`, so that it isn't preempted by the real-time thread this
+    /// handle refers to, which waits for it. `priority` should be at least the priority of this
+    /// thread.
+    ///
+    /// The IRQ number is found by looking for `irq_name` in `/proc/interrupts`, and the handler
+    /// thread is the kernel thread named `irq/<number>-<device>`. This requires `CAP_SYS_NICE`.
+    /// The original scheduling policy and priority of the handler thread are restored when the
+    /// returned handle is dropped.
+    pub fn set_irq_thread_priority(
+        &self,
+        irq_name: &str,
+        priority: u32,
+    ) -> Result<IrqPriorityHandle, AudioThreadPriorityError> {
+        let interrupts = fs::read_to_string("/proc/interrupts").map_err(|e| {
+            AudioThreadPriorityError::new_with_inner("read /proc/interrupts", Box::new(e))
+        })?;
+        let irq = interrupts
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(_, rest)| {
+                rest.split_whitespace()
+                    .any(|word| word.trim_end_matches(',') == irq_name)
+            })
+            .and_then(|(irq, _)| irq.trim().parse::<u32>().ok())
+            .ok_or_else(|| {
+                AudioThreadPriorityError::new(&format!("no interrupt for {}", irq_name))
+            })?;
+
+        set_irq_thread_fifo(irq, priority)
+    }
+
+    /// Reduce the latency added by the USB stack for the USB audio interface at `device_path`,
+    /// e.g. `/sys/bus/usb/devices/1-2` (or just `1-2`):
+    ///
+    /// - the handler thread of the interrupt of the USB host controller the device is connected
+    ///   to is scheduled with `SCHED_FIFO`, at the priority of the thread this handle refers to,
+    ///   see `set_irq_thread_priority`,
+    /// - the autosuspend of the device is disabled, by writing `-1` to its
+    ///   `power/autosuspend_delay_ms`.
+    ///
+    /// The interrupt interval of the device is not changed: it comes from the descriptors of its
+    /// endpoints, and usbfs has no way of changing it. This requires `CAP_SYS_NICE` and write
+    /// access to sysfs, which usually means running as root. The original settings are restored
+    /// when the returned handle is dropped.
+    pub fn usb_audio_latency_tuning(
+        &self,
+        device_path: &str,
+    ) -> Result<UsbTuningHandle, AudioThreadPriorityError> {
+        let device = Path::new("/sys/bus/usb/devices").join(device_path);
+        let device = fs::canonicalize(&device).map_err(|e| {
+            AudioThreadPriorityError::new_with_inner(&format!("{}", device.display()), Box::new(e))
+        })?;
+        // The host controller is the closest ancestor with an interrupt, e.g. a PCI device.
+        let irq = device
+            .ancestors()
+            .find_map(|dir| {
+                fs::read_to_string(dir.join("irq"))
+                    .ok()?
+                    .trim()
+                    .parse::<u32>()
+                    .ok()
+            })
+            .ok_or_else(|| {
+                AudioThreadPriorityError::new(&format!(
+                    "no host controller interrupt for {}",
+                    device.display()
+                ))
+            })?;
+        let mut handle = UsbTuningHandle {
+            _irq_priority: set_irq_thread_fifo(irq, cmp::max(self.priority, 1))?,
+            original_autosuspend_delay: None,
+        };
+
+        let path = device.join("power/autosuspend_delay_ms");
+        let error = |e| {
+            AudioThreadPriorityError::new_with_inner(&format!("{}", path.display()), Box::new(e))
+        };
+        let original = fs::read_to_string(&path).map_err(error)?;
+        fs::write(&path, "-1").map_err(error)?;
+        handle.original_autosuspend_delay = Some((path.clone(), original.trim_end().to_string()));
+        Ok(handle)
+    }
+
+    /// Register the thread this handle refers to with the watchdog, that logs a warning when
+    /// `heartbeat` hasn't been called for more than `interval`, e.g. because the thread is stuck.
+    ///
+    /// Calling this again changes the interval. The thread is unregistered when
+    /// `disable_watchdog` is called, or when this handle is dropped.
+    pub fn set_watchdog_heartbeat_interval(&mut self, interval: Duration) {
+        let entry = Arc::new(WatchdogEntry {
+            thread_id: self.thread_info.thread_id,
+            interval,
+            last_heartbeat_ns: AtomicU64::new(watchdog_now_ns()),
+            reported: AtomicBool::new(false),
+        });
+        RtWatchdog::register(&entry);
+        self.watchdog_entry = Some(entry);
+    }
+
+    /// Signal the watchdog that the thread is alive. This doesn't lock nor allocate, and can be
+    /// called from the real-time thread. This does nothing if `set_watchdog_heartbeat_interval`
+    /// hasn't been called.
+    pub fn heartbeat(&self) {
+        if let Some(entry) = &self.watchdog_entry {
+            entry
+                .last_heartbeat_ns
+                .store(watchdog_now_ns(), Ordering::Relaxed);
+            entry.reported.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Unregister the thread this handle refers to from the watchdog.
+    pub fn disable_watchdog(&mut self) {
+        // The watchdog only keeps weak references.
+        self.watchdog_entry = None;
+    }
+
+    /// Trap invalid operations and divisions by zero in floating-point code on the thread this
+    /// handle refers to, e.g. to find the DSP code producing NaNs.
+    ///
+    /// When such an operation happens, the thread is demoted to `SCHED_OTHER`, a message is
+    /// written to stderr, and the process is terminated by `SIGFPE`, so that a core dump (and its
+    /// backtrace) can be inspected without the rest of the system being starved. This has to be
+    /// called on the thread this handle refers to. The trap is removed when the returned guard is
+    /// dropped.
+    #[cfg(feature = "fpe-trap")]
+    pub fn install_fpe_trap(&self) -> Result<FpeTrapGuard, AudioThreadPriorityError> {
+        if unsafe { libc::syscall(libc::SYS_gettid) } != self.thread_info.thread_id {
+            return Err(AudioThreadPriorityError::new(
+                "install_fpe_trap called on another thread",
+            ));
+        }
+        FpeTrapGuard::install()
+    }
+
+    /// Set the interrupt coalescing of the network interface `device`, e.g. `eth0`, so that it
+    /// raises an interrupt at most every `rx_usec` microseconds when receiving and every `tx_usec`
+    /// microseconds when transmitting. This avoids interrupt storms that delay the real-time
+    /// thread with network audio protocols (e.g. AES67) sending many small packets.
+    ///
+    /// This uses the `ETHTOOL_SCOALESCE` ioctl, that requires `CAP_NET_ADMIN` and a driver that
+    /// supports it. The original settings are restored when the returned handle is dropped.
+    #[cfg(feature = "netaudio")]
+    pub fn set_interrupt_coalescing(
+        &self,
+        device: &str,
+        rx_usec: u32,
+        tx_usec: u32,
+    ) -> Result<CoalescingHandle, AudioThreadPriorityError> {
+        let mut original = EthtoolCoalesce::default();
+        ethtool_coalesce(device, ETHTOOL_GCOALESCE, &mut original)?;
+        let mut coalesce = EthtoolCoalesce {
+            rx_coalesce_usecs: rx_usec,
+            tx_coalesce_usecs: tx_usec,
+            ..original
+        };
+        ethtool_coalesce(device, ETHTOOL_SCOALESCE, &mut coalesce)?;
+        Ok(CoalescingHandle {
+            device: device.to_string(),
+            original,
+        })
+    }
+
+    /// Create an allocator that the thread this handle refers to can use in its audio callbacks,
+    /// instead of `malloc`, that can block on a lock or fault pages in. It has a fixed capacity of
+    /// `capacity_bytes`, allocated upfront with `mmap` and locked in RAM with `mlock`.
+    ///
+    /// Allocations are never freed individually: `RtAllocator::reset` is meant to be called at
+    /// the start of each callback, to reuse the memory allocated during the previous one. Locking
+    /// the memory requires a high enough `RLIMIT_MEMLOCK` or `CAP_IPC_LOCK`.
+    pub fn realtime_capable_memory_allocator(
+        &self,
+        capacity_bytes: usize,
+    ) -> Result<RtAllocator, AudioThreadPriorityError> {
+        RtAllocator::new(capacity_bytes)
+    }
+
+    /// Make sure that the memory bandwidth of the thread this handle refers to isn't throttled by
+    /// Intel Resource Director Technology Memory Bandwidth Allocation, by lifting the limit of its
+    /// resctrl group on all the memory domains.
+    ///
+    /// When the `resctrl` filesystem is mounted with the `mba_MBps` option, limits are in MB/s,
+    /// and the limit is raised to at least `bytes_per_sec`. Otherwise, limits are percentages of
+    /// the maximum bandwidth, and the limit is set to 100%. This requires `resctrl` to be mounted
+    /// at `/sys/fs/resctrl`, and write access to it, which usually means running as root. Note
+    /// that this applies to all the threads in the same resctrl group. The original limits are
+    /// restored when the returned handle is dropped.
+    #[cfg(feature = "rdt")]
+    pub fn request_memory_bandwidth_qos(
+        &self,
+        bytes_per_sec: u64,
+    ) -> Result<MemoryBandwidthHandle, AudioThreadPriorityError> {
+        let schemata = cat_group_of(self.thread_info.thread_id)?.join("schemata");
+        let error = |e| {
+            AudioThreadPriorityError::new_with_inner(
+                &format!("{}", schemata.display()),
+                Box::new(e),
+            )
+        };
+        let original = fs::read_to_string(&schemata)
+            .map_err(error)?
+            .lines()
+            .map(str::trim)
+            .find(|line| line.starts_with("MB:"))
+            .ok_or_else(|| AudioThreadPriorityError::new("no memory bandwidth allocation"))?
+            .to_string();
+        let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+        let mbps = mounts
+            .lines()
+            .any(|line| line.starts_with("resctrl ") && line.contains("mba_MBps"));
+        let minimum = if mbps {
+            bytes_per_sec.div_ceil(1_000_000)
+        } else {
+            100
+        };
+
+        let mut domains = Vec::new();
+        for domain in original["MB:".len()..].split(';') {
+            let invalid =
+                || AudioThreadPriorityError::new(&format!("invalid schemata {}", original));
+            let (id, limit) = domain.split_once('=').ok_or_else(invalid)?;
+            let limit: u64 = limit.trim().parse().map_err(|_| invalid())?;
+            domains.push(format!("{}={}", id.trim(), cmp::max(limit, minimum)));
+        }
+        fs::write(&schemata, format!("MB:{}\n", domains.join(";"))).map_err(error)?;
+        Ok(MemoryBandwidthHandle {
+            schemata: schemata.clone(),
+            original,
+        })
+    }
+
+    /// Ask the kernel not to back the `len` bytes of memory at `addr`, e.g. the working buffers of
+    /// the real-time thread, with transparent huge pages, with `madvise(MADV_NOHUGEPAGE)`. The
+    /// compaction of memory into huge pages can block the thread for several milliseconds.
+    ///
+    /// The range is extended to whole pages. This only gives advice to the kernel: the memory
+    /// isn't accessed, and its contents are not changed.
+    #[cfg(feature = "memory-tuning")]
+    pub fn hint_no_hugepages(
+        &self,
+        addr: *mut u8,
+        len: usize,
+    ) -> Result<(), AudioThreadPriorityError> {
+        madvise_no_hugepages(addr, len)
+    }
+
+    /// Call `hint_no_hugepages` on the whole stack of the calling thread, that has to be the
+    /// thread this handle refers to.
+    #[cfg(feature = "memory-tuning")]
+    pub fn apply_to_stack(&self) -> Result<(), AudioThreadPriorityError> {
+        if unsafe { libc::syscall(libc::SYS_gettid) } != self.thread_info.thread_id {
+            return Err(AudioThreadPriorityError::new(
+                "apply_to_stack called on another thread",
+            ));
+        }
+        let (stack_addr, stack_size) = current_thread_stack()?;
+        self.hint_no_hugepages(stack_addr, stack_size)
+    }
+
+    /// Reserve a CPU for the thread this handle refers to, by moving it to a new exclusive
+    /// `cpuset` cgroup containing a single CPU: the highest-numbered CPU it is allowed to run on.
+    /// No other cpuset cgroup can then use this CPU.
+    ///
+    /// This requires the cgroup v1 `cpuset` hierarchy to be mounted at `/sys/fs/cgroup/cpuset`,
+    /// and write access to it, which usually means running as root. Tasks in the parent cpuset can
+    /// still run on the CPU: for a complete isolation, they have to be moved elsewhere, or the CPU
+    /// isolated with the `isolcpus` kernel parameter. The thread is moved back to its original
+    /// cpuset, and the new cpuset removed, when the returned handle is dropped.
+    pub fn request_exclusive_core(&self) -> Result<ExclusiveCoreHandle, AudioThreadPriorityError> {
+        let tid = self.thread_info.thread_id;
+        let error = |what: &str, e| AudioThreadPriorityError::new_with_inner(what, Box::new(e));
+
+        let cpus = match &self.affinity {
+            Some(cpus) => cpus.clone(),
+            None => get_thread_affinity(tid)?,
+        };
+        let cpu = *cpus
+            .iter()
+            .max()
+            .ok_or_else(|| AudioThreadPriorityError::new("no cpu available"))?;
+
+        let original = fs::read_to_string(task_proc_path(&self.thread_info).join("cpuset"))
+            .map_err(|e| error("read cpuset", e))?;
+        let original_cpuset = Path::new(CPUSET_ROOT).join(original.trim().trim_start_matches('/'));
+        let mems = fs::read_to_string(original_cpuset.join("cpuset.mems"))
+            .map_err(|e| error("read cpuset.mems", e))?;
+
+        let cpuset = Path::new(CPUSET_ROOT).join(format!(
+            "audio_thread_priority-{}-{}",
+            self.thread_info.pid, tid
+        ));
+        fs::create_dir(&cpuset).map_err(|e| error("create cpuset", e))?;
+        // From here, dropping the handle cleans up.
+        let handle = ExclusiveCoreHandle {
+            tid,
+            cpu,
+            cpuset,
+            original_cpuset,
+        };
+        // A task can't be added to a cpuset without CPUs and memory nodes.
+        fs::write(handle.cpuset.join("cpuset.cpus"), cpu.to_string())
+            .map_err(|e| error("write cpuset.cpus", e))?;
+        fs::write(handle.cpuset.join("cpuset.mems"), mems.trim())
+            .map_err(|e| error("write cpuset.mems", e))?;
+        fs::write(handle.cpuset.join("cpuset.cpu_exclusive"), "1")
+            .map_err(|e| error("write cpuset.cpu_exclusive", e))?;
+        fs::write(handle.cpuset.join("tasks"), tid.to_string())
+            .map_err(|e| error("write cpuset tasks", e))?;
+        Ok(handle)
+    }
+
+    /// Check whether the CPU affinity of the thread this handle refers to has been changed behind
+    /// its back, e.g. by a cgroup manager. The current affinity is compared to the one last set via
+    /// this handle if any, or to the one the thread had when it was promoted otherwise.
+    ///
+    /// This is cheap enough to be called before each callback. If the affinity has changed, the
+    /// caller can set it again.
+    pub fn thread_affinity_changed(&self) -> Result<bool, AudioThreadPriorityError> {
+        let expected = self
+            .affinity
+            .as_ref()
+            .or(self.affinity_at_promotion.as_ref())
+            .ok_or_else(|| AudioThreadPriorityError::new("no known CPU affinity"))?;
+        Ok(get_thread_affinity(self.thread_info.thread_id)? != *expected)
+    }
+
+    /// Check the state of the thread this handle refers to every `interval`, and call `on_event`
+    /// when something is wrong, until `stop_monitoring` is called. This runs on the calling
+    /// thread, which shouldn't be the thread this handle refers to.
+    ///
+    /// The following is checked:
+    /// - that the real-time priority of the thread is still the one it was promoted to,
+    /// - that the thread doesn't use more CPU time per audio buffer than its soft real-time
+    ///   budget,
+    /// - that its CPU affinity hasn't changed (see `thread_affinity_changed`),
+    /// - that it doesn't cause major page faults.
+    ///
+    /// Checks that can't be performed, e.g. because `/proc` is not available, are skipped.
+    pub fn monitor_loop<F: Fn(MonitorEvent)>(&self, interval: Duration, on_event: F) {
+        let mut last_cpu_time = task_cpu_time(&self.thread_info).ok();
+        let mut last_major_faults = major_faults(&self.thread_info).ok();
+        while !self.stop_monitoring.swap(false, Ordering::Relaxed) {
+            std::thread::sleep(interval);
+
+            if let Ok(attr) = sched_getattr(self.thread_info.thread_id as libc::pid_t) {
+                if attr.sched_priority != self.priority {
+                    on_event(MonitorEvent::PriorityDrifted {
+                        priority: attr.sched_priority,
+                    });
+                }
+            }
+
+            let cpu_time = task_cpu_time(&self.thread_info).ok();
+            if let (Some(last), Some(current)) = (last_cpu_time, cpu_time) {
+                let buffers = interval.as_micros() as f64 / self.latency_target_us as f64;
+                if self.budget_us > 0 && buffers > 0.0 {
+                    let used_us = current.saturating_sub(last).as_micros() as f64 / buffers;
+                    if used_us > self.budget_us as f64 {
+                        on_event(MonitorEvent::BudgetExceeded {
+                            cpu_time_per_buffer_us: used_us as u64,
+                        });
+                    }
+                }
+            }
+            last_cpu_time = cpu_time;
+
+            if let Ok(true) = self.thread_affinity_changed() {
+                on_event(MonitorEvent::AffinityChanged);
+            }
+
+            let faults = major_faults(&self.thread_info).ok();
+            if let (Some(last), Some(current)) = (last_major_faults, faults) {
+                if current > last {
+                    on_event(MonitorEvent::MemoryPressure {
+                        major_faults: current - last,
+                    });
+                }
+            }
+            last_major_faults = faults;
+        }
+    }
+
+    /// Keep the thread this handle refers to at the real-time priority it was promoted to, in case
+    /// something else (e.g. a security policy or another process) changes it. A background thread
+    /// checks the scheduling policy and priority of the thread every `interval`, and promotes it
+    /// again via RTKit if they have changed.
+    ///
+    /// The background thread exits when the returned handle is stopped or dropped, when this
+    /// handle is demoted or dropped, or when the thread this handle refers to exits. This doesn't
+    /// work with `SCHED_DEADLINE`.
+    pub fn freeze_thread_priority(
+        &self,
+        interval: Duration,
+    ) -> Result<FreezeHandle, AudioThreadPriorityError> {
+        if self.deadline_params.is_some() {
+            return Err(AudioThreadPriorityError::new(
+                "can't freeze the priority of a SCHED_DEADLINE thread",
+            ));
+        }
+        let thread_info = self.thread_info;
+        let priority = self.priority;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let demoted = Arc::downgrade(&self.demoted);
+        let thread = std::thread::Builder::new()
+            .name("rt-freeze".to_string())
+            .spawn(move || {
+                while !thread_stop.load(Ordering::Relaxed) {
+                    std::thread::park_timeout(interval);
+                    if thread_stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    // The handle has been dropped.
+                    let demoted = match demoted.upgrade() {
+                        Some(demoted) => demoted,
+                        None => break,
+                    };
+                    let is_demoted = demoted.lock().unwrap_or_else(|e| e.into_inner());
+                    if *is_demoted {
+                        break;
+                    }
+                    // The thread has exited.
+                    let attr = match sched_getattr(thread_info.thread_id as libc::pid_t) {
+                        Ok(attr) => attr,
+                        Err(_) => break,
+                    };
+                    let policy = attr.sched_policy as libc::c_int & !SCHED_RESET_ON_FORK;
+                    if (policy == libc::SCHED_RR || policy == libc::SCHED_FIFO)
+                        && attr.sched_priority == priority
+                    {
+                        continue;
+                    }
+                    warn!(
+                        "Priority of thread {} changed to {}, promoting it again.",
+                        thread_info.thread_id, attr.sched_priority
+                    );
+                    if let Err(e) = rtkit_set_realtime(
+                        &RTKIT,
+                        thread_info.thread_id as u64,
+                        thread_info.pid as u64,
+                        priority,
+                    ) {
+                        warn!("Could not promote thread {}: {}", thread_info.thread_id, e);
+                    }
+                }
+            })
+            .map_err(|e| AudioThreadPriorityError::new_with_inner("spawn", Box::new(e)))?;
+        Ok(FreezeHandle {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Make `monitor_loop` return once its current iteration completes: the request is checked
+    /// before each iteration. If `monitor_loop` is not running, the next call returns
+    /// immediately, without checking anything.
+    pub fn stop_monitoring(&self) {
+        self.stop_monitoring.store(true, Ordering::Relaxed);
+    }
+
+    /// Block the thread this handle refers to for a moment, so that its real-time budget is
+    /// available again, and return how long it was blocked.
+    ///
+    /// The kernel resets the `RLIMIT_RTTIME` counter of a thread whenever it blocks, there is no
+    /// other budget period. This sleeps for `budget_margin_us` (at least 1us), i.e. the part of an
+    /// audio buffer that isn't covered by the budget, which is useful to pace threads that
+    /// process in bursts. This has to be called on the thread this handle refers to.
+    pub fn pause_and_wait_for_budget_reset(&self) -> Result<Duration, AudioThreadPriorityError> {
+        if unsafe { libc::syscall(libc::SYS_gettid) } != self.thread_info.thread_id {
+            return Err(AudioThreadPriorityError::new(
+                "pause_and_wait_for_budget_reset called on another thread",
+            ));
+        }
+        let pause_us = cmp::max(self.budget_margin_us(), 1);
+        let start = Instant::now();
+        monotonic_sleep(Duration::from_micros(pause_us))?;
+        Ok(start.elapsed())
+    }
+
+    /// How early, in microseconds, the thread this handle refers to should ask to be woken up to
+    /// be running on time despite the scheduling jitter: the 95th percentile of the jitter plus
+    /// `JITTER_SAFETY_MARGIN_US`.
+    ///
+    /// The jitter is measured on the calling thread, which takes a few milliseconds: this should
+    /// be called on the thread this handle refers to, before it starts processing audio.
+    pub fn jitter_compensation_offset_us(&self) -> i64 {
+        let jitter_us = measure_scheduling_jitter_us();
+        jitter_us[jitter_us.len() * 95 / 100] as i64 + JITTER_SAFETY_MARGIN_US
+    }
+
+    /// The expected maximum latency of an audio callback of the thread this handle refers to, in
+    /// microseconds, including the scheduling overhead: the real-time budget plus the 99th
+    /// percentile of the scheduling jitter.
+    ///
+    /// The jitter measured at promotion time is used if `RtPriorityRequest::measure_initial_jitter`
+    /// was set. Otherwise, it is measured now on the calling thread, which takes a few
+    /// milliseconds: this should then be called on the thread this handle refers to.
+    pub fn effective_latency_us(&self) -> u64 {
+        let jitter_p99_us = self
+            .initial_jitter_p99_us
+            .unwrap_or_else(measure_scheduling_jitter_p99_us);
+        self.budget_us + jitter_p99_us
+    }
+
+    /// When enabled, `sleep_with_jitter_compensation` wakes the thread up
+    /// `jitter_compensation_offset_us` early. The offset is measured when this is called, so this
+    /// should be called on the thread this handle refers to, before it starts processing audio.
+    pub fn set_jitter_compensation_enabled(&mut self, enabled: bool) {
+        self.jitter_compensation_us = if enabled {
+            Some(self.jitter_compensation_offset_us())
+        } else {
+            None
+        };
+    }
+
+    /// Sleep for `duration` with `clock_nanosleep`, minus the jitter compensation offset if it has
+    /// been enabled with `set_jitter_compensation_enabled`, and return how long the thread slept.
+    pub fn sleep_with_jitter_compensation(
+        &self,
+        duration: Duration,
+    ) -> Result<Duration, AudioThreadPriorityError> {
+        let offset = Duration::from_micros(self.jitter_compensation_us.unwrap_or(0).max(0) as u64);
+        let start = Instant::now();
+        monotonic_sleep(duration.saturating_sub(offset))?;
+        Ok(start.elapsed())
+    }
+
+    /// Run `f`, a very short critical section (e.g. writing hardware registers), at the highest
+    /// `SCHED_FIFO` priority, with the memory of the process locked, so that no other user-space
+    /// thread can preempt it. This must be called on the thread this handle refers to.
+    ///
+    /// This is the closest user-space equivalent of disabling preemption: interrupts and kernel
+    /// threads at a higher priority still run. Raising the priority to the maximum usually
+    /// requires `CAP_SYS_NICE`, RTKit doesn't allow it. If it fails, `f` isn't run and an error is
+    /// returned. The scheduling of the thread is restored afterwards, and the memory unlocked if
+    /// it wasn't locked before, also if `f` panics.
+    ///
+    /// Whether the memory was locked before is read from `VmLck`, which is for the whole process:
+    /// if another thread calls `mlockall` while `f` runs, its lock is undone too.
+    pub fn preemption_deferred_section<R>(
+        &self,
+        f: impl FnOnce() -> R,
+    ) -> Result<R, AudioThreadPriorityError> {
+        if unsafe { libc::syscall(libc::SYS_gettid) } != self.thread_info.thread_id {
+            return Err(AudioThreadPriorityError::new(
+                "preemption_deferred_section called on another thread",
+            ));
+        }
+        let thread = unsafe { libc::pthread_self() };
+        let mut original_policy = 0;
+        let mut original_param = libc::sched_param { sched_priority: 0 };
+        let rv = unsafe {
+            libc::pthread_getschedparam(thread, &mut original_policy, &mut original_param)
+        };
+        if rv != 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "pthread_getschedparam",
+                Box::new(OSError::from_raw_os_error(rv)),
+            ));
+        }
+        // Restores the scheduling and the memory locking, also when unwinding.
+        struct Restore {
+            thread: libc::pthread_t,
+            policy: libc::c_int,
+            param: libc::sched_param,
+            restore_scheduling: bool,
+            unlock: bool,
+        }
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                if self.restore_scheduling {
+                    let rv = unsafe {
+                        libc::pthread_setschedparam(self.thread, self.policy, &self.param)
+                    };
+                    if rv != 0 {
+                        warn!(
+                            "Could not restore the scheduling of the thread: {}",
+                            OSError::from_raw_os_error(rv)
+                        );
+                    }
+                }
+                if self.unlock {
+                    unsafe { libc::munlockall() };
+                }
+            }
+        }
+
+        let was_locked = task_status_field(&self.thread_info, "VmLck").unwrap_or(0) > 0;
+        if unsafe { libc::mlockall(libc::MCL_CURRENT) } < 0 {
+            warn!("mlockall failed: {}", OSError::last_os_error());
+        }
+        let mut restore = Restore {
+            thread,
+            policy: original_policy,
+            param: original_param,
+            restore_scheduling: false,
+            unlock: !was_locked,
+        };
+        let param = libc::sched_param {
+            sched_priority: unsafe { libc::sched_get_priority_max(libc::SCHED_FIFO) },
+        };
+        let rv = unsafe { libc::pthread_setschedparam(thread, libc::SCHED_FIFO, &param) };
+        if rv != 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "pthread_setschedparam",
+                Box::new(OSError::from_raw_os_error(rv)),
+            ));
+        }
+        restore.restore_scheduling = true;
+        Ok(f())
+    }
+
+    /// Create a mutex using the priority ceiling protocol (`PTHREAD_PRIO_PROTECT`) with a ceiling
+    /// of `ceiling`, to protect data shared between the thread this handle refers to and other
+    /// threads. Any thread holding it runs at `ceiling`, so that it can't be preempted by the
+    /// real-time thread waiting for it.
+    ///
+    /// `ceiling` has to be a valid `SCHED_FIFO` priority, and at least the priority of the thread.
+    /// This replaces the mutex created by a previous call: it can't be locked at this point,
+    /// because the guards returned by `lock_priority_ceiling_mutex` borrow this handle.
+    pub fn set_priority_ceiling(&mut self, ceiling: u32) -> Result<(), AudioThreadPriorityError> {
+        let max = unsafe { libc::sched_get_priority_max(libc::SCHED_FIFO) };
+        if ceiling < self.priority || ceiling as i64 > max as i64 {
+            return Err(AudioThreadPriorityError::new(&format!(
+                "invalid priority ceiling {}, must be between {} and {}",
+                ceiling, self.priority, max
+            )));
+        }
+        self.priority_ceiling_mutex = Some(PriorityCeilingMutex::new(ceiling)?);
+        Ok(())
+    }
+
+    /// Lock the mutex created by `set_priority_ceiling`. The calling thread runs at the priority
+    /// ceiling until the returned guard is dropped.
+    ///
+    /// This fails if there is no such mutex, or if the priority of the calling thread is above the
+    /// ceiling.
+    pub fn lock_priority_ceiling_mutex(
+        &self,
+    ) -> Result<PriorityCeilingGuard<'_>, AudioThreadPriorityError> {
+        let mutex = self
+            .priority_ceiling_mutex
+            .as_ref()
+            .ok_or_else(|| AudioThreadPriorityError::new("no priority ceiling mutex"))?;
+        let rv = unsafe { libc::pthread_mutex_lock(mutex.mutex.get()) };
+        if rv != 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "pthread_mutex_lock",
+                Box::new(OSError::from_raw_os_error(rv)),
+            ));
+        }
+        Ok(PriorityCeilingGuard {
+            mutex,
+            _not_send: std::marker::PhantomData,
+        })
+    }
+
+    /// Check that the priority ceiling of `mutex` is at least the priority of the thread this
+    /// handle refers to. Otherwise, the thread fails to lock `mutex`.
+    ///
+    /// # Safety
+    ///
+    /// `mutex` must point to an initialized mutex.
+    pub unsafe fn validate_priority_ceiling(
+        &self,
+        mutex: *const libc::pthread_mutex_t,
+    ) -> Result<(), AudioThreadPriorityError> {
+        let mut ceiling: libc::c_int = 0;
+        let rv = pthread_mutex_getprioceiling(mutex, &mut ceiling);
+        if rv != 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "pthread_mutex_getprioceiling",
+                Box::new(OSError::from_raw_os_error(rv)),
+            ));
+        }
+        if (ceiling as i64) < self.priority as i64 {
+            return Err(AudioThreadPriorityError::new(&format!(
+                "priority ceiling {} lower than the thread priority {}",
+                ceiling, self.priority
+            )));
+        }
+        Ok(())
+    }
+
+    /// Record that the thread this handle refers to holds (or takes) the mutex at `mutex_addr`,
+    /// for `check_for_priority_inheritance_violation`. This is only available in debug builds.
+    ///
+    /// # Safety
+    ///
+    /// `mutex_addr` must be the address of an initialized `pthread_mutex_t`, that stays valid
+    /// until it is unregistered with `unregister_mutex`.
+    #[cfg(debug_assertions)]
+    pub unsafe fn register_mutex(&mut self, mutex_addr: usize) {
+        self.registered_mutexes.push(mutex_addr);
+    }
+
+    /// Stop tracking the mutex at `mutex_addr`, registered with `register_mutex`.
+    #[cfg(debug_assertions)]
+    pub fn unregister_mutex(&mut self, mutex_addr: usize) {
+        if let Some(i) = self
+            .registered_mutexes
+            .iter()
+            .position(|&m| m == mutex_addr)
+        {
+            self.registered_mutexes.swap_remove(i);
+        }
+    }
+
+    /// Check each mutex registered with `register_mutex` with `validate_priority_ceiling`, and
+    /// return the address of the mutexes that can cause a priority inversion, with the reason:
+    /// mutexes without the priority ceiling protocol, or with a ceiling lower than the priority
+    /// of the thread. This is only available in debug builds.
+    #[cfg(debug_assertions)]
+    pub fn check_for_priority_inheritance_violation(
+        &self,
+    ) -> Vec<(usize, AudioThreadPriorityError)> {
+        self.registered_mutexes
+            .iter()
+            .filter_map(|&mutex_addr| {
+                // Valid per the contract of `register_mutex`.
+                unsafe {
+                    self.validate_priority_ceiling(mutex_addr as *const libc::pthread_mutex_t)
+                }
+                .err()
+                .map(|e| (mutex_addr, e))
+            })
+            .collect()
+    }
+
+    /// Check that the thread `other_tid` of the same process, that has a lower priority than the
+    /// thread this handle refers to, doesn't hold `mutex`, a mutex the real-time thread needs:
+    /// the real-time thread would then wait for a thread that can be preempted by any other
+    /// real-time thread. The error describes what the other thread is blocked on, from its
+    /// `wchan`.
+    ///
+    /// A mutex with a priority ceiling at least as high as the priority of the real-time thread
+    /// doesn't cause an inversion, because the thread holding it runs at the ceiling. The owner
+    /// of the mutex can only be found with glibc: with other C libraries, `mutex` is assumed to be
+    /// held by `other_tid`.
+    ///
+    /// # Safety
+    ///
+    /// `mutex` must point to an initialized `pthread_mutex_t`.
+    pub unsafe fn verify_no_lock_inversion_with_nonrt_thread(
+        &self,
+        other_tid: u64,
+        mutex: *const libc::pthread_mutex_t,
+    ) -> Result<(), AudioThreadPriorityError> {
+        let other_policy = libc::sched_getscheduler(other_tid as libc::pid_t);
+        let mut other_param = libc::sched_param { sched_priority: 0 };
+        if other_policy < 0 || libc::sched_getparam(other_tid as libc::pid_t, &mut other_param) < 0
+        {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "sched_getscheduler",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        let other_priority = match other_policy & !SCHED_RESET_ON_FORK {
+            libc::SCHED_FIFO | libc::SCHED_RR => other_param.sched_priority as u32,
+            _ => 0,
+        };
+        if other_priority >= self.priority {
+            return Ok(());
+        }
+        let mut ceiling: libc::c_int = 0;
+        if pthread_mutex_getprioceiling(mutex, &mut ceiling) == 0
+            && ceiling as i64 >= self.priority as i64
+        {
+            return Ok(());
+        }
+        if mutex_owner(mutex).is_some_and(|owner| owner as u64 != other_tid) {
+            return Ok(());
+        }
+        let wchan = fs::read_to_string(format!(
+            "/proc/{}/task/{}/wchan",
+            self.thread_info.pid, other_tid
+        ))
+        .map_err(|e| AudioThreadPriorityError::new_with_inner("read wchan", Box::new(e)))?;
+        let blocked_on = match wchan.trim() {
+            "" | "0" => "running".to_string(),
+            wchan => format!("blocked in {}", wchan),
+        };
+        Err(AudioThreadPriorityError::new(&format!(
+            "priority inversion: thread {} (priority {}, {}) holds a mutex needed by thread {} \
+             (priority {})",
+            other_tid, other_priority, blocked_on, self.thread_info.thread_id, self.priority
+        )))
+    }
+
+    /// Check that the thread this handle refers to is currently running (or last ran) on one of
+    /// the CPUs it is allowed to run on: the CPUs set via this handle if any, or the CPU affinity
+    /// of the thread otherwise.
+    ///
+    /// This should always be the case, but kernel bugs or changes to the cgroup of the thread can
+    /// break this assumption. `false` is also returned if this can't be checked.
+    pub fn verify_thread_is_pinned_to_rt_cpu(&self) -> bool {
+        let cpu = match read_task_stat(&self.thread_info).and_then(|f| task_stat_field(&f, 39)) {
+            Ok(cpu) => cpu as usize,
+            Err(_) => return false,
+        };
+        match &self.affinity {
+            Some(cpus) => cpus.contains(&cpu),
+            None => get_thread_affinity(self.thread_info.thread_id)
+                .map(|cpus| cpus.contains(&cpu))
+                .unwrap_or(false),
+        }
+    }
+
+    /// Gather the state of the thread this handle refers to in a single report.
+    // kernel_pid_t is not 64 bits on all platforms.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn report(&self) -> Result<RtReport, AudioThreadPriorityError> {
+        let label = fs::read_to_string(task_proc_path(&self.thread_info).join("comm"))
+            .map_err(|e| AudioThreadPriorityError::new_with_inner("read comm", Box::new(e)))?
+            .trim_end()
+            .to_string();
+
+        Ok(RtReport {
+            thread_id: self.thread_info.thread_id as i64,
+            pid: self.thread_info.pid,
+            label,
+            effective_priority: self.priority,
+            effective_budget_us: self.budget_us,
+            time_since_promotion: self.promoted_at.map(|t| t.elapsed()),
+            is_still_realtime: self.priority_class() == PriorityClass::Realtime,
+            involuntary_context_switches: task_status_field(
+                &self.thread_info,
+                "nonvoluntary_ctxt_switches",
+            )?,
+            major_faults: self.major_faults_since_promotion()?,
+            jitter_p99_us: None,
+        })
+    }
+
+    /// Send an `org.audio.RtThread.Report` signal on the session bus, for audio frameworks (e.g.
+    /// PipeWire) that aggregate the statistics of the real-time threads of a session.
+    ///
+    /// The arguments of the signal are `session_name`, followed by the `pid`, `tid`, `priority`,
+    /// `budget_us`, `jitter_p99_us` and `involuntary_switches` of the thread. The jitter is
+    /// measured on the calling thread, which takes a few milliseconds: this should be called on
+    /// the thread this handle refers to, outside of the audio callback.
+    // kernel_pid_t is not 64 bits on all platforms.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn report_to_dbus_session(
+        &self,
+        session_name: &str,
+    ) -> Result<(), AudioThreadPriorityError> {
+        let involuntary_switches =
+            task_status_field(&self.thread_info, "nonvoluntary_ctxt_switches")?;
+        let mut m = Message::new_signal("/org/audio/RtThread", "org.audio.RtThread", "Report")
+            .map_err(|e| AudioThreadPriorityError::new(&e))?;
+        m.append_items(&[
+            session_name.into(),
+            self.thread_info.pid.into(),
+            (self.thread_info.thread_id as i64).into(),
+            self.priority.into(),
+            self.budget_us.into(),
+            measure_scheduling_jitter_p99_us().into(),
+            involuntary_switches.into(),
+        ]);
+        with_dbus_connection(BusType::Session, |c| {
+            c.send(m)
+                .map(|_| ())
+                .map_err(|_| AudioThreadPriorityError::new("could not send the report signal"))
+        })
+    }
+
+    /// Whether the process containing the thread this handle refers to still exists. This is
+    /// useful when the handle has been sent to another process.
+    pub fn is_process_alive(&self) -> bool {
+        process_is_alive(self.thread_info.pid)
+    }
+
+    /// Measure the rate of involuntary context switches of the thread this handle refers to, per
+    /// second, over 100ms. This blocks the calling thread for that long.
+    ///
+    /// A rate above about 100 per second means that the thread is regularly preempted, e.g.
+    /// because of priority inversion or because it exhausts its real-time budget.
+    pub fn context_switch_rate(&self) -> Result<f64, AudioThreadPriorityError> {
+        const SAMPLING_INTERVAL: Duration = Duration::from_millis(100);
+        let before = task_status_field(&self.thread_info, "nonvoluntary_ctxt_switches")?;
+        std::thread::sleep(SAMPLING_INTERVAL);
+        let after = task_status_field(&self.thread_info, "nonvoluntary_ctxt_switches")?;
+        Ok(after.saturating_sub(before) as f64 / SAMPLING_INTERVAL.as_secs_f64())
+    }
+
+    /// Get a systemd unit file directive that passes the real-time configuration of the thread
+    /// this handle refers to (priority, budget and scheduling policy) to a service, as the
+    /// `audio-rt` credential, e.g.
+    /// `SetCredential=audio-rt:priority=10,budget_us=5000,policy=SCHED_RR`.
+    ///
+    /// The service can read it back with `load_from_systemd_credential`.
+    pub fn to_systemd_cred_spec(&self) -> String {
+        // RTKit always uses SCHED_RR.
+        let policy = if self.deadline_params.is_some() {
+            "SCHED_DEADLINE"
+        } else {
+            "SCHED_RR"
+        };
+        format!(
+            "SetCredential={}:priority={},budget_us={},policy={}",
+            SYSTEMD_CREDENTIAL_NAME, self.priority, self.budget_us, policy
+        )
+    }
+
+    /// Format this handle like a fragment of `/proc/<pid>/task/<tid>/status`, with the scheduling
+    /// of the thread added, so that code parsing `/proc` can be tested with synthetic handles,
+    /// e.g. created with `from_raw_handle`, without real-time threads. For example:
+    ///
+    /// ```text
+    /// Name:   AudioMixer
+    /// Pid:    12345
+    /// Tgid:   12340
+    /// Policy: SCHED_RR
+    /// RtPrio: 10
+    /// ```
+    ///
+    /// As in `/proc`, the values are separated from the keys by tabs, `Pid` is the thread id and
+    /// `Tgid` the process id. The name is read from `/proc` if the thread exists, and is
+    /// `thread-<tid>` otherwise. The policy is guessed like in `to_systemd_cred_spec` for promoted
+    /// threads.
+    pub fn to_linux_proc_entry(&self) -> String {
+        let name = fs::read_to_string(task_proc_path(&self.thread_info).join("comm"))
+            .map(|comm| comm.trim_end().to_string())
+            .unwrap_or_else(|_| format!("thread-{}", self.thread_info.thread_id));
+        let policy = if self.deadline_params.is_some() {
+            "SCHED_DEADLINE"
+        } else if self.priority > 0 {
+            // RTKit always uses SCHED_RR.
+            "SCHED_RR"
+        } else {
+            match self.thread_info.policy & !SCHED_RESET_ON_FORK {
+                libc::SCHED_FIFO => "SCHED_FIFO",
+                libc::SCHED_RR => "SCHED_RR",
+                libc::SCHED_BATCH => "SCHED_BATCH",
+                libc::SCHED_IDLE => "SCHED_IDLE",
+                SCHED_DEADLINE => "SCHED_DEADLINE",
+                _ => "SCHED_OTHER",
+            }
+        };
+        format!(
+            "Name:\t{}\nPid:\t{}\nTgid:\t{}\nPolicy:\t{}\nRtPrio:\t{}\n",
+            name, self.thread_info.thread_id, self.thread_info.pid, policy, self.priority
+        )
+    }
+
+    /// Read the real-time configuration passed to the current service with a directive generated
+    /// by `to_systemd_cred_spec`, from the `audio-rt` file of the directory in the
+    /// `CREDENTIALS_DIRECTORY` environment variable.
+    pub fn load_from_systemd_credential() -> Result<SystemdRtConfig, AudioThreadPriorityError> {
+        let dir = std::env::var_os("CREDENTIALS_DIRECTORY")
+            .ok_or_else(|| AudioThreadPriorityError::new("CREDENTIALS_DIRECTORY not set"))?;
+        let path = Path::new(&dir).join(SYSTEMD_CREDENTIAL_NAME);
+        let spec = fs::read_to_string(&path).map_err(|e| {
+            AudioThreadPriorityError::new_with_inner("read credential", Box::new(e))
+        })?;
+
+        let mut priority = None;
+        let mut budget_us = None;
+        let mut policy = None;
+        for entry in spec.trim().split(',') {
+            let invalid = || AudioThreadPriorityError::new(&format!("invalid entry {:?}", entry));
+            let (key, value) = entry.split_once('=').ok_or_else(invalid)?;
+            match key {
+                "priority" => priority = Some(value.parse().map_err(|_| invalid())?),
+                "budget_us" => budget_us = Some(value.parse().map_err(|_| invalid())?),
+                "policy" => {
+                    policy = Some(match value {
+                        "SCHED_RR" => libc::SCHED_RR,
+                        "SCHED_FIFO" => libc::SCHED_FIFO,
+                        "SCHED_DEADLINE" => SCHED_DEADLINE,
+                        _ => return Err(invalid()),
+                    })
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        match (priority, budget_us, policy) {
+            (Some(priority), Some(budget_us), Some(policy)) => Ok(SystemdRtConfig {
+                priority,
+                budget_us,
+                policy,
+            }),
+            _ => Err(AudioThreadPriorityError::new(&format!(
+                "incomplete credential {:?}",
+                spec
+            ))),
+        }
+    }
+
+    /// The semantic band of the real-time priority requested at promotion time.
+    pub fn priority_band(&self) -> PriorityBand {
+        PriorityBand::from_priority(self.priority)
+    }
+
+    /// Demote the thread this handle refers to when the normal demotion fails, e.g. with `EPERM`
+    /// because the process has dropped `CAP_SYS_NICE`: the scheduling policy is reset with
+    /// `sched_setscheduler`, which works for threads of other processes. If that fails too, the
+    /// nice value of the thread is still reset with `setpriority(PRIO_PROCESS, tid, 0)`, but an
+    /// error is returned, because the thread is still real-time.
+    ///
+    /// Unlike `demote_current_thread_from_real_time`, this doesn't restore the other settings
+    /// changed at promotion time.
+    pub fn force_demotion(&self) -> Result<(), AudioThreadPriorityError> {
+        force_demote(self.thread_info.thread_id)
+    }
+
+    /// Demote the thread `handle` refers to from a signal handler, e.g. for `SIGXCPU` or
+    /// `SIGTERM`: this only makes the `sched_setscheduler` system call on the thread id stored in
+    /// the handle, resetting the thread to `SCHED_OTHER`, and returns 0 or an error number. System
+    /// calls are async-signal-safe, unlike `pthread_setschedparam`, which is not on the POSIX list
+    /// and needs a valid pthread id. Nothing is logged, the handle is left untouched, and the
+    /// other settings changed at promotion time are not restored, drop or demote the handle
+    /// normally once out of the signal handler for that.
+    ///
+    /// `EINVAL` is returned if `handle` is null.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be null or point to a valid handle, that is not being modified concurrently.
+    pub unsafe fn signal_safe_demote(handle: *const RtPriorityHandleInternal) -> libc::c_int {
+        if handle.is_null() {
+            return libc::EINVAL;
+        }
+        let param = libc::sched_param { sched_priority: 0 };
+        if libc::syscall(
+            libc::SYS_sched_setscheduler,
+            (*handle).thread_info.thread_id,
+            libc::SCHED_OTHER | SCHED_RESET_ON_FORK,
+            &param,
+        ) < 0
+        {
+            return *libc::__errno_location();
+        }
+        0
+    }
+
+    /// Demote the calling thread, that has to be the thread this handle refers to, while the
+    /// audio device changes, e.g. when the default output device is switched. `new_request` is
+    /// the request the thread is going to be promoted again with, for the new device: it is
+    /// checked before demoting the thread, and nothing is done if it's invalid.
+    ///
+    /// Once the device change is complete, call `SuspendedRtHandle::resume` to promote the thread
+    /// again. This fails for handles that weren't created by promoting a thread, e.g.
+    /// deserialized handles.
+    ///
+    /// If this fails, the handle is returned along with the error, and the thread is still
+    /// real-time.
+    pub fn suspend_for_device_change(
+        self,
+        new_request: &RtPriorityRequest,
+    ) -> Result<SuspendedRtHandle, Box<(RtPriorityHandleInternal, AudioThreadPriorityError)>> {
+        let check = || {
+            if new_request.audio_samplerate_hz == 0 {
+                return Err(AudioThreadPriorityError::new("sample rate is zero"));
+            }
+            request_budget_us(new_request)?;
+            if unsafe { libc::syscall(libc::SYS_gettid) } != self.thread_info.thread_id {
+                return Err(AudioThreadPriorityError::new(
+                    "suspend_for_device_change called on another thread",
+                ));
+            }
+            self.request.clone().ok_or_else(|| {
+                AudioThreadPriorityError::new("the handle wasn't created by a promotion")
+            })
+        };
+        let previous_request = match check() {
+            Ok(previous_request) => previous_request,
+            Err(e) => return Err(Box::new((self, e))),
+        };
+        if let Err(e) = demote_handle(&self) {
+            return Err(Box::new((self, e)));
+        }
+        Ok(SuspendedRtHandle { previous_request })
+    }
+
+    /// Get the current scheduling class of the thread this handle refers to.
+    ///
+    /// This queries the kernel, and works for threads in other processes. If the scheduling policy
+    /// can't be queried (e.g. because the thread has exited), `PriorityClass::Normal` is returned.
+    pub fn priority_class(&self) -> PriorityClass {
+        let tid = self.thread_info.thread_id as libc::pid_t;
+        let policy = unsafe { libc::sched_getscheduler(tid) };
+        if policy < 0 {
+            return PriorityClass::Normal;
+        }
+        match policy & !SCHED_RESET_ON_FORK {
+            libc::SCHED_FIFO | libc::SCHED_RR => PriorityClass::Realtime,
+            libc::SCHED_IDLE => PriorityClass::Idle,
+            libc::SCHED_BATCH => PriorityClass::BelowNormal,
+            _ => {
+                // Time-shared thread, use the nice value to refine the class. -1 is a valid nice
+                // value, errno has to be checked to detect errors.
+                unsafe { *libc::__errno_location() = 0 };
+                let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, tid as libc::id_t) };
+                if nice == -1 && unsafe { *libc::__errno_location() } != 0 {
+                    return PriorityClass::Normal;
+                }
+                match nice.cmp(&0) {
+                    cmp::Ordering::Less => PriorityClass::AboveNormal,
+                    cmp::Ordering::Equal => PriorityClass::Normal,
+                    cmp::Ordering::Greater => PriorityClass::BelowNormal,
+                }
+            }
+        }
+    }
+
+    /// Get the scheduling policy and priority of all the threads of the process containing the
+    /// thread this handle refers to, this thread included, sorted by decreasing real-time
+    /// priority.
+    ///
+    /// Threads that exit while the list is being built are skipped.
+    pub fn get_sibling_handles_in_process(
+        &self,
+    ) -> Result<Vec<ThreadPriorityInfo>, AudioThreadPriorityError> {
+        let task_dir = PathBuf::from(format!("/proc/{}/task", self.thread_info.pid));
+        let entries = fs::read_dir(&task_dir)
+            .map_err(|e| AudioThreadPriorityError::new_with_inner("read task", Box::new(e)))?;
+        let mut threads = Vec::new();
+        for entry in entries {
+            let entry = entry
+                .map_err(|e| AudioThreadPriorityError::new_with_inner("read task", Box::new(e)))?;
+            let tid: libc::pid_t = match entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+                Some(tid) => tid,
+                None => continue,
+            };
+            let attr = match sched_getattr(tid) {
+                Ok(attr) => attr,
+                Err(_) => continue,
+            };
+            let thread_name = match fs::read_to_string(entry.path().join("comm")) {
+                Ok(comm) => comm.trim_end().to_string(),
+                Err(_) => continue,
+            };
+            threads.push(ThreadPriorityInfo {
+                tid: tid as i64,
+                policy: attr.sched_policy as libc::c_int,
+                priority: attr.sched_priority,
+                thread_name,
+            });
+        }
+        threads.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.tid.cmp(&b.tid)));
+        Ok(threads)
+    }
+
+    /// Attach `semaphore` to this handle, for `signal_callback_done`. The semaphore is typically
+    /// shared with a non-real-time consumer thread, that waits on it with `Semaphore::wait`.
+    pub fn with_attached_semaphore(
+        mut self,
+        semaphore: Arc<Semaphore>,
+    ) -> RtPriorityHandleInternal {
+        self.attached_semaphore = Some(semaphore);
+        self
+    }
+
+    /// Post the semaphore attached with `with_attached_semaphore`, to signal the consumer thread
+    /// that a callback completed. This doesn't allocate nor block, and can be called from the
+    /// real-time thread. Returns 0 on success, including when no semaphore is attached, or an
+    /// error number.
+    pub fn signal_callback_done(&self) -> libc::c_int {
+        match &self.attached_semaphore {
+            Some(semaphore) => semaphore.post(),
+            None => 0,
+        }
+    }
+
+    /// Register the thread this handle refers to with `registry`, see `RtRegistry::register`.
+    /// Unlike `RtRegistry::register`, the thread is unregistered automatically when this handle
+    /// is dropped or demoted.
+    #[cfg(feature = "registry")]
+    pub fn register_with_rt_registry(&mut self, registry: &RtRegistry) {
+        registry.register(self);
+        self.registry_entry = Some(RegistryEntry {
+            threads: Arc::downgrade(&registry.threads),
+            thread_id: self.thread_info.thread_id as u64,
+        });
+    }
+
+    /// Read a handle previously written to `path` with `save_to_file`.
+    ///
+    /// This fails if the file doesn't start with the expected magic number and format version, or
+    /// if it doesn't have the expected size.
+    pub fn load_from_file(
+        path: &Path,
+    ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+        let mut bytes = Vec::new();
+        fs::File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .map_err(|e| AudioThreadPriorityError::new_with_inner("read", Box::new(e)))?;
+
+        let header_len = HANDLE_FILE_MAGIC.len() + std::mem::size_of::<u32>();
+        if bytes.len() != header_len + std::mem::size_of::<RtPriorityThreadInfoInternal>() {
+            return Err(AudioThreadPriorityError::new("invalid handle file size"));
+        }
+        if bytes[..HANDLE_FILE_MAGIC.len()] != HANDLE_FILE_MAGIC {
+            return Err(AudioThreadPriorityError::new("invalid handle file magic"));
+        }
+        let mut version = [0; std::mem::size_of::<u32>()];
+        version.copy_from_slice(&bytes[HANDLE_FILE_MAGIC.len()..header_len]);
+        if u32::from_ne_bytes(version) != HANDLE_FILE_VERSION {
+            return Err(AudioThreadPriorityError::new(
+                "unsupported handle file version",
+            ));
+        }
+
+        let mut serialized = [0; std::mem::size_of::<RtPriorityThreadInfoInternal>()];
+        serialized.copy_from_slice(&bytes[header_len..]);
+
+        Ok(RtPriorityHandleInternal::new(
+            RtPriorityThreadInfoInternal::deserialize(serialized),
+        ))
+    }
+}
+
+/// The scheduling parameters of a thread, as returned by
+/// `RtPriorityHandle::get_sibling_handles_in_process`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThreadPriorityInfo {
+    /// The system-wide thread id.
+    pub tid: i64,
+    /// The scheduling policy, e.g. `libc::SCHED_FIFO`.
+    pub policy: libc::c_int,
+    /// The real-time priority, 0 for threads that are not real-time.
+    pub priority: u32,
+    /// The name of the thread.
+    pub thread_name: String,
+}
+
+/// The real-time threads of a process, registered with `register` or
+/// `RtPriorityHandle::register_with_rt_registry`, e.g. to list them in diagnostics, or to demote
+/// them all on shutdown. A registry can be cloned, the clones refer to the same threads.
+#[cfg(feature = "registry")]
+#[derive(Clone, Default)]
+pub struct RtRegistry {
+    threads: Arc<RwLock<HashMap<u64, ThreadPriorityInfo>>>,
+}
+
+#[cfg(feature = "registry")]
+impl RtRegistry {
+    /// Create an empty registry.
+    pub fn new() -> RtRegistry {
+        RtRegistry::default()
+    }
+
+    /// Add the thread `handle` refers to, with its priority at promotion time, replacing a
+    /// previous registration of the same thread. The thread stays registered until `unregister`
+    /// or `demote_all` is called.
+    pub fn register(&self, handle: &RtPriorityHandleInternal) {
+        let thread_id = handle.thread_info.thread_id;
+        let thread_name = fs::read_to_string(task_proc_path(&handle.thread_info).join("comm"))
+            .map(|comm| comm.trim_end().to_string())
+            .unwrap_or_default();
+        // kernel_pid_t is not 64 bits on all platforms.
+        #[allow(clippy::unnecessary_cast)]
+        let info = ThreadPriorityInfo {
+            tid: thread_id as i64,
+            policy: handle.thread_info.policy,
+            priority: handle.priority,
+            thread_name,
+        };
+        self.threads
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(thread_id as u64, info);
+    }
+
+    /// Remove the thread `thread_id`, if it is registered.
+    pub fn unregister(&self, thread_id: u64) {
+        self.threads
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&thread_id);
+    }
+
+    /// The registered threads, sorted by thread id.
+    pub fn all_handles(&self) -> Vec<ThreadPriorityInfo> {
+        let mut threads: Vec<ThreadPriorityInfo> = self
+            .threads
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .cloned()
+            .collect();
+        threads.sort_by_key(|thread| thread.tid);
+        threads
+    }
+
+    /// Demote all the registered threads with `sched_setscheduler`, like
+    /// `RtPriorityHandle::force_demotion`, and unregister them. This returns the number of threads
+    /// demoted, failures are logged.
+    pub fn demote_all(&self) -> usize {
+        let threads = std::mem::take(&mut *self.threads.write().unwrap_or_else(|e| e.into_inner()));
+        threads
+            .keys()
+            .filter(
+                |&&thread_id| match force_demote(thread_id as kernel_pid_t) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        warn!("Could not demote thread {}: {}", thread_id, e);
+                        false
+                    }
+                },
+            )
+            .count()
+    }
+}
+
+/// The registration of a handle with `RtPriorityHandle::register_with_rt_registry`, removed on
+/// drop.
+#[cfg(feature = "registry")]
+struct RegistryEntry {
+    threads: Weak<RwLock<HashMap<u64, ThreadPriorityInfo>>>,
+    thread_id: u64,
+}
+
+#[cfg(feature = "registry")]
+impl Drop for RegistryEntry {
+    fn drop(&mut self) {
+        if let Some(threads) = self.threads.upgrade() {
+            threads
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&self.thread_id);
+        }
+    }
+}
+
+/// A real-time configuration passed to a systemd service, see
+/// `RtPriorityHandle::load_from_systemd_credential`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SystemdRtConfig {
+    /// The real-time priority.
+    pub priority: u32,
+    /// The real-time budget, in microseconds.
+    pub budget_us: u64,
+    /// The scheduling policy, e.g. `libc::SCHED_RR`.
+    pub policy: libc::c_int,
+}
+
+/// The `SCHED_DEADLINE` parameters of a thread, see
+/// `RtPriorityHandle::get_scheduler_deadline_params`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeadlineParams {
+    /// The CPU time the thread can use each period, in nanoseconds.
+    pub runtime_ns: u64,
+    /// The time after the start of each period by which the runtime has to be used, in
+    /// nanoseconds.
+    pub deadline_ns: u64,
+    /// The period, in nanoseconds.
+    pub period_ns: u64,
+}
+
+impl DeadlineParams {
+    /// The fraction of a CPU reserved for the thread, `runtime_ns / period_ns`, or 0.0 if the
+    /// period is 0.
+    pub fn utilization_fraction(&self) -> f64 {
+        if self.period_ns == 0 {
+            return 0.0;
+        }
+        self.runtime_ns as f64 / self.period_ns as f64
+    }
+}
+
+/// How a thread was promoted by `RtPriorityHandle::promote_with_fallback_chain`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PromotionMethod {
+    /// `SCHED_DEADLINE`, set with `sched_setattr`.
+    #[cfg(feature = "sched-deadline")]
+    Deadline,
+    /// `SCHED_RR`, set by RTKit.
+    Rtkit,
+    /// `SCHED_FIFO`, set with `sched_setscheduler`.
+    SchedSetscheduler,
+    /// `SCHED_FIFO`, set by `chrt` run with `pkexec`.
+    Pkexec,
+}
+
+/// A range of real-time priorities, with the kind of thread it is typically used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PriorityBand {
+    /// Priorities 1 to 3 (and 0, for threads that haven't been promoted).
+    Low,
+    /// Priorities 4 to 8.
+    Medium,
+    /// Priorities 9 to 15.
+    High,
+    /// Priorities 16 to 20.
+    Critical,
+    /// Priorities 21 to 99.
+    Maximum,
+}
+
+impl PriorityBand {
+    /// The band `priority` is in.
+    pub fn from_priority(priority: u32) -> PriorityBand {
+        match priority {
+            0..=3 => PriorityBand::Low,
+            4..=8 => PriorityBand::Medium,
+            9..=15 => PriorityBand::High,
+            16..=20 => PriorityBand::Critical,
+            _ => PriorityBand::Maximum,
+        }
+    }
+
+    /// What the threads in this band are typically used for.
+    pub fn typical_use_case(&self) -> &'static str {
+        match self {
+            PriorityBand::Low => "Background audio work, e.g. decoding or disk streaming",
+            PriorityBand::Medium => "Audio processing with buffering, e.g. mixing or effects",
+            PriorityBand::High => "Real-time audio rendering",
+            PriorityBand::Critical => "Hard-deadline audio I/O callback",
+            PriorityBand::Maximum => "Interrupt handlers and watchdogs",
+        }
+    }
+}
+
+impl std::fmt::Display for PriorityBand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} ({})", self, self.typical_use_case())
+    }
+}
+
+/// A Linux scheduling policy, see `RtPolicy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SchedulingPolicy {
+    /// `SCHED_OTHER`, the default time-sharing policy.
+    Other,
+    /// `SCHED_FIFO`.
+    Fifo,
+    /// `SCHED_RR`, used by RTKit.
+    RoundRobin,
+    /// `SCHED_BATCH`.
+    Batch,
+    /// `SCHED_IDLE`.
+    Idle,
+    /// `SCHED_DEADLINE`.
+    Deadline,
+}
+
+impl SchedulingPolicy {
+    /// The policy for a `sched_getscheduler` value, without `SCHED_RESET_ON_FORK`.
+    fn from_raw(policy: libc::c_int) -> Option<SchedulingPolicy> {
+        match policy {
+            libc::SCHED_OTHER => Some(SchedulingPolicy::Other),
+            libc::SCHED_FIFO => Some(SchedulingPolicy::Fifo),
+            libc::SCHED_RR => Some(SchedulingPolicy::RoundRobin),
+            libc::SCHED_BATCH => Some(SchedulingPolicy::Batch),
+            libc::SCHED_IDLE => Some(SchedulingPolicy::Idle),
+            SCHED_DEADLINE => Some(SchedulingPolicy::Deadline),
+            _ => None,
+        }
+    }
+}
+
+/// Rules real-time threads have to follow, see `RtPriorityHandle::validate_against_policy`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RtPolicy {
+    /// The highest real-time priority allowed.
+    pub max_priority: u32,
+    /// The largest real-time budget allowed, in microseconds.
+    pub max_budget_us: u64,
+    /// The scheduling policies allowed.
+    pub allowed_policies: Vec<SchedulingPolicy>,
+    /// Whether all the memory of the process has to be locked in RAM.
+    pub require_mlockall: bool,
+}
+
+/// A rule of an `RtPolicy` that a thread doesn't follow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyViolation {
+    /// The priority of the thread is above `RtPolicy::max_priority`.
+    PriorityTooHigh {
+        /// The priority of the thread.
+        priority: u32,
+        /// The highest priority allowed.
+        max_priority: u32,
+    },
+    /// The budget of the thread is above `RtPolicy::max_budget_us`.
+    BudgetTooLarge {
+        /// The budget of the thread, in microseconds.
+        budget_us: u64,
+        /// The largest budget allowed, in microseconds.
+        max_budget_us: u64,
+    },
+    /// The scheduling policy of the thread is not in `RtPolicy::allowed_policies`, `None` for
+    /// policies unknown to this library.
+    PolicyNotAllowed(Option<SchedulingPolicy>),
+    /// The memory of the process is not locked, and `RtPolicy::require_mlockall` is set.
+    MemoryNotLocked,
+    /// The scheduling policy and priority of the thread couldn't be queried.
+    SchedulingUnknown,
+}
+
+/// The result of `RtPriorityHandle::validate_against_policy`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationResult {
+    /// Whether the thread follows all the rules of the policy.
+    pub passed: bool,
+    /// The rules the thread doesn't follow.
+    pub violations: Vec<PolicyViolation>,
+}
+
+/// The result of `RtPriorityHandle::dry_run`, or of a promotion with
+/// `RtPriorityRequest::dry_run`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DryRunResult {
+    /// Whether the promotion would have succeeded.
+    pub would_succeed: bool,
+    /// The real-time priority the thread would have had.
+    pub effective_priority: u32,
+    /// The real-time budget the thread would have had, in microseconds, after clamping to the
+    /// maximum allowed by RTKit.
+    pub effective_budget_us: u64,
+    /// What made the promotion fail, or what reduced the priority or budget, if anything.
+    pub limiting_factor: Option<String>,
+}
+
+impl std::fmt::Display for DryRunResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}, priority {}, budget {}us",
+            if self.would_succeed {
+                "would succeed"
+            } else {
+                "would fail"
+            },
+            self.effective_priority,
+            self.effective_budget_us
+        )?;
+        if let Some(factor) = &self.limiting_factor {
+            write!(f, " ({})", factor)?;
+        }
+        Ok(())
+    }
+}
+
+/// The format of the audio buffers processed by a real-time thread, see
+/// `RtPriorityHandle::compute_audio_buffer_budget_us`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AudioFormat {
+    /// The sample-rate, in Hz.
+    pub sample_rate: u32,
+    /// The number of frames in a buffer, or 0 for the default of 50ms.
+    pub buffer_frames: u32,
+    /// The number of channels.
+    pub channels: u16,
+    /// The number of bits per sample.
+    pub bit_depth: u8,
+}
+
+/// A summary of the state of a real-time thread, for monitoring purposes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RtReport {
+    /// The system-wide id of the thread.
+    pub thread_id: i64,
+    /// The PID of the process containing the thread.
+    pub pid: i32,
+    /// The name of the thread.
+    pub label: String,
+    /// The real-time priority requested at promotion time.
+    pub effective_priority: u32,
+    /// The soft real-time budget set at promotion time, in microseconds.
+    pub effective_budget_us: u64,
+    /// The time elapsed since the thread was promoted, if known.
+    pub time_since_promotion: Option<Duration>,
+    /// Whether the thread is currently scheduled with a real-time policy.
+    pub is_still_realtime: bool,
+    /// The number of times the thread has been preempted.
+    pub involuntary_context_switches: u64,
+    /// The number of major page faults of the thread since it was promoted.
+    pub major_faults: u64,
+    /// The 99th percentile of the scheduling jitter of the thread, in microseconds, if measured.
+    pub jitter_p99_us: Option<u64>,
+}
+
+impl RtReport {
+    /// Format this report as a single-line JSON object, e.g. to append it to a log file.
+    pub fn to_json_line(&self) -> String {
+        fn optional<T: std::fmt::Display>(value: Option<T>) -> String {
+            value.map_or_else(|| "null".to_string(), |v| v.to_string())
+        }
+        format!(
+            "{{\"thread_id\":{},\"pid\":{},\"label\":\"{}\",\"effective_priority\":{},\
+             \"priority_band\":\"{}\",\"effective_budget_us\":{},\"time_since_promotion_us\":{},\
+             \"is_still_realtime\":{},\
+             \"involuntary_context_switches\":{},\"major_faults\":{},\"jitter_p99_us\":{}}}",
+            self.thread_id,
+            self.pid,
+            json_escape(&self.label),
+            self.effective_priority,
+            json_escape(&PriorityBand::from_priority(self.effective_priority).to_string()),
+            self.effective_budget_us,
+            optional(self.time_since_promotion.map(|d| d.as_micros())),
+            self.is_still_realtime,
+            self.involuntary_context_switches,
+            self.major_faults,
+            optional(self.jitter_p99_us),
+        )
+    }
+}
+
+/// Send `metrics` (e.g. `rt_promotions:1|c`), prefixed with `prefix.`, to the StatsD server at
+/// `addr`, in a single datagram. Errors are only logged, like for the audit log.
+#[cfg(feature = "statsd")]
+fn statsd_send(addr: &SocketAddr, prefix: &str, metrics: &[String]) {
+    static SOCKET_V4: OnceLock<Option<UdpSocket>> = OnceLock::new();
+    static SOCKET_V6: OnceLock<Option<UdpSocket>> = OnceLock::new();
+    let (socket, bind_addr) = match addr {
+        SocketAddr::V4(_) => (&SOCKET_V4, "0.0.0.0:0"),
+        SocketAddr::V6(_) => (&SOCKET_V6, "[::]:0"),
+    };
+    let socket = socket.get_or_init(|| match UdpSocket::bind(bind_addr) {
+        Ok(socket) => Some(socket),
+        Err(e) => {
+            warn!("Could not create the StatsD socket: {}", e);
+            None
+        }
+    });
+    let socket = match socket {
+        Some(socket) => socket,
+        None => return,
+    };
+    let datagram = metrics
+        .iter()
+        .map(|metric| format!("{}.{}", prefix, metric))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if let Err(e) = socket.send_to(datagram.as_bytes(), addr) {
+        warn!("Could not send metrics to StatsD server {}: {}", addr, e);
+    }
+}
+
+/// Append a line describing a promotion or demotion to the audit log at `path`. Errors are only
+/// logged, auditing shouldn't get in the way of the audio.
+#[cfg(feature = "audit")]
+fn audit_log(path: &Path, action: &str, handle: &RtPriorityHandleInternal) {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let line = format!(
+        "{{\"timestamp\":{}.{:06},\"action\":\"{}\",\"pid\":{},\"tid\":{},\"priority\":{},\
+         \"budget_us\":{},\"user\":{}}}\n",
+        timestamp.as_secs(),
+        timestamp.subsec_micros(),
+        json_escape(action),
+        handle.thread_info.pid,
+        handle.thread_info.thread_id,
+        handle.priority,
+        handle.budget_us,
+        unsafe { libc::getuid() },
+    );
+    // A single write, so that lines from multiple processes don't get interleaved.
+    let r = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(e) = r {
+        warn!("Could not write to audit log {}: {}", path.display(), e);
+    }
+}
+
+/// The handles of the threads promoted and not yet demoted, exported as JSON by
+/// `export_for_gdb_pretty_printer` at promotion time, by thread id.
+#[cfg(feature = "gdb-support")]
+static GDB_HANDLES: Mutex<Vec<(kernel_pid_t, String)>> = Mutex::new(Vec::new());
+
+/// Called by debuggers to list the threads promoted by this library and not yet demoted, e.g.
+/// `call (char*)atp_gdb_handles()` in GDB. This returns a JSON array of the handles as exported
+/// by `RtPriorityHandle::export_for_gdb_pretty_printer` at promotion time, valid until the next
+/// call.
+#[cfg(feature = "gdb-support")]
+#[no_mangle]
+pub extern "C" fn atp_gdb_handles() -> *const libc::c_char {
+    static OUTPUT: Mutex<Option<std::ffi::CString>> = Mutex::new(None);
+    let handles: Vec<String> = GDB_HANDLES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|(_, json)| json.clone())
+        .collect();
+    let json = std::ffi::CString::new(format!("[{}]", handles.join(",")))
+        .expect("escaped JSON doesn't contain NUL bytes");
+    let mut output = OUTPUT.lock().unwrap_or_else(|e| e.into_inner());
+    output.insert(json).as_ptr()
+}
+
+/// Add or remove the thread of `handle` to the handles listed by `atp_gdb_handles`.
+#[cfg(feature = "gdb-support")]
+fn gdb_register_handle(handle: &RtPriorityHandleInternal, promoted: bool) {
+    let tid = handle.thread_info.thread_id;
+    let mut handles = GDB_HANDLES.lock().unwrap_or_else(|e| e.into_inner());
+    handles.retain(|(t, _)| *t != tid);
+    if promoted {
+        handles.push((tid, handle.export_for_gdb_pretty_printer()));
+    }
+}
+
+/// Escape `s` to be used in a JSON string.
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A set of CPUs, see `RtPriorityHandle::get_effective_cpu_set`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuSet {
+    /// Sorted CPU numbers.
+    cpus: Vec<usize>,
+}
+
+impl CpuSet {
+    /// The CPUs in this set, in increasing order.
+    pub fn cpus(&self) -> &[usize] {
+        &self.cpus
+    }
+
+    /// Whether `cpu` is in this set.
+    pub fn contains(&self, cpu: usize) -> bool {
+        self.cpus.binary_search(&cpu).is_ok()
+    }
+
+    /// The number of CPUs in this set.
+    pub fn len(&self) -> usize {
+        self.cpus.len()
+    }
+
+    /// Whether this set has no CPU.
+    pub fn is_empty(&self) -> bool {
+        self.cpus.is_empty()
+    }
+}
+
+/// The CPU affinity applied to a real-time thread.
+#[cfg(feature = "numa")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuAffinityHandle {
+    cpus: Vec<usize>,
+}
+
+#[cfg(feature = "numa")]
+impl CpuAffinityHandle {
+    /// The CPUs the thread is allowed to run on.
+    pub fn cpus(&self) -> &[usize] {
+        &self.cpus
+    }
+}
+
+/// A CPU wake-up latency requested with `RtPriorityHandle::with_cpu_dma_latency_hint`. The
+/// request is removed when this is dropped.
+#[derive(Debug)]
+pub struct CpuDmaLatencyGuard {
+    /// The kernel keeps the request for as long as the file is open.
+    _file: fs::File,
+}
+
+/// The tuning applied by `RtPriorityHandle::tune_for_audio_workload`. The tuning is undone on
+/// drop, and the thread is demoted. This can be dropped on any thread.
+pub struct RtTuningGuard {
+    /// The tuned thread.
+    tid: kernel_pid_t,
+    handle: Option<RtPriorityHandleInternal>,
+    cpu_dma_latency: Option<CpuDmaLatencyGuard>,
+    /// Whether the memory of the process was locked by `tune_for_audio_workload`, and wasn't
+    /// locked before.
+    memory_locked: bool,
+    original_affinity: Option<Vec<usize>>,
+    original_timer_slack: libc::c_int,
+}
+
+impl RtTuningGuard {
+    /// The handle of the thread promoted to real-time.
+    pub fn handle(&self) -> &RtPriorityHandleInternal {
+        self.handle
+            .as_ref()
+            .expect("the handle is only taken when dropping")
+    }
+}
+
+impl Drop for RtTuningGuard {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let tid = handle.thread_info.thread_id;
+            if let Err(e) = demote_with_force_internal(handle) {
+                warn!("Could not demote thread {}: {}", tid, e);
+            }
+        }
+        if self.original_timer_slack > 0 {
+            if let Err(e) = set_timer_slack(self.tid, self.original_timer_slack as u64) {
+                warn!("Could not restore the timer slack: {}", e);
+            }
+        }
+        if let Some(affinity) = &self.original_affinity {
+            if let Err(e) = set_thread_affinity(self.tid, affinity) {
+                warn!("Could not restore the CPU affinity: {}", e);
+            }
+        }
+        self.cpu_dma_latency = None;
+        if self.memory_locked {
+            unsafe { libc::munlockall() };
+        }
+    }
+}
+
+/// Interrupt coalescing settings changed by `RtPriorityHandle::set_interrupt_coalescing`. The
+/// original settings are restored on drop.
+#[cfg(feature = "netaudio")]
+#[derive(Debug)]
+pub struct CoalescingHandle {
+    device: String,
+    original: EthtoolCoalesce,
+}
+
+#[cfg(feature = "netaudio")]
+impl Drop for CoalescingHandle {
+    fn drop(&mut self) {
+        let mut original = self.original;
+        if let Err(e) = ethtool_coalesce(&self.device, ETHTOOL_SCOALESCE, &mut original) {
+            warn!(
+                "Could not restore the interrupt coalescing of {}: {}",
+                self.device, e
+            );
+        }
+    }
+}
+
+/// CPU wake-up latency limits set by `RtPriorityHandle::request_governor_latency_netlink`. The
+/// original settings are restored on drop.
+#[derive(Debug)]
+pub struct PmQosHandle {
+    /// The sysfs files that have been changed, and their original value.
+    original_settings: Vec<(PathBuf, String)>,
+}
+
+impl Drop for PmQosHandle {
+    fn drop(&mut self) {
+        for (path, original) in &self.original_settings {
+            if let Err(e) = fs::write(path, original) {
+                warn!("Could not restore {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// CPU frequency settings changed by `RtPriorityHandle::request_cpu_frequency_boost`. The
+/// original settings are restored on drop.
+#[derive(Debug)]
+pub struct CpuFreqBoostHandle {
+    /// The sysfs files that have been changed, and their original value.
+    original_settings: Vec<(PathBuf, String)>,
+}
+
+impl CpuFreqBoostHandle {
+    /// Write `performance` to the sysfs file at `path`, remembering its original value.
+    fn boost_setting(&mut self, path: PathBuf) -> Result<(), AudioThreadPriorityError> {
+        let error = |e| {
+            AudioThreadPriorityError::new_with_inner(&format!("{}", path.display()), Box::new(e))
+        };
+        let original = fs::read_to_string(&path).map_err(error)?;
+        let original = original.trim_end();
+        if original != "performance" {
+            fs::write(&path, "performance").map_err(error)?;
+            self.original_settings
+                .push((path.clone(), original.to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CpuFreqBoostHandle {
+    fn drop(&mut self) {
+        // Restore in reverse order, the energy/performance preference of a CPU can't be changed
+        // while its governor is performance.
+        for (path, original) in self.original_settings.iter().rev() {
+            if let Err(e) = fs::write(path, original) {
+                warn!("Could not restore {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// A lock-free bump allocator backed by memory locked in RAM, see
+/// `RtPriorityHandle::realtime_capable_memory_allocator`. The memory is unmapped on drop.
+#[derive(Debug)]
+pub struct RtAllocator {
+    base: *mut u8,
+    capacity: usize,
+    /// The offset of the first free byte from `base`.
+    offset: AtomicUsize,
+}
+
+// The memory is only handed out through `offset`, that is atomic.
+unsafe impl Send for RtAllocator {}
+unsafe impl Sync for RtAllocator {}
+
+impl RtAllocator {
+    fn new(capacity: usize) -> Result<RtAllocator, AudioThreadPriorityError> {
+        if capacity == 0 {
+            return Err(AudioThreadPriorityError::new("empty allocator"));
+        }
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                capacity,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "mmap",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        // This also faults all the pages in.
+        if unsafe { libc::mlock(base, capacity) } < 0 {
+            let error = OSError::last_os_error();
+            unsafe { libc::munmap(base, capacity) };
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "mlock",
+                Box::new(error),
+            ));
+        }
+        Ok(RtAllocator {
+            base: base as *mut u8,
+            capacity,
+            offset: AtomicUsize::new(0),
+        })
+    }
+
+    /// Allocate memory for `layout`, or return `None` if there isn't enough space left. This
+    /// never blocks, and can be called from multiple threads.
+    pub fn alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let base = self.base as usize;
+        let mut offset = self.offset.load(Ordering::Relaxed);
+        loop {
+            let start = (base + offset).checked_next_multiple_of(layout.align())? - base;
+            let end = start.checked_add(layout.size())?;
+            if end > self.capacity {
+                return None;
+            }
+            match self.offset.compare_exchange_weak(
+                offset,
+                end,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return NonNull::new(unsafe { self.base.add(start) }),
+                Err(current) => offset = current,
+            }
+        }
+    }
+
+    /// Release memory allocated with `alloc`. This does nothing: the memory is only reclaimed by
+    /// `reset`.
+    pub fn dealloc(&self, _ptr: NonNull<u8>, _layout: Layout) {}
+
+    /// Make all the memory available again. The memory previously returned by `alloc` must not
+    /// be used anymore.
+    pub fn reset(&mut self) {
+        *self.offset.get_mut() = 0;
+    }
+
+    /// The number of bytes allocated since the last reset, including alignment padding.
+    pub fn used(&self) -> usize {
+        self.offset.load(Ordering::Relaxed)
+    }
+
+    /// The total number of bytes that can be allocated.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl Drop for RtAllocator {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.capacity) };
+    }
+}
+
+/// A description of a `RtPriorityHandle` stored in atomics, so that a thread (e.g. a monitoring
+/// thread) can take consistent snapshots of it while another thread (e.g. the audio thread)
+/// updates it, without a lock.
+///
+/// There must be a single thread updating it at a time. Readers retry if an update happens while
+/// they read, so they can't starve the writer.
+#[derive(Debug)]
+pub struct AtomicRtPriorityHandle {
+    /// Odd while an update is in progress.
+    sequence: AtomicU32,
+    pid: AtomicI32,
+    thread_id: AtomicI64,
+    policy: AtomicI32,
+    priority: AtomicI32,
+    budget_us: AtomicU64,
+}
+
+impl AtomicRtPriorityHandle {
+    /// Create an atomic description of `handle`.
+    pub fn new(handle: &RtPriorityHandleInternal) -> AtomicRtPriorityHandle {
+        let raw = handle.as_raw_handle();
+        AtomicRtPriorityHandle {
+            sequence: AtomicU32::new(0),
+            pid: AtomicI32::new(raw.pid),
+            thread_id: AtomicI64::new(raw.thread_id),
+            policy: AtomicI32::new(raw.policy),
+            priority: AtomicI32::new(raw.priority),
+            budget_us: AtomicU64::new(raw.budget_us),
+        }
+    }
+
+    /// Replace the description by the one of `handle`.
+    pub fn store(&self, handle: &RtPriorityHandleInternal) {
+        let raw = handle.as_raw_handle();
+        self.update(|this| {
+            this.pid.store(raw.pid, Ordering::Relaxed);
+            this.thread_id.store(raw.thread_id, Ordering::Relaxed);
+            this.policy.store(raw.policy, Ordering::Relaxed);
+            this.priority.store(raw.priority, Ordering::Relaxed);
+            this.budget_us.store(raw.budget_us, Ordering::Relaxed);
+        });
+    }
+
+    /// Update the real-time priority and budget.
+    pub fn set_priority_and_budget(&self, priority: u32, budget_us: u64) {
+        self.update(|this| {
+            this.priority.store(priority as i32, Ordering::Relaxed);
+            this.budget_us.store(budget_us, Ordering::Relaxed);
+        });
+    }
+
+    fn update<F: FnOnce(&AtomicRtPriorityHandle)>(&self, f: F) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence
+            .store(sequence.wrapping_add(1), Ordering::Relaxed);
+        std::sync::atomic::fence(Ordering::Release);
+        f(self);
+        self.sequence
+            .store(sequence.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Get a consistent copy of the description.
+    pub fn snapshot(&self) -> RawRtHandle {
+        loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            if sequence % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let raw = RawRtHandle {
+                pid: self.pid.load(Ordering::Relaxed),
+                thread_id: self.thread_id.load(Ordering::Relaxed),
+                policy: self.policy.load(Ordering::Relaxed),
+                priority: self.priority.load(Ordering::Relaxed),
+                budget_us: self.budget_us.load(Ordering::Relaxed),
+            };
+            std::sync::atomic::fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == sequence {
+                return raw;
+            }
+        }
+    }
+
+    /// Get a consistent copy of the description, in the format of
+    /// `RtPriorityHandle::serialize_atomic_snapshot`.
+    pub fn serialize_atomic_snapshot(&self) -> [u8; MMAP_HANDLE_SIZE] {
+        raw_handle_to_bytes(&self.snapshot())
+    }
+}
+
+/// Memory bandwidth limits lifted by `RtPriorityHandle::request_memory_bandwidth_qos`. The
+/// original limits are restored on drop.
+#[cfg(feature = "rdt")]
+#[derive(Debug)]
+pub struct MemoryBandwidthHandle {
+    /// The `schemata` file of the resctrl group.
+    schemata: PathBuf,
+    /// The original `MB:` line of the schemata.
+    original: String,
+}
+
+#[cfg(feature = "rdt")]
+impl Drop for MemoryBandwidthHandle {
+    fn drop(&mut self) {
+        if let Err(e) = fs::write(&self.schemata, format!("{}\n", self.original)) {
+            warn!("Could not restore {}: {}", self.schemata.display(), e);
+        }
+    }
+}
+
+/// Frequency boost settings changed by `RtPriorityHandle::set_turbo_boost_hint`. The original
+/// settings are restored on drop.
+#[cfg(feature = "cpu-tuning")]
+#[derive(Debug)]
+pub struct TurboBoostHandle {
+    /// The sysfs files that have been changed, and their original value.
+    original_settings: Vec<(PathBuf, String)>,
+}
+
+#[cfg(feature = "cpu-tuning")]
+impl TurboBoostHandle {
+    /// Write `value` to the sysfs file at `path`, remembering its original value.
+    fn set(&mut self, path: PathBuf, value: &str) -> Result<(), AudioThreadPriorityError> {
+        let error = |e| {
+            AudioThreadPriorityError::new_with_inner(&format!("{}", path.display()), Box::new(e))
+        };
+        let original = fs::read_to_string(&path).map_err(error)?;
+        let original = original.trim_end();
+        if original != value {
+            fs::write(&path, value).map_err(error)?;
+            self.original_settings
+                .push((path.clone(), original.to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "cpu-tuning")]
+impl Drop for TurboBoostHandle {
+    fn drop(&mut self) {
+        for (path, original) in &self.original_settings {
+            if let Err(e) = fs::write(path, original) {
+                warn!("Could not restore {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// A CPU reserved by `RtPriorityHandle::request_exclusive_core`. The CPU is released on drop.
+#[derive(Debug)]
+pub struct ExclusiveCoreHandle {
+    tid: kernel_pid_t,
+    cpu: usize,
+    /// The cpuset created for the thread.
+    cpuset: PathBuf,
+    /// The cpuset the thread was in before.
+    original_cpuset: PathBuf,
+}
+
+impl ExclusiveCoreHandle {
+    /// The CPU reserved for the thread.
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+}
+
+impl Drop for ExclusiveCoreHandle {
+    fn drop(&mut self) {
+        // Moving the thread out can fail if it has exited, the cpuset can be removed anyway.
+        if let Err(e) = fs::write(self.original_cpuset.join("tasks"), self.tid.to_string()) {
+            warn!(
+                "Could not move thread {} out of its cpuset: {}",
+                self.tid, e
+            );
+        }
+        if let Err(e) = fs::remove_dir(&self.cpuset) {
+            warn!("Could not remove {}: {}", self.cpuset.display(), e);
+        }
+    }
+}
+
+#[cfg(feature = "fpe-trap")]
+extern "C" {
+    // glibc extensions, not exposed by libc.
+    fn feenableexcept(excepts: libc::c_int) -> libc::c_int;
+    fn fedisableexcept(excepts: libc::c_int) -> libc::c_int;
+}
+
+#[cfg(all(feature = "fpe-trap", any(target_arch = "x86", target_arch = "x86_64")))]
+const FPE_TRAPPED_EXCEPTIONS: libc::c_int = 0x01 /* FE_INVALID */ | 0x04 /* FE_DIVBYZERO */;
+#[cfg(all(
+    feature = "fpe-trap",
+    any(target_arch = "arm", target_arch = "aarch64")
+))]
+const FPE_TRAPPED_EXCEPTIONS: libc::c_int = 0x01 /* FE_INVALID */ | 0x02 /* FE_DIVBYZERO */;
+
+/// A floating-point exception trap installed by `RtPriorityHandle::install_fpe_trap`, removed on
+/// drop. This has to be dropped on the thread it was installed on.
+#[cfg(feature = "fpe-trap")]
+pub struct FpeTrapGuard {
+    /// The floating-point exceptions that were already trapped.
+    previous_excepts: libc::c_int,
+    /// The `SIGFPE` handler that was installed.
+    previous_action: libc::sigaction,
+}
+
+#[cfg(feature = "fpe-trap")]
+impl FpeTrapGuard {
+    fn install() -> Result<FpeTrapGuard, AudioThreadPriorityError> {
+        let mut action = unsafe { std::mem::zeroed::<libc::sigaction>() };
+        action.sa_sigaction = fpe_trap_handler as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO;
+        let mut previous_action = unsafe { std::mem::zeroed::<libc::sigaction>() };
+        if unsafe { libc::sigaction(libc::SIGFPE, &action, &mut previous_action) } < 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "sigaction",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        // Not all CPUs support trapping floating-point exceptions.
+        let previous_excepts = unsafe { feenableexcept(FPE_TRAPPED_EXCEPTIONS) };
+        if previous_excepts < 0 {
+            unsafe { libc::sigaction(libc::SIGFPE, &previous_action, std::ptr::null_mut()) };
+            return Err(AudioThreadPriorityError::new(
+                "floating-point exception traps are not supported",
+            ));
+        }
+        Ok(FpeTrapGuard {
+            previous_excepts,
+            previous_action,
+        })
+    }
+}
+
+#[cfg(feature = "fpe-trap")]
+impl Drop for FpeTrapGuard {
+    fn drop(&mut self) {
+        unsafe {
+            fedisableexcept(FPE_TRAPPED_EXCEPTIONS & !self.previous_excepts);
+            libc::sigaction(libc::SIGFPE, &self.previous_action, std::ptr::null_mut());
+        }
+    }
+}
+
+/// `SIGFPE` handler installed by `FpeTrapGuard`. This only calls async-signal-safe functions.
+#[cfg(feature = "fpe-trap")]
+extern "C" fn fpe_trap_handler(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    const MESSAGE: &[u8] = b"audio_thread_priority: floating-point exception, demoting thread\n";
+    unsafe {
+        let param = std::mem::zeroed::<libc::sched_param>();
+        libc::sched_setscheduler(0, libc::SCHED_OTHER, &param);
+        libc::write(
+            libc::STDERR_FILENO,
+            MESSAGE.as_ptr() as *const libc::c_void,
+            MESSAGE.len(),
+        );
+        // Returning re-executes the faulting instruction, which then gets the default action.
+        libc::signal(libc::SIGFPE, libc::SIG_DFL);
+    }
+}
+
+/// The result of `RtPriorityHandle::fork`, in the parent and in the child process.
+// This is only moved once, out of fork.
+#[allow(clippy::large_enum_variant)]
+pub enum RtForkResult {
+    /// In the parent process: the handle `fork` was called on, and a handle to the promoted
+    /// thread of the child process.
+    Parent {
+        /// The handle `fork` was called on.
+        parent: RtPriorityHandleInternal,
+        /// The promoted thread of the child process.
+        child: RtPriorityHandleInternal,
+    },
+    /// In the child process: a handle to its promoted thread.
+    Child(RtPriorityHandleInternal),
+}
+
+/// A thread registered with the watchdog, see
+/// `RtPriorityHandle::set_watchdog_heartbeat_interval`.
+#[derive(Debug)]
+struct WatchdogEntry {
+    thread_id: kernel_pid_t,
+    interval: Duration,
+    /// The time of the last heartbeat, see `watchdog_now_ns`.
+    last_heartbeat_ns: AtomicU64,
+    /// Whether a missed heartbeat has been reported since the last heartbeat.
+    reported: AtomicBool,
+}
+
+/// The time elapsed since the watchdog was first used, in nanoseconds.
+fn watchdog_now_ns() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
+
+/// Checks that the threads registered with it call `heartbeat` often enough, from a thread
+/// started the first time a thread is registered. The thread waits on `registered` while no
+/// thread is registered.
+struct RtWatchdog {
+    entries: Mutex<Vec<Weak<WatchdogEntry>>>,
+    registered: Condvar,
+}
+
+impl RtWatchdog {
+    /// How often the watchdog checks the heartbeats.
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    fn register(entry: &Arc<WatchdogEntry>) {
+        static WATCHDOG: OnceLock<RtWatchdog> = OnceLock::new();
+        let watchdog = WATCHDOG.get_or_init(|| {
+            std::thread::Builder::new()
+                .name("RtWatchdog".to_string())
+                .spawn(|| WATCHDOG.wait().run())
+                .expect("could not start the watchdog thread");
+            RtWatchdog {
+                entries: Mutex::new(Vec::new()),
+                registered: Condvar::new(),
+            }
+        });
+        watchdog
+            .entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Arc::downgrade(entry));
+        watchdog.registered.notify_one();
+    }
+
+    fn run(&self) {
+        loop {
+            // Don't poll while no thread is registered.
+            {
+                let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+                let _entries = self
+                    .registered
+                    .wait_while(entries, |entries| entries.is_empty())
+                    .unwrap_or_else(|e| e.into_inner());
+            }
+            std::thread::sleep(RtWatchdog::POLL_INTERVAL);
+            let now = watchdog_now_ns();
+            let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            entries.retain(|entry| {
+                let entry = match entry.upgrade() {
+                    Some(entry) => entry,
+                    None => return false,
+                };
+                let since_heartbeat =
+                    now.saturating_sub(entry.last_heartbeat_ns.load(Ordering::Relaxed));
+                if since_heartbeat > entry.interval.as_nanos() as u64
+                    && !entry.reported.swap(true, Ordering::Relaxed)
+                {
+                    warn!(
+                        "Thread {} missed its heartbeat: none for {}us (interval {}us).",
+                        entry.thread_id,
+                        since_heartbeat / 1000,
+                        entry.interval.as_micros()
+                    );
+                }
+                true
+            });
+        }
+    }
+}
+
+/// The background thread started by `RtPriorityHandle::freeze_thread_priority`. It is stopped
+/// on drop.
+#[derive(Debug)]
+pub struct FreezeHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl FreezeHandle {
+    /// Stop the background thread, and wait for it to exit.
+    pub fn stop(self) {
+        // This is done on drop.
+    }
+}
+
+impl Drop for FreezeHandle {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// D-Bus round-trip times, see `RtPriorityHandle::benchmark_dbus_round_trip`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DbusLatencyStats {
+    /// The shortest round-trip time, in microseconds.
+    pub min_us: u64,
+    /// The longest round-trip time, in microseconds.
+    pub max_us: u64,
+    /// The mean round-trip time, in microseconds.
+    pub mean_us: u64,
+    /// The 99th percentile of the round-trip times, in microseconds.
+    pub p99_us: u64,
+}
+
+/// Intervals between the interrupts of an audio device, see
+/// `RtPriorityHandle::measure_dma_transfer_jitter`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JitterStats {
+    /// The shortest interval, in microseconds.
+    pub min_us: u64,
+    /// The longest interval, in microseconds.
+    pub max_us: u64,
+    /// The 99th percentile of the intervals, in microseconds.
+    pub p99_us: u64,
+    /// The standard deviation of the intervals, in microseconds.
+    pub stddev_us: f64,
+}
+
+/// A preemption model of the Linux kernel, see `RtPriorityHandle::get_kernel_preemption_model`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KernelPreemptionModel {
+    /// No preemption of kernel code (`PREEMPT_NONE`).
+    None,
+    /// Kernel code can be preempted at explicit preemption points (`PREEMPT_VOLUNTARY`).
+    Voluntary,
+    /// Kernel code can be preempted outside of critical sections (`PREEMPT`).
+    Full,
+    /// Almost all kernel code can be preempted, including interrupt handlers (`PREEMPT_RT`).
+    Realtime,
+}
+
+impl KernelPreemptionModel {
+    /// A typical range of the time between a real-time thread becoming runnable and running, on
+    /// a loaded system, in microseconds. The actual latency depends a lot on the hardware and
+    /// the drivers, this is only an order of magnitude.
+    pub fn estimated_preemption_latency_us(&self) -> RangeInclusive<u64> {
+        match self {
+            KernelPreemptionModel::None => 1_000..=100_000,
+            KernelPreemptionModel::Voluntary => 100..=10_000,
+            KernelPreemptionModel::Full => 50..=1_000,
+            KernelPreemptionModel::Realtime => 5..=100,
+        }
+    }
+}
+
+/// The CPU, I/O and memory settings applied by `RtPriorityHandle::set_thread_qos`.
+#[derive(Clone, Debug)]
+pub struct QosClass {
+    /// The CPU scheduling of the thread.
+    pub cpu: RtQos,
+    /// The I/O priority of the thread.
+    pub io: IoQos,
+    /// How the memory used by the thread is handled.
+    pub mem: MemQos,
+}
+
+/// The CPU scheduling part of a `QosClass`.
+#[derive(Clone, Debug)]
+pub enum RtQos {
+    /// Keep the current scheduling of the thread.
+    Unchanged,
+    /// Promote the thread to real-time with this request.
+    RealTime(Box<RtPriorityRequest>),
+}
+
+/// The I/O scheduling part of a `QosClass`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoQos {
+    /// Keep the current I/O priority of the thread.
+    Unchanged,
+    /// Set the I/O scheduling class and level (0 to 7) of the thread.
+    Priority(IoSchedulingClass, u8),
+}
+
+/// The memory part of a `QosClass`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemQos {
+    /// Don't change how the memory is handled.
+    Unchanged,
+    /// Lock the memory of the process in RAM, and don't use transparent huge pages for the stack
+    /// of the thread.
+    Locked,
+}
+
+/// The settings applied by `RtPriorityHandle::set_thread_qos`. The thread is demoted, its I/O
+/// priority restored, and the memory unlocked on drop.
+pub struct QosHandle {
+    handle: Option<RtPriorityHandleInternal>,
+    io_priority: Option<IoPriorityHandle>,
+    /// Whether memory wasn't locked before `set_thread_qos` locked it, and is unlocked on drop.
+    memory_locked: bool,
+}
+
+impl QosHandle {
+    /// The handle of the thread, if it was promoted to real-time.
+    pub fn handle(&self) -> Option<&RtPriorityHandleInternal> {
+        self.handle.as_ref()
+    }
+}
+
+impl Drop for QosHandle {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let tid = handle.thread_info.thread_id;
+            if let Err(e) = demote_with_force_internal(handle) {
+                warn!("Could not demote thread {}: {}", tid, e);
+            }
+        }
+        self.io_priority = None;
+        if self.memory_locked {
+            unsafe { libc::munlockall() };
+        }
+    }
+}
+
+/// An I/O scheduling class, see `RtPriorityHandle::set_io_priority`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoSchedulingClass {
+    /// Always served first (`IOPRIO_CLASS_RT`).
+    RealTime,
+    /// The default class (`IOPRIO_CLASS_BE`).
+    BestEffort,
+    /// Only served when no other thread does I/O (`IOPRIO_CLASS_IDLE`).
+    Idle,
+}
+
+/// An I/O priority set by `RtPriorityHandle::set_io_priority`. The original I/O priority is
+/// restored on drop.
+#[derive(Debug)]
+pub struct IoPriorityHandle {
+    tid: kernel_pid_t,
+    original: libc::c_long,
+}
+
+impl Drop for IoPriorityHandle {
+    fn drop(&mut self) {
+        if let Err(e) = set_io_priority(self.tid, self.original) {
+            warn!(
+                "Could not restore the I/O priority of thread {}: {}",
+                self.tid, e
+            );
+        }
+    }
+}
+
+/// The traffic class and priority of a socket changed by
+/// `RtPriorityHandle::request_low_latency_network_qos`. The original values are restored on drop.
+#[derive(Debug)]
+pub struct NetworkQosHandle {
+    fd: RawFd,
+    traffic_class_option: (libc::c_int, libc::c_int, &'static str),
+    original_traffic_class: i32,
+    original_priority: i32,
+}
+
+impl Drop for NetworkQosHandle {
+    fn drop(&mut self) {
+        let (level, option, name) = self.traffic_class_option;
+        let r = set_socket_option(self.fd, level, option, name, self.original_traffic_class)
+            .and_then(|_| set_socket_priority(self.fd, self.original_priority));
+        if let Err(e) = r {
+            warn!("Could not restore the QoS of socket {}: {}", self.fd, e);
+        }
+    }
+}
+
+/// An OOM killer score adjustment set by `RtPriorityHandle::set_oom_score_adj`. The original
+/// score is restored on drop.
+#[derive(Debug)]
+pub struct OomScoreHandle {
+    path: PathBuf,
+    original: String,
+}
+
+impl Drop for OomScoreHandle {
+    fn drop(&mut self) {
+        if let Err(e) = fs::write(&self.path, &self.original) {
+            warn!("Could not restore {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// A POSIX unnamed semaphore, to signal a non-real-time thread from a real-time thread without
+/// allocating or blocking, see `RtPriorityHandle::with_attached_semaphore`. `sem_post` is also
+/// async-signal-safe.
+pub struct Semaphore {
+    // sem_t can't be moved once initialized.
+    sem: Box<UnsafeCell<libc::sem_t>>,
+}
+
+// POSIX semaphores are made to be used concurrently by multiple threads.
+unsafe impl Send for Semaphore {}
+unsafe impl Sync for Semaphore {}
+
+impl Semaphore {
+    /// Create a semaphore with an initial value of `value`, private to this process.
+    pub fn new(value: u32) -> Result<Semaphore, AudioThreadPriorityError> {
+        let sem = Box::new(UnsafeCell::new(unsafe {
+            std::mem::zeroed::<libc::sem_t>()
+        }));
+        if unsafe { libc::sem_init(sem.get(), 0, value) } < 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "sem_init",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        Ok(Semaphore { sem })
+    }
+
+    /// Increment the semaphore, waking up a thread blocked in `wait`. Returns 0 on success, or an
+    /// error number.
+    pub fn post(&self) -> libc::c_int {
+        if unsafe { libc::sem_post(self.sem.get()) } < 0 {
+            return OSError::last_os_error()
+                .raw_os_error()
+                .unwrap_or(libc::EINVAL);
+        }
+        0
+    }
+
+    /// Block until the semaphore can be decremented, and decrement it. Interruptions by signals
+    /// are retried. Returns 0 on success, or an error number.
+    pub fn wait(&self) -> i32 {
+        loop {
+            if unsafe { libc::sem_wait(self.sem.get()) } == 0 {
+                return 0;
+            }
+            let errno = OSError::last_os_error()
+                .raw_os_error()
+                .unwrap_or(libc::EINVAL);
+            if errno != libc::EINTR {
+                return errno;
+            }
+        }
+    }
+}
+
+impl Drop for Semaphore {
+    fn drop(&mut self) {
+        unsafe { libc::sem_destroy(self.sem.get()) };
+    }
+}
+
+impl std::fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Semaphore").finish_non_exhaustive()
+    }
+}
+
+/// A thread demoted by `RtPriorityHandle::suspend_for_device_change`, waiting to be promoted
+/// again.
+#[derive(Debug)]
+pub struct SuspendedRtHandle {
+    /// The request the handle that was suspended was promoted with.
+    previous_request: RtPriorityRequest,
+}
+
+impl SuspendedRtHandle {
+    /// Promote the calling thread, that has to be the thread that was suspended, with
+    /// `new_request`, e.g. with the buffer size of the new audio device. If that fails, the
+    /// thread is promoted with the request it was promoted with before being suspended, and a
+    /// warning is logged. The error of the promotion with `new_request` is returned if both fail.
+    pub fn resume(
+        self,
+        new_request: &RtPriorityRequest,
+    ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+        promote_current_thread_to_real_time_internal(new_request).or_else(|e| {
+            warn!(
+                "Could not promote with the new parameters, using the previous ones: {}",
+                e
+            );
+            promote_current_thread_to_real_time_internal(&self.previous_request).map_err(|_| e)
+        })
+    }
+}
+
+/// A periodic timer created by `RtPriorityHandle::request_dedicated_timer_interrupt`. The timer is
+/// disarmed and closed on drop.
+#[derive(Debug)]
+pub struct TimerHandle {
+    fd: RawFd,
+}
+
+impl TimerHandle {
+    /// Block until the timer expires, and return the number of expirations since the last call,
+    /// which is more than 1 if ticks were missed.
+    pub fn wait_for_tick(&self) -> Result<u64, AudioThreadPriorityError> {
+        loop {
+            let mut expirations = 0u64;
+            let r = unsafe {
+                libc::read(
+                    self.fd,
+                    &mut expirations as *mut u64 as *mut libc::c_void,
+                    std::mem::size_of::<u64>(),
+                )
+            };
+            if r == std::mem::size_of::<u64>() as isize {
+                return Ok(expirations);
+            }
+            let e = OSError::last_os_error();
+            match e.kind() {
+                std::io::ErrorKind::Interrupted => continue,
+                // The timerfd is non-blocking, so that it can be used with `poll` and friends.
+                std::io::ErrorKind::WouldBlock => {
+                    let mut pollfd = libc::pollfd {
+                        fd: self.fd,
+                        events: libc::POLLIN,
+                        revents: 0,
+                    };
+                    if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+                        let e = OSError::last_os_error();
+                        if e.kind() != std::io::ErrorKind::Interrupted {
+                            return Err(AudioThreadPriorityError::new_with_inner(
+                                "poll",
+                                Box::new(e),
+                            ));
+                        }
+                    }
+                }
+                _ => {
+                    return Err(AudioThreadPriorityError::new_with_inner(
+                        "read",
+                        Box::new(e),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// The file descriptor of the timer, readable when the timer has expired. It is
+    /// non-blocking, and owned by this handle.
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for TimerHandle {
+    fn drop(&mut self) {
+        let disarm = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            it_value: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+        };
+        unsafe {
+            libc::timerfd_settime(self.fd, 0, &disarm, std::ptr::null_mut());
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// The scheduling of an interrupt handler thread changed by
+/// `RtPriorityHandle::set_irq_thread_priority`. The original scheduling policy and priority are
+/// restored on drop.
+#[derive(Debug)]
+pub struct IrqPriorityHandle {
+    tid: libc::pid_t,
+    original_policy: libc::c_int,
+    original_priority: libc::c_int,
+}
+
+impl Drop for IrqPriorityHandle {
+    fn drop(&mut self) {
+        let param = libc::sched_param {
+            sched_priority: self.original_priority,
+        };
+        if unsafe { libc::sched_setscheduler(self.tid, self.original_policy, &param) } < 0 {
+            warn!(
+                "Could not restore the scheduling of IRQ thread {}: {}",
+                self.tid,
+                OSError::last_os_error()
+            );
+        }
+    }
+}
+
+/// The USB settings changed by `RtPriorityHandle::usb_audio_latency_tuning`. The original
+/// settings are restored on drop.
+#[derive(Debug)]
+pub struct UsbTuningHandle {
+    /// The priority of the host controller interrupt thread, restored when dropped.
+    _irq_priority: IrqPriorityHandle,
+    /// The `power/autosuspend_delay_ms` file of the device, and its original value.
+    original_autosuspend_delay: Option<(PathBuf, String)>,
+}
+
+impl Drop for UsbTuningHandle {
+    fn drop(&mut self) {
+        if let Some((path, original)) = &self.original_autosuspend_delay {
+            if let Err(e) = fs::write(path, original) {
+                warn!("Could not restore {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Memory usage of a real-time thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemorySnapshot {
+    /// Resident set size of the process containing the thread, in kilobytes.
+    pub rss_kb: u64,
+    /// Number of page faults of the thread that didn't require loading a page from disk.
+    pub minor_faults: u64,
+    /// Number of page faults of the thread that required loading a page from disk.
+    pub major_faults: u64,
+}
+
+/// The number of threads promoted by this process, see
+/// `RtPriorityHandle::latency_report_to_prometheus`.
+#[cfg(feature = "prometheus-text")]
+static PROMOTION_COUNT: AtomicU64 = AtomicU64::new(0);
+/// The number of threads demoted by this process, see
+/// `RtPriorityHandle::latency_report_to_prometheus`.
+#[cfg(feature = "prometheus-text")]
+static DEMOTION_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// A histogram of audio callback latencies, with power-of-2 buckets, see
+/// `RtPriorityHandle::record_callback_latency_us`. There are 32 buckets: bucket 0 counts the
+/// latencies up to 1us, and bucket `i` the latencies above `2^(i-1)`us and up to `2^i`us, the
+/// highest finite bound being `2^30`us (about 18 minutes) for bucket 30. The last bucket counts
+/// all the larger latencies.
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_HISTOGRAM_BUCKETS],
+    sum_us: AtomicU64,
+}
+
+impl LatencyHistogram {
+    /// Create an empty histogram.
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram::default()
+    }
+
+    /// Record a latency, in microseconds. This doesn't allocate nor block.
+    pub fn record(&self, duration_us: u64) {
+        let bucket = if duration_us <= 1 {
+            0
+        } else {
+            cmp::min(
+                (64 - (duration_us - 1).leading_zeros()) as usize,
+                LATENCY_HISTOGRAM_BUCKETS - 1,
+            )
+        };
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(duration_us, Ordering::Relaxed);
+    }
+
+    /// The number of latencies recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// The `p`th percentile of the latencies recorded, `p` being between 0 and 100, in
+    /// microseconds. This is the upper bound of the bucket the percentile falls in, `u64::MAX` if
+    /// this is the last bucket, which has no upper bound, or 0 if nothing has been recorded.
+    pub fn percentile(&self, p: f64) -> u64 {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        let rank = cmp::max(
+            (total as f64 * p.clamp(0.0, 100.0) / 100.0).ceil() as u64,
+            1,
+        );
+        let mut cumulative = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= rank {
+                if bucket == LATENCY_HISTOGRAM_BUCKETS - 1 {
+                    return u64::MAX;
+                }
+                return 1 << bucket;
+            }
+        }
+        u64::MAX
+    }
+
+    /// The histogram in the Prometheus text exposition format, as the
+    /// `audio_callback_latency_us` histogram metric. The last bucket is exported as `+Inf`.
+    pub fn to_prometheus_text(&self) -> String {
+        self.prometheus_text(
+            "audio_callback_latency_us",
+            "Latency of the audio callbacks, in microseconds.",
+        )
+    }
+
+    /// The histogram in the Prometheus text exposition format, as the `name` histogram metric.
+    fn prometheus_text(&self, name: &str, help: &str) -> String {
+        let mut text = format!("# HELP {} {}\n# TYPE {} histogram\n", name, help, name);
+        let mut cumulative = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = if bucket == LATENCY_HISTOGRAM_BUCKETS - 1 {
+                "+Inf".to_string()
+            } else {
+                (1u64 << bucket).to_string()
+            };
+            text.push_str(&format!(
+                "{}_bucket{{le=\"{}\"}} {}\n",
+                name, le, cumulative
+            ));
+        }
+        text.push_str(&format!(
+            "{}_sum {}\n{}_count {}\n",
+            name,
+            self.sum_us.load(Ordering::Relaxed),
+            name,
+            cumulative
+        ));
+        text
+    }
+}
+
+impl Clone for LatencyHistogram {
+    fn clone(&self) -> LatencyHistogram {
+        let histogram = LatencyHistogram::new();
+        for (copy, bucket) in histogram.buckets.iter().zip(self.buckets.iter()) {
+            copy.store(bucket.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        histogram
+            .sum_us
+            .store(self.sum_us.load(Ordering::Relaxed), Ordering::Relaxed);
+        histogram
+    }
+}
+
+/// A memory mapping at least partially locked in RAM, see
+/// `RtPriorityHandle::get_mmap_locked_regions`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockedRegion {
+    /// The start address of the mapping.
+    pub start: usize,
+    /// The end address of the mapping, exclusive.
+    pub end: usize,
+    /// How much of the mapping is locked, in kilobytes.
+    pub size_kb: u64,
+    /// The file mapped, or the kind of mapping such as `[stack]` or `[heap]`, if any.
+    pub path: Option<String>,
+}
+
+/// Parse the mappings at least partially locked in RAM from the content of a `smaps` file.
+fn parse_smaps_locked_regions(smaps: &str) -> Result<Vec<LockedRegion>, AudioThreadPriorityError> {
+    let mut regions = Vec::new();
+    let mut current = None;
+    for line in smaps.lines() {
+        if let Some(value) = line.strip_prefix("Locked:") {
+            let size_kb: u64 =
+                value
+                    .trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse()
+                    .map_err(|_| {
+                        AudioThreadPriorityError::new(&format!("invalid smaps line: {}", line))
+                    })?;
+            if let Some((start, end, path)) = current.take() {
+                if size_kb > 0 {
+                    regions.push(LockedRegion {
+                        start,
+                        end,
+                        size_kb,
+                        path,
+                    });
+                }
+            }
+            continue;
+        }
+        // Mapping headers look like `start-end perms offset dev inode [path]`, the other lines
+        // are `Field: value`.
+        let mut fields = line.splitn(6, char::is_whitespace);
+        let range = fields.next().and_then(|range| range.split_once('-'));
+        if let Some((start, end)) = range {
+            if let (Ok(start), Ok(end)) = (
+                usize::from_str_radix(start, 16),
+                usize::from_str_radix(end, 16),
+            ) {
+                let path = fields
+                    .nth(4)
+                    .map(str::trim_start)
+                    .filter(|path| !path.is_empty())
+                    .map(str::to_string);
+                current = Some((start, end, path));
+            }
+        }
+    }
+    Ok(regions)
+}
+
+/// How much memory of a process is locked in RAM, see `RtPriorityHandle::memory_locking_status`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemlockStatus {
+    /// Whether all the resident memory of the process is locked.
+    pub all_locked: bool,
+    /// The amount of locked memory, in kilobytes.
+    pub current_locked_kb: u64,
+    /// The soft `RLIMIT_MEMLOCK` limit, in kilobytes, `u64::MAX` if unlimited.
+    pub max_lockable_kb: u64,
+}
+
+/// The cost of a callback, as measured by `RtPriorityHandle::profile_one_callback`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CallbackProfile {
+    /// The time it took to run the callback, in microseconds.
+    pub wall_time_us: u64,
+    /// The CPU time used by the callback, in microseconds.
+    pub cpu_time_us: u64,
+    /// The number of times the thread has been preempted while running the callback.
+    pub involuntary_switches: u64,
+    /// The number of minor page faults caused by the callback.
+    pub minor_faults: u64,
+    /// The fraction of the soft real-time budget of the thread used by the callback.
+    pub budget_utilization: f64,
+}
+
+/// A set of changes to apply together with `RtPriorityHandle::transaction`.
+#[cfg(feature = "transactions")]
+#[derive(Debug)]
+pub struct Transaction {
+    changes: Vec<TransactionChange>,
+}
+
+#[cfg(feature = "transactions")]
+#[derive(Debug)]
+enum TransactionChange {
+    /// 0 demotes the thread.
+    Priority(u32),
+    BudgetUs(u64),
+    /// Restore `RLIMIT_RTTIME` as it was before a `BudgetUs` change, including when it wasn't
+    /// set, and the budget of the handle.
+    RttimeLimit {
+        limit: (u64, u64),
+        budget_us: u64,
+    },
+    Affinity(Vec<usize>),
+}
+
+#[cfg(feature = "transactions")]
+impl Transaction {
+    /// Change the real-time priority of the thread.
+    pub fn set_priority(&mut self, priority: u32) -> &mut Transaction {
+        self.changes.push(TransactionChange::Priority(priority));
+        self
+    }
+
+    /// Change the soft real-time budget (`RLIMIT_RTTIME`) of the process, in microseconds.
+    pub fn set_budget_us(&mut self, budget_us: u64) -> &mut Transaction {
+        self.changes.push(TransactionChange::BudgetUs(budget_us));
+        self
+    }
+
+    /// Only allow the thread to run on `cpus`.
+    pub fn set_affinity(&mut self, cpus: &[usize]) -> &mut Transaction {
+        self.changes
+            .push(TransactionChange::Affinity(cpus.to_vec()));
+        self
+    }
+}
+
+/// An anomaly detected by `RtPriorityHandle::monitor_loop`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MonitorEvent {
+    /// The real-time priority of the thread is not the one it was promoted to anymore, e.g.
+    /// because RTKit demoted it.
+    PriorityDrifted {
+        /// The current real-time priority, 0 if the thread is not real-time anymore.
+        priority: u32,
+    },
+    /// The thread used more CPU time per audio buffer than its soft real-time budget.
+    BudgetExceeded {
+        /// The average CPU time used per audio buffer since the last check, in microseconds.
+        cpu_time_per_buffer_us: u64,
+    },
+    /// The CPU affinity of the thread has been changed.
+    AffinityChanged,
+    /// The thread caused major page faults.
+    MemoryPressure {
+        /// The number of major page faults since the last check.
+        major_faults: u64,
+    },
+}
+
+/// How `AdaptiveBudgetHandle` derives the recommended DSP load from the budget pressure.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdaptiveStrategy {
+    /// Recommend `max_fraction` when the thread doesn't use any CPU time, `min_fraction` when it
+    /// uses its entire soft `RLIMIT_RTTIME` budget between two samples, and interpolate linearly in
+    /// between.
+    LinearReduction {
+        /// Fraction of the DSP load recommended under maximum budget pressure.
+        min_fraction: f64,
+        /// Fraction of the DSP load recommended when there is no budget pressure.
+        max_fraction: f64,
+    },
+}
+
+/// Recommends how much DSP work a real-time thread should do, depending on how close it gets to
+/// its real-time budget (the soft `RLIMIT_RTTIME` limit).
+///
+/// This is meant to be used on the real-time thread: `sample` should be called once per callback,
+/// and measures the CPU time used by the thread since the previous call.
+///
+/// `RLIMIT_RTTIME` is a per-process limit: only one controller should be used at a time. The soft
+/// limit it had when the controller was created is restored on drop.
+#[derive(Debug)]
+pub struct AdaptiveBudgetHandle {
+    strategy: AdaptiveStrategy,
+    /// The soft `RLIMIT_RTTIME` limit when the controller was created, in microseconds, `None` if
+    /// unlimited.
+    budget_us: Option<u64>,
+    /// The hard `RLIMIT_RTTIME` limit, in microseconds.
+    max_rttime_us: u64,
+    /// The soft `RLIMIT_RTTIME` limit last set by `sample`, in microseconds.
+    scaled_budget_us: Option<u64>,
+    last_cpu_time: Duration,
+    current_fraction: f64,
+}
+
+impl AdaptiveBudgetHandle {
+    /// Measure the CPU time used by the calling thread since the last sample, and update the
+    /// recommended DSP load fraction accordingly. The soft `RLIMIT_RTTIME` limit is set to the
+    /// budget the controller was created with, scaled by the new fraction, so that a thread that
+    /// doesn't reduce its DSP work gets `SIGXCPU` earlier. Nothing is scaled if `RLIMIT_RTTIME`
+    /// was unlimited.
+    ///
+    /// Returns the new recommended DSP load fraction.
+    pub fn sample(&mut self) -> Result<f64, AudioThreadPriorityError> {
+        let cpu_time = thread_cpu_time()?;
+        let used = cpu_time.saturating_sub(self.last_cpu_time);
+        self.last_cpu_time = cpu_time;
+
+        let pressure = match self.budget_us {
+            Some(budget_us) if budget_us > 0 => {
+                (used.as_micros() as f64 / budget_us as f64).min(1.0)
+            }
+            _ => 0.0,
+        };
+
+        self.current_fraction = match self.strategy {
+            AdaptiveStrategy::LinearReduction {
+                min_fraction,
+                max_fraction,
+            } => max_fraction - (max_fraction - min_fraction) * pressure,
+        }
+        .clamp(0.0, 1.0);
+
+        if let Some(budget_us) = self.budget_us {
+            // A soft limit of 0 would send SIGXCPU as soon as the thread runs.
+            let scaled_budget_us = cmp::max((budget_us as f64 * self.current_fraction) as u64, 1);
+            if self.scaled_budget_us != Some(scaled_budget_us) {
+                set_real_time_limit_with_max(scaled_budget_us, self.max_rttime_us)?;
+                self.scaled_budget_us = Some(scaled_budget_us);
+            }
+        }
+
+        Ok(self.current_fraction)
+    }
+
+    /// The recommended DSP load fraction, between 0.0 and 1.0, as of the last `sample` call.
+    pub fn current_fraction(&self) -> f64 {
+        self.current_fraction
+    }
+}
+
+impl Drop for AdaptiveBudgetHandle {
+    fn drop(&mut self) {
+        if let Some(budget_us) = self.budget_us {
+            if self.scaled_budget_us != self.budget_us {
+                if let Err(e) = set_limits(budget_us, self.max_rttime_us) {
+                    warn!("Could not restore RLIMIT_RTTIME: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// The CPU time consumed by the calling thread.
+fn thread_cpu_time() -> Result<Duration, AudioThreadPriorityError> {
+    let mut ts = unsafe { std::mem::zeroed::<libc::timespec>() };
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) } < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "clock_gettime",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+/// The CPU time consumed by a thread, possibly in another process, from
+/// `/proc/<pid>/task/<tid>/schedstat`.
+fn task_cpu_time(
+    thread_info: &RtPriorityThreadInfoInternal,
+) -> Result<Duration, AudioThreadPriorityError> {
+    let schedstat = fs::read_to_string(task_proc_path(thread_info).join("schedstat"))
+        .map_err(|e| AudioThreadPriorityError::new_with_inner("read schedstat", Box::new(e)))?;
+    schedstat
+        .split_whitespace()
+        .next()
+        .and_then(|ns| ns.parse().ok())
+        .map(Duration::from_nanos)
+        .ok_or_else(|| AudioThreadPriorityError::new("invalid schedstat format"))
+}
+
+/// Measure how late the calling thread wakes up after short sleeps, and return the 99th percentile,
+/// in microseconds.
+fn measure_scheduling_jitter_p99_us() -> u64 {
+    let lateness_us = measure_scheduling_jitter_us();
+    lateness_us[lateness_us.len() * 99 / 100]
+}
+
+/// Measure how late the calling thread wakes up after 100 short sleeps, in microseconds, sorted.
+fn measure_scheduling_jitter_us() -> Vec<u64> {
+    const SAMPLES: usize = 100;
+    const SLEEP: Duration = Duration::from_micros(50);
+    let mut lateness_us = Vec::with_capacity(SAMPLES);
+    for _ in 0..SAMPLES {
+        let start = Instant::now();
+        std::thread::sleep(SLEEP);
+        lateness_us.push(start.elapsed().saturating_sub(SLEEP).as_micros() as u64);
+    }
+    lateness_us.sort_unstable();
+    lateness_us
+}
+
+/// Sleep for `duration` on `CLOCK_MONOTONIC`, resuming the sleep when interrupted by a signal.
+fn monotonic_sleep(duration: Duration) -> Result<(), AudioThreadPriorityError> {
+    let mut remaining = libc::timespec {
+        tv_sec: duration.as_secs() as libc::time_t,
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
+    };
+    loop {
+        let request = remaining;
+        let rv =
+            unsafe { libc::clock_nanosleep(libc::CLOCK_MONOTONIC, 0, &request, &mut remaining) };
+        match rv {
+            0 => return Ok(()),
+            libc::EINTR => continue,
+            _ => {
+                return Err(AudioThreadPriorityError::new_with_inner(
+                    "clock_nanosleep",
+                    Box::new(OSError::from_raw_os_error(rv)),
+                ))
+            }
+        }
+    }
+}
+
+/// The part of `union bpf_attr` used by the `BPF_MAP_*_ELEM` commands, from linux/bpf.h.
+#[cfg(feature = "bpf")]
+#[repr(C)]
+#[derive(Default)]
+struct BpfMapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+/// `struct perf_event_attr`, from linux/perf_event.h, in its first version
+/// (`PERF_ATTR_SIZE_VER0`), that is enough to count hardware events.
+#[cfg(feature = "perf-events")]
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    /// A bit field, see `PERF_ATTR_FLAG_*`.
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+/// The last-level cache counters of a thread, see `RtPriorityHandle::estimate_cache_miss_rate`.
+/// The counters are closed on drop.
+#[cfg(feature = "perf-events")]
+struct CacheCounters {
+    references: RawFd,
+    misses: RawFd,
+}
+
+#[cfg(feature = "perf-events")]
+impl CacheCounters {
+    /// Start counting the cache references and misses of the thread `tid`, on all the CPUs.
+    fn open(tid: kernel_pid_t) -> Result<CacheCounters, AudioThreadPriorityError> {
+        let references = open_perf_counter(tid, PERF_COUNT_HW_CACHE_REFERENCES)?;
+        let misses = open_perf_counter(tid, PERF_COUNT_HW_CACHE_MISSES).inspect_err(|_| {
+            unsafe { libc::close(references) };
+        })?;
+        Ok(CacheCounters { references, misses })
+    }
+}
+
+#[cfg(feature = "perf-events")]
+impl Drop for CacheCounters {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.references);
+            libc::close(self.misses);
+        }
+    }
+}
+
+/// Open a counter of the hardware event `config` for the user-space code of the thread `tid`.
+#[cfg(feature = "perf-events")]
+fn open_perf_counter(tid: kernel_pid_t, config: u64) -> Result<RawFd, AudioThreadPriorityError> {
+    let attr = PerfEventAttr {
+        type_: PERF_TYPE_HARDWARE,
+        size: std::mem::size_of::<PerfEventAttr>() as u32,
+        config,
+        flags: PERF_ATTR_FLAG_EXCLUDE_KERNEL | PERF_ATTR_FLAG_EXCLUDE_HV,
+        ..Default::default()
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &attr as *const PerfEventAttr,
+            tid,
+            -1,
+            -1,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "perf_event_open",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok(fd as RawFd)
+}
+
+/// The current value of a counter opened with `open_perf_counter`.
+#[cfg(feature = "perf-events")]
+fn read_perf_counter(fd: RawFd) -> Result<u64, AudioThreadPriorityError> {
+    let mut value = 0u64;
+    let size = std::mem::size_of::<u64>();
+    if unsafe { libc::read(fd, &mut value as *mut u64 as *mut libc::c_void, size) } != size as isize
+    {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "read perf counter",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok(value)
+}
+
+/// `struct ethtool_coalesce`, from linux/ethtool.h.
+#[cfg(feature = "netaudio")]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct EthtoolCoalesce {
+    cmd: u32,
+    rx_coalesce_usecs: u32,
+    rx_max_coalesced_frames: u32,
+    rx_coalesce_usecs_irq: u32,
+    rx_max_coalesced_frames_irq: u32,
+    tx_coalesce_usecs: u32,
+    /// The other settings, that are kept as they are.
+    others: [u32; 17],
+}
+
+/// Run the ethtool command `cmd` on the network interface `device`, with `coalesce` as argument.
+#[cfg(feature = "netaudio")]
+fn ethtool_coalesce(
+    device: &str,
+    cmd: u32,
+    coalesce: &mut EthtoolCoalesce,
+) -> Result<(), AudioThreadPriorityError> {
+    let mut request = unsafe { std::mem::zeroed::<libc::ifreq>() };
+    if device.len() >= request.ifr_name.len() || device.contains('\0') {
+        return Err(AudioThreadPriorityError::new(&format!(
+            "invalid network interface name {:?}",
+            device
+        )));
+    }
+    for (dst, &src) in request.ifr_name.iter_mut().zip(device.as_bytes()) {
+        *dst = src as libc::c_char;
+    }
+    coalesce.cmd = cmd;
+    request.ifr_ifru.ifru_data = coalesce as *mut EthtoolCoalesce as *mut libc::c_char;
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "socket",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    let r = unsafe { libc::ioctl(fd, libc::SIOCETHTOOL, &mut request) };
+    let error = OSError::last_os_error();
+    unsafe { libc::close(fd) };
+    if r < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            &format!("SIOCETHTOOL on {}", device),
+            Box::new(error),
+        ));
+    }
+    Ok(())
+}
+
+/// Set the I/O scheduling class and level of the thread `tid`, see
+/// `RtPriorityHandle::set_io_priority`.
+fn set_thread_io_priority(
+    tid: kernel_pid_t,
+    class: IoSchedulingClass,
+    level: u8,
+) -> Result<IoPriorityHandle, AudioThreadPriorityError> {
+    if level > 7 {
+        return Err(AudioThreadPriorityError::new(&format!(
+            "invalid I/O priority level {}",
+            level
+        )));
+    }
+    let original = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, tid) };
+    if original < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "ioprio_get",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    let class = match class {
+        IoSchedulingClass::RealTime => IOPRIO_CLASS_RT,
+        IoSchedulingClass::BestEffort => IOPRIO_CLASS_BE,
+        IoSchedulingClass::Idle => IOPRIO_CLASS_IDLE,
+    };
+    set_io_priority(tid, class << IOPRIO_CLASS_SHIFT | level as libc::c_long)?;
+    Ok(IoPriorityHandle { tid, original })
+}
+
+/// The address and size of the stack of the calling thread.
+fn current_thread_stack() -> Result<(*mut u8, usize), AudioThreadPriorityError> {
+    let mut attr = unsafe { std::mem::zeroed::<libc::pthread_attr_t>() };
+    let r = unsafe { libc::pthread_getattr_np(libc::pthread_self(), &mut attr) };
+    if r != 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "pthread_getattr_np",
+            Box::new(OSError::from_raw_os_error(r)),
+        ));
+    }
+    let mut stack_addr = std::ptr::null_mut();
+    let mut stack_size = 0;
+    let r = unsafe { libc::pthread_attr_getstack(&attr, &mut stack_addr, &mut stack_size) };
+    unsafe { libc::pthread_attr_destroy(&mut attr) };
+    if r != 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "pthread_attr_getstack",
+            Box::new(OSError::from_raw_os_error(r)),
+        ));
+    }
+    Ok((stack_addr as *mut u8, stack_size))
+}
+
+/// See `RtPriorityHandle::enforce_stack_size`.
+fn enforce_current_thread_stack_size(
+    min_stack_bytes: usize,
+) -> Result<(), AudioThreadPriorityError> {
+    let (_, stack_size) = current_thread_stack()?;
+    if stack_size < min_stack_bytes {
+        warn!(
+            "The stack of thread {} is too small, set RUST_MIN_STACK or use \
+             std::thread::Builder::stack_size to make it at least {} bytes.",
+            unsafe { libc::syscall(libc::SYS_gettid) },
+            min_stack_bytes
+        );
+        return Err(AudioThreadPriorityError::new(&format!(
+            "stack too small: {} bytes, the minimum is {} bytes",
+            stack_size, min_stack_bytes
+        )));
+    }
+    Ok(())
+}
+
+/// `madvise(MADV_NOHUGEPAGE)` the pages containing the `len` bytes at `addr`.
+fn madvise_no_hugepages(addr: *mut u8, len: usize) -> Result<(), AudioThreadPriorityError> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = addr as usize / page_size * page_size;
+    let len = len + (addr as usize - start);
+    if unsafe { libc::madvise(start as *mut libc::c_void, len, libc::MADV_NOHUGEPAGE) } < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "madvise",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok(())
+}
+
+/// Set the I/O priority of thread `tid`, in the format used by `ioprio_set`.
+fn set_io_priority(
+    tid: kernel_pid_t,
+    ioprio: libc::c_long,
+) -> Result<(), AudioThreadPriorityError> {
+    // IOPRIO_WHO_PROCESS targets a single thread when given a thread id.
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, tid, ioprio) } < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "ioprio_set",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok(())
+}
+
+/// Lay out `raw` as a complete record written by `RtPriorityHandle::serialize_for_mmap`.
+fn raw_handle_to_bytes(raw: &RawRtHandle) -> [u8; MMAP_HANDLE_SIZE] {
+    let mut bytes = [0; MMAP_HANDLE_SIZE];
+    bytes[0..8].copy_from_slice(&raw.thread_id.to_ne_bytes());
+    bytes[8..16].copy_from_slice(&raw.budget_us.to_ne_bytes());
+    bytes[16..20].copy_from_slice(&raw.pid.to_ne_bytes());
+    bytes[20..24].copy_from_slice(&raw.policy.to_ne_bytes());
+    bytes[24..28].copy_from_slice(&raw.priority.to_ne_bytes());
+    bytes[28..32].copy_from_slice(&MMAP_HANDLE_SNAPSHOT_SEQUENCE.to_ne_bytes());
+    bytes
+}
+
+/// The preemption model of a kernel, from the `release` and `version` fields of its `uname()`.
+fn preemption_model_from_uname(release: &str, version: &str) -> KernelPreemptionModel {
+    let flags: Vec<&str> = version.split_whitespace().collect();
+    if release.contains("-rt") || flags.contains(&"PREEMPT_RT") {
+        KernelPreemptionModel::Realtime
+    } else if flags.contains(&"PREEMPT") {
+        KernelPreemptionModel::Full
+    } else {
+        KernelPreemptionModel::None
+    }
+}
+
+/// Switch thread `tid` to `SCHED_DEADLINE`, with the given runtime, deadline and period in
+/// microseconds. Nothing is changed if this fails.
+fn set_sched_deadline(
+    tid: kernel_pid_t,
+    runtime_us: u64,
+    deadline_us: u64,
+    period_us: u64,
+) -> Result<(), AudioThreadPriorityError> {
+    if runtime_us == 0 || runtime_us > deadline_us || deadline_us > period_us {
+        return Err(AudioThreadPriorityError::new(&format!(
+            "invalid deadline parameters: runtime {}us, deadline {}us, period {}us",
+            runtime_us, deadline_us, period_us
+        )));
+    }
+    let attr = SchedAttr {
+        size: std::mem::size_of::<SchedAttr>() as u32,
+        sched_policy: SCHED_DEADLINE as u32,
+        // Deadline threads can't fork otherwise.
+        sched_flags: SCHED_FLAG_RESET_ON_FORK,
+        sched_runtime: runtime_us * 1000,
+        sched_deadline: deadline_us * 1000,
+        sched_period: period_us * 1000,
+        ..Default::default()
+    };
+    if unsafe { libc::syscall(libc::SYS_sched_setattr, tid, &attr, 0) } < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "sched_setattr",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok(())
+}
+
+/// `struct sched_attr`, as used by the `sched_getattr` system call, which has no wrapper in libc.
+#[repr(C)]
+#[derive(Default)]
+struct SchedAttr {
+    size: u32,
+    sched_policy: u32,
+    sched_flags: u64,
+    sched_nice: i32,
+    sched_priority: u32,
+    sched_runtime: u64,
+    sched_deadline: u64,
+    sched_period: u64,
+}
+
+/// Get the scheduling attributes of a thread.
+fn sched_getattr(tid: libc::pid_t) -> Result<SchedAttr, AudioThreadPriorityError> {
+    let mut attr = SchedAttr::default();
+    let size = std::mem::size_of::<SchedAttr>() as libc::c_uint;
+    if unsafe { libc::syscall(libc::SYS_sched_getattr, tid, &mut attr, size, 0) } < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "sched_getattr",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok(attr)
+}
+
+/// `struct sched_attr` with the utilization clamping fields added in Linux 5.3
+/// (`SCHED_ATTR_SIZE_VER1`).
+#[cfg(feature = "numa")]
+#[repr(C)]
+#[derive(Default)]
+struct SchedAttrUtilClamp {
+    attr: SchedAttr,
+    sched_util_min: u32,
+    sched_util_max: u32,
+}
+
+/// The maximum utilization thread `tid` is clamped to, out of `SCHED_CAPACITY_SCALE`.
+#[cfg(feature = "numa")]
+fn util_clamp_max(tid: libc::pid_t) -> Result<u32, AudioThreadPriorityError> {
+    let mut attr = SchedAttrUtilClamp::default();
+    let size = std::mem::size_of::<SchedAttrUtilClamp>() as libc::c_uint;
+    if unsafe { libc::syscall(libc::SYS_sched_getattr, tid, &mut attr, size, 0) } < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "sched_getattr",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok(attr.sched_util_max)
+}
+
+/// Clamp the maximum utilization of thread `tid` to `util_max`, out of `SCHED_CAPACITY_SCALE`,
+/// without changing its scheduling policy and parameters.
+#[cfg(feature = "numa")]
+fn set_util_clamp_max(tid: libc::pid_t, util_max: u32) -> Result<(), AudioThreadPriorityError> {
+    let attr = SchedAttrUtilClamp {
+        attr: SchedAttr {
+            size: std::mem::size_of::<SchedAttrUtilClamp>() as u32,
+            sched_flags: SCHED_FLAG_KEEP_ALL | SCHED_FLAG_UTIL_CLAMP_MAX,
+            ..Default::default()
+        },
+        sched_util_max: util_max,
+        ..Default::default()
+    };
+    if unsafe { libc::syscall(libc::SYS_sched_setattr, tid, &attr, 0) } < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "sched_setattr",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok(())
+}
+
+/// Get the integer socket option `option` at `level` of socket `fd`. `name` is used in errors.
+fn get_socket_option(
+    fd: RawFd,
+    level: libc::c_int,
+    option: libc::c_int,
+    name: &str,
+) -> Result<i32, AudioThreadPriorityError> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    if unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            option,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    } < 0
+    {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            &format!("getsockopt {} on fd {}", name, fd),
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok(value)
+}
+
+/// Set the integer socket option `option` at `level` of socket `fd`. `name` is used in errors.
+fn set_socket_option(
+    fd: RawFd,
+    level: libc::c_int,
+    option: libc::c_int,
+    name: &str,
+    value: i32,
+) -> Result<(), AudioThreadPriorityError> {
+    if unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    } < 0
+    {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            &format!("setsockopt {} on fd {}", name, fd),
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok(())
+}
+
+/// Get the `SO_PRIORITY` option of socket `fd`.
+fn get_socket_priority(fd: RawFd) -> Result<i32, AudioThreadPriorityError> {
+    get_socket_option(fd, libc::SOL_SOCKET, libc::SO_PRIORITY, "SO_PRIORITY")
+}
+
+/// Set the `SO_PRIORITY` option of socket `fd`.
+fn set_socket_priority(fd: RawFd, priority: i32) -> Result<(), AudioThreadPriorityError> {
+    set_socket_option(
+        fd,
+        libc::SOL_SOCKET,
+        libc::SO_PRIORITY,
+        "SO_PRIORITY",
+        priority,
+    )
+}
+
+/// The socket option holding the traffic class of socket `fd`: `IP_TOS` for IPv4 sockets,
+/// `IPV6_TCLASS` for IPv6 sockets, as `(level, option, name)`.
+fn traffic_class_option(
+    fd: RawFd,
+) -> Result<(libc::c_int, libc::c_int, &'static str), AudioThreadPriorityError> {
+    match get_socket_option(fd, libc::SOL_SOCKET, libc::SO_DOMAIN, "SO_DOMAIN")? {
+        libc::AF_INET => Ok((libc::IPPROTO_IP, libc::IP_TOS, "IP_TOS")),
+        libc::AF_INET6 => Ok((libc::IPPROTO_IPV6, libc::IPV6_TCLASS, "IPV6_TCLASS")),
+        domain => Err(AudioThreadPriorityError::new(&format!(
+            "fd {} is not an IP socket (domain {})",
+            fd, domain
+        ))),
+    }
+}
+
+/// Restore the socket priorities changed when promoting a thread, after a failed promotion.
+fn restore_socket_priorities(handle: &RtPriorityHandleInternal) {
+    for &(fd, priority) in &handle.original_socket_priorities {
+        if let Err(e) = set_socket_priority(fd, priority) {
+            warn!("Could not restore the priority of socket {}: {}", fd, e);
+        }
+    }
+}
+
+/// The thread id of the owner of a locked mutex, or 0 if it's unlocked. This reads the
+/// `__owner` field of the glibc mutex, and returns `None` with other C libraries.
+#[cfg(target_env = "gnu")]
+unsafe fn mutex_owner(mutex: *const libc::pthread_mutex_t) -> Option<libc::pid_t> {
+    // `__lock`, `__count`, `__owner`.
+    Some(*(mutex as *const libc::c_int).add(2))
+}
+
+#[cfg(not(target_env = "gnu"))]
+unsafe fn mutex_owner(_mutex: *const libc::pthread_mutex_t) -> Option<libc::pid_t> {
+    None
+}
+
+/// The `/proc` directory of a thread.
+fn task_proc_path(thread_info: &RtPriorityThreadInfoInternal) -> PathBuf {
+    PathBuf::from(format!(
+        "/proc/{}/task/{}",
+        thread_info.pid, thread_info.thread_id
+    ))
+}
+
+/// The thermal zones that measure the temperature of a CPU, or all the thermal zones if none of
+/// them look like it, see `RtPriorityHandle::core_temp_celsius`.
+#[cfg(feature = "thermal")]
+fn cpu_thermal_zones() -> Result<Vec<PathBuf>, AudioThreadPriorityError> {
+    let zones = fs::read_dir("/sys/class/thermal")
+        .map_err(|e| AudioThreadPriorityError::new_with_inner("read_dir", Box::new(e)))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with("thermal_zone"))
+        })
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    let cpu_zones = zones
+        .iter()
+        .filter(|zone| {
+            fs::read_to_string(zone.join("type")).is_ok_and(|kind| {
+                let kind = kind.trim().to_ascii_lowercase();
+                kind.contains("cpu") || kind.contains("pkg") || kind.contains("core")
+            })
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    if cpu_zones.is_empty() {
+        Ok(zones)
+    } else {
+        Ok(cpu_zones)
     }
 }
 
-impl PartialEq for RtPriorityThreadInfoInternal {
-    fn eq(&self, other: &Self) -> bool {
-        self.thread_id == other.thread_id && self.pthread_id == other.pthread_id
+/// Read a temperature in millidegrees Celsius from the sysfs file at `path`.
+#[cfg(feature = "thermal")]
+fn read_millicelsius(path: &Path) -> Option<i64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Schedule the handler thread of interrupt `irq` with `SCHED_FIFO` at `priority`, see
+/// `RtPriorityHandle::set_irq_thread_priority`.
+fn set_irq_thread_fifo(
+    irq: u32,
+    priority: u32,
+) -> Result<IrqPriorityHandle, AudioThreadPriorityError> {
+    // Kernel threads are the only thread of their "process".
+    let prefix = format!("irq/{}-", irq);
+    let tid = fs::read_dir("/proc")
+        .map_err(|e| AudioThreadPriorityError::new_with_inner("read_dir", Box::new(e)))?
+        .filter_map(|entry| {
+            entry
+                .ok()?
+                .file_name()
+                .to_str()?
+                .parse::<libc::pid_t>()
+                .ok()
+        })
+        .find(|pid| {
+            fs::read_to_string(format!("/proc/{}/task/{}/comm", pid, pid))
+                .is_ok_and(|comm| comm.starts_with(&prefix))
+        })
+        .ok_or_else(|| {
+            AudioThreadPriorityError::new(&format!("no handler thread for IRQ {}", irq))
+        })?;
+
+    let original_policy = unsafe { libc::sched_getscheduler(tid) };
+    let mut original_param = libc::sched_param { sched_priority: 0 };
+    if original_policy < 0 || unsafe { libc::sched_getparam(tid, &mut original_param) } < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "sched_getscheduler",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    let param = libc::sched_param {
+        sched_priority: priority as libc::c_int,
+    };
+    if unsafe { libc::sched_setscheduler(tid, libc::SCHED_FIFO, &param) } < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "sched_setscheduler",
+            Box::new(OSError::last_os_error()),
+        ));
     }
+    Ok(IrqPriorityHandle {
+        tid,
+        original_policy,
+        original_priority: original_param.sched_priority,
+    })
 }
 
-/*#[derive(Debug)]*/
-pub struct RtPriorityHandleInternal {
-    thread_info: RtPriorityThreadInfoInternal,
+/// The current time of `CLOCK_MONOTONIC`, that is the same for all the processes, in nanoseconds.
+fn monotonic_now_ns() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64
+}
+
+/// Fill `buf` with random bytes from `getrandom`.
+#[cfg(any(feature = "secure-ipc", feature = "otel"))]
+fn fill_random(buf: &mut [u8]) -> Result<(), AudioThreadPriorityError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let rv = unsafe {
+            libc::getrandom(
+                buf[filled..].as_mut_ptr() as *mut libc::c_void,
+                buf.len() - filled,
+                0,
+            )
+        };
+        if rv < 0 {
+            let error = OSError::last_os_error();
+            if error.raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "getrandom",
+                Box::new(error),
+            ));
+        }
+        filled += rv as usize;
+    }
+    Ok(())
+}
+
+/// Read the frequency in `file` of the cpufreq directory of the CPU the calling thread runs on,
+/// in MHz. cpufreq reports frequencies in kHz.
+fn cpufreq_mhz(file: &str) -> Result<u32, AudioThreadPriorityError> {
+    let cpu = unsafe { libc::sched_getcpu() };
+    if cpu < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "sched_getcpu",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    let path = PathBuf::from(format!(
+        "/sys/devices/system/cpu/cpu{}/cpufreq/{}",
+        cpu, file
+    ));
+    let khz = fs::read_to_string(&path).map_err(|e| {
+        AudioThreadPriorityError::new_with_inner(&format!("{}", path.display()), Box::new(e))
+    })?;
+    khz.trim()
+        .parse::<u32>()
+        .map(|khz| khz / 1000)
+        .map_err(|_| AudioThreadPriorityError::new(&format!("invalid {}", file)))
+}
+
+/// The fields of `/proc/<pid>/task/<tid>/stat`, starting at the third one (`state`). The second
+/// field (`comm`) is skipped, because it can contain spaces and parentheses.
+fn read_task_stat(
+    thread_info: &RtPriorityThreadInfoInternal,
+) -> Result<Vec<String>, AudioThreadPriorityError> {
+    let stat = fs::read_to_string(task_proc_path(thread_info).join("stat"))
+        .map_err(|e| AudioThreadPriorityError::new_with_inner("read stat", Box::new(e)))?;
+    let after_comm = stat
+        .rfind(')')
+        .map(|i| &stat[i + 1..])
+        .ok_or_else(|| AudioThreadPriorityError::new("invalid stat format"))?;
+    Ok(after_comm.split_whitespace().map(String::from).collect())
+}
+
+/// Parse field `n` (1-based, as numbered in proc(5)) of the fields returned by `read_task_stat`.
+fn task_stat_field(fields: &[String], n: usize) -> Result<u64, AudioThreadPriorityError> {
+    fields
+        .get(n - 3)
+        .and_then(|f| f.parse().ok())
+        .ok_or_else(|| AudioThreadPriorityError::new(&format!("invalid stat field {}", n)))
+}
+
+/// Parse the value of `key` in `/proc/<pid>/task/<tid>/status`.
+fn task_status_field(
+    thread_info: &RtPriorityThreadInfoInternal,
+    key: &str,
+) -> Result<u64, AudioThreadPriorityError> {
+    let status = fs::read_to_string(task_proc_path(thread_info).join("status"))
+        .map_err(|e| AudioThreadPriorityError::new_with_inner("read status", Box::new(e)))?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+        .ok_or_else(|| AudioThreadPriorityError::new(&format!("no {} in status", key)))
+}
+
+/// The ids RTKit is given for a thread running in a container, see
+/// `RtPriorityRequest::in_container`: the parent of the process, from `getppid()` for the calling
+/// process, as the authorizing process, and the id of the thread in its innermost PID namespace,
+/// from the last `NSpid` entry of its `status` file.
+fn container_namespace_ids(
+    thread_info: &RtPriorityThreadInfoInternal,
+) -> Result<(u64, u64), AudioThreadPriorityError> {
+    let parent_pid = if thread_info.pid == unsafe { libc::getpid() } {
+        unsafe { libc::getppid() as u64 }
+    } else {
+        task_status_field(thread_info, "PPid")?
+    };
+    let path = task_proc_path(thread_info).join("status");
+    let status = fs::read_to_string(&path).map_err(|e| {
+        AudioThreadPriorityError::new_with_inner(&format!("read {}", path.display()), Box::new(e))
+    })?;
+    let thread_id = innermost_namespace_id(&status, "NSpid")
+        .ok_or_else(|| AudioThreadPriorityError::new(&format!("no NSpid in {}", path.display())))?;
+    Ok((parent_pid, thread_id))
+}
+
+/// The last id of the `key` field (e.g. `NSpid`) of a `status` file, that is the id in the
+/// innermost PID namespace.
+fn innermost_namespace_id(status: &str, key: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|ids| ids.split_whitespace().last())
+        .and_then(|id| id.parse().ok())
+}
+
+/// The number of major page faults of a thread.
+fn major_faults(
+    thread_info: &RtPriorityThreadInfoInternal,
+) -> Result<u64, AudioThreadPriorityError> {
+    task_stat_field(&read_task_stat(thread_info)?, 12)
+}
+
+/// The `se.nr_migrations` field of the scheduler debug statistics of a thread.
+fn cpu_migrations(
+    thread_info: &RtPriorityThreadInfoInternal,
+) -> Result<u64, AudioThreadPriorityError> {
+    let sched = fs::read_to_string(task_proc_path(thread_info).join("sched"))
+        .map_err(|e| AudioThreadPriorityError::new_with_inner("read sched", Box::new(e)))?;
+    sched
+        .lines()
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if key.trim() == "se.nr_migrations" {
+                value.trim().parse().ok()
+            } else {
+                None
+            }
+        })
+        .ok_or_else(|| AudioThreadPriorityError::new("no se.nr_migrations in sched"))
+}
+
+/// Parse a list of CPUs in the format used by sysfs, e.g. `0-3,8,10-11`.
+fn parse_cpu_list(list: &str) -> Result<Vec<usize>, AudioThreadPriorityError> {
+    let invalid = || AudioThreadPriorityError::new(&format!("invalid cpu list: {}", list));
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let start: usize = bounds
+            .next()
+            .and_then(|b| b.parse().ok())
+            .ok_or_else(invalid)?;
+        let end: usize = match bounds.next() {
+            Some(b) => b.parse().map_err(|_| invalid())?,
+            None => start,
+        };
+        cpus.extend(start..=end);
+    }
+    Ok(cpus)
+}
+
+/// The CPUs thread `tid` is allowed to run on.
+fn get_thread_affinity(tid: kernel_pid_t) -> Result<Vec<usize>, AudioThreadPriorityError> {
+    let mut set = unsafe { std::mem::zeroed::<libc::cpu_set_t>() };
+    if unsafe {
+        libc::sched_getaffinity(
+            tid as libc::pid_t,
+            std::mem::size_of::<libc::cpu_set_t>(),
+            &mut set,
+        )
+    } < 0
+    {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "sched_getaffinity",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok((0..libc::CPU_SETSIZE as usize)
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+        .collect())
+}
+
+/// The effective CPUs of the cpuset cgroup of a thread, from cgroup v2 if the thread is in a
+/// unified hierarchy, from the v1 cpuset hierarchy otherwise. `None` if they can't be read.
+fn cgroup_cpus(thread_info: &RtPriorityThreadInfoInternal) -> Option<Vec<usize>> {
+    let cgroups = fs::read_to_string(task_proc_path(thread_info).join("cgroup")).ok()?;
+    // Each line is `hierarchy-id:controllers:path`, the v2 hierarchy has id 0 and no controllers.
+    let (path, file) = cgroups.lines().find_map(|line| {
+        let mut fields = line.splitn(3, ':');
+        let (id, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+        if id == "0" && controllers.is_empty() {
+            Some((
+                Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/')),
+                "cpuset.cpus.effective",
+            ))
+        } else if controllers.split(',').any(|c| c == "cpuset") {
+            Some((
+                Path::new(CPUSET_ROOT).join(path.trim_start_matches('/')),
+                "cpuset.effective_cpus",
+            ))
+        } else {
+            None
+        }
+    })?;
+    parse_cpu_list(&fs::read_to_string(path.join(file)).ok()?).ok()
+}
+
+/// The performance cores of a hybrid CPU, or the CPUs with the highest maximum frequency
+/// otherwise.
+fn performance_cpus() -> Result<Vec<usize>, AudioThreadPriorityError> {
+    // Intel hybrid CPUs expose their performance cores as a separate PMU.
+    if let Ok(list) = fs::read_to_string("/sys/devices/cpu_core/cpus") {
+        return parse_cpu_list(&list);
+    }
+    let online = fs::read_to_string("/sys/devices/system/cpu/online")
+        .map_err(|e| AudioThreadPriorityError::new_with_inner("read online cpus", Box::new(e)))?;
+    let mut max_freqs = Vec::new();
+    for cpu in parse_cpu_list(&online)? {
+        let path = format!(
+            "/sys/devices/system/cpu/cpu{}/cpufreq/cpuinfo_max_freq",
+            cpu
+        );
+        let freq: u64 = fs::read_to_string(&path)
+            .ok()
+            .and_then(|freq| freq.trim().parse().ok())
+            .ok_or_else(|| {
+                AudioThreadPriorityError::new(&format!("no max frequency in {}", path))
+            })?;
+        max_freqs.push((cpu, freq));
+    }
+    let highest = max_freqs.iter().map(|&(_, freq)| freq).max().unwrap_or(0);
+    Ok(max_freqs
+        .into_iter()
+        .filter(|&(_, freq)| freq == highest)
+        .map(|(cpu, _)| cpu)
+        .collect())
+}
+
+/// Set the timer slack of thread `tid`, of the calling process, to `slack_ns`. This uses
+/// `prctl(PR_SET_TIMERSLACK)` on the calling thread, and `/proc/self/task/<tid>/timerslack_ns`
+/// for the other threads.
+fn set_timer_slack(tid: kernel_pid_t, slack_ns: u64) -> Result<(), AudioThreadPriorityError> {
+    if tid == unsafe { libc::syscall(libc::SYS_gettid) } {
+        if unsafe { libc::prctl(libc::PR_SET_TIMERSLACK, slack_ns as libc::c_ulong) } < 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "prctl",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        return Ok(());
+    }
+    fs::write(
+        format!("/proc/self/task/{}/timerslack_ns", tid),
+        slack_ns.to_string(),
+    )
+    .map_err(|e| AudioThreadPriorityError::new_with_inner("write timerslack_ns", Box::new(e)))
+}
+
+/// Only allow thread `tid` to run on `cpus`.
+fn set_thread_affinity(tid: kernel_pid_t, cpus: &[usize]) -> Result<(), AudioThreadPriorityError> {
+    let mut set = unsafe { std::mem::zeroed::<libc::cpu_set_t>() };
+    for &cpu in cpus {
+        if cpu >= libc::CPU_SETSIZE as usize {
+            return Err(AudioThreadPriorityError::new(&format!(
+                "cpu {} out of range",
+                cpu
+            )));
+        }
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    if unsafe {
+        libc::sched_setaffinity(
+            tid as libc::pid_t,
+            std::mem::size_of::<libc::cpu_set_t>(),
+            &set,
+        )
+    } < 0
+    {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "sched_setaffinity",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether process `pid` still exists. This is true for processes that exist but that we are not
+/// allowed to signal.
+fn process_is_alive(pid: libc::pid_t) -> bool {
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    OSError::last_os_error().raw_os_error() == Some(libc::EPERM)
 }
 
 fn item_as_i64(i: MessageItem) -> Result<i64, AudioThreadPriorityError> {
@@ -86,56 +7033,805 @@ fn item_as_i64(i: MessageItem) -> Result<i64, AudioThreadPriorityError> {
     }
 }
 
-fn rtkit_set_realtime(thread: u64, pid: u64, prio: u32) -> Result<(), Box<dyn Error>> {
-    let m = if unsafe { libc::getpid() as u64 } == pid {
+/// A D-Bus service implementing the `org.freedesktop.RealtimeKit1` interface.
+struct RealtimeService {
+    bus: BusType,
+    name: &'static str,
+    path: &'static str,
+    interface: &'static str,
+    /// Whether `MakeThreadRealtime` can be used for threads of the calling process, otherwise
+    /// `MakeThreadRealtimeWithPID` is always used.
+    has_make_thread_realtime: bool,
+}
+
+const RTKIT: RealtimeService = RealtimeService {
+    bus: BusType::System,
+    name: "org.freedesktop.RealtimeKit1",
+    path: "/org/freedesktop/RealtimeKit1",
+    interface: "org.freedesktop.RealtimeKit1",
+    has_make_thread_realtime: true,
+};
+
+/// The realtime interface of the XDG Desktop Portal, proxied to RTKit by PipeWire, and available
+/// from sandboxes (e.g. Flatpak) that can't reach RTKit on the system bus.
+#[cfg(feature = "pipewire-portal")]
+const REALTIME_PORTAL: RealtimeService = RealtimeService {
+    bus: BusType::Session,
+    name: "org.freedesktop.portal.Desktop",
+    path: "/org/freedesktop/portal/desktop",
+    interface: "org.freedesktop.portal.Realtime",
+    has_make_thread_realtime: false,
+};
+
+// Not exposed by libc for glibc targets.
+extern "C" {
+    fn pthread_mutexattr_setprioceiling(
+        attr: *mut libc::pthread_mutexattr_t,
+        prioceiling: libc::c_int,
+    ) -> libc::c_int;
+    fn pthread_mutex_getprioceiling(
+        mutex: *const libc::pthread_mutex_t,
+        prioceiling: *mut libc::c_int,
+    ) -> libc::c_int;
+}
+
+/// A timer on the CPU time of the thread that created it, that doesn't send a signal when it
+/// expires, see `RtPriorityRequest::use_timer_for_budget_monitoring`.
+struct BudgetTimer {
+    timer: libc::timer_t,
+    /// The CPU time after which the timer expires, once armed.
+    expiry: libc::timespec,
+}
+
+// Timer ids are valid in the whole process.
+unsafe impl Send for BudgetTimer {}
+unsafe impl Sync for BudgetTimer {}
+
+impl BudgetTimer {
+    /// Create a timer expiring after `BUDGET_TIMER_FRACTION` of `budget_us` of CPU time of the
+    /// calling thread, and arm it.
+    fn new(budget_us: u64) -> Result<BudgetTimer, AudioThreadPriorityError> {
+        let mut event = unsafe { std::mem::zeroed::<libc::sigevent>() };
+        event.sigev_notify = libc::SIGEV_NONE;
+        let mut timer = std::ptr::null_mut();
+        if unsafe { libc::timer_create(libc::CLOCK_THREAD_CPUTIME_ID, &mut event, &mut timer) } < 0
+        {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "timer_create",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        // At least 1ns, a zero expiry disarms the timer.
+        let expiry_ns = cmp::max(
+            (budget_us as f64 * BUDGET_TIMER_FRACTION * 1000.0) as u64,
+            1,
+        );
+        let timer = BudgetTimer {
+            timer,
+            expiry: libc::timespec {
+                tv_sec: (expiry_ns / 1_000_000_000) as libc::time_t,
+                tv_nsec: (expiry_ns % 1_000_000_000) as libc::c_long,
+            },
+        };
+        timer.arm()?;
+        Ok(timer)
+    }
+
+    fn arm(&self) -> Result<(), AudioThreadPriorityError> {
+        let value = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            it_value: self.expiry,
+        };
+        if unsafe { libc::timer_settime(self.timer, 0, &value, std::ptr::null_mut()) } < 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "timer_settime",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        Ok(())
+    }
+
+    fn expired(&self) -> bool {
+        let mut value = unsafe { std::mem::zeroed::<libc::itimerspec>() };
+        if unsafe { libc::timer_gettime(self.timer, &mut value) } < 0 {
+            return false;
+        }
+        // A one-shot timer is disarmed when it expires.
+        value.it_value.tv_sec == 0 && value.it_value.tv_nsec == 0
+    }
+}
+
+impl Drop for BudgetTimer {
+    fn drop(&mut self) {
+        unsafe { libc::timer_delete(self.timer) };
+    }
+}
+
+/// A mutex using the priority ceiling protocol, see `RtPriorityHandle::set_priority_ceiling`.
+struct PriorityCeilingMutex {
+    // Boxed, a pthread mutex can't be moved once initialized.
+    mutex: Box<UnsafeCell<libc::pthread_mutex_t>>,
+}
+
+// pthread mutexes are meant to be used from multiple threads.
+unsafe impl Send for PriorityCeilingMutex {}
+unsafe impl Sync for PriorityCeilingMutex {}
+
+impl PriorityCeilingMutex {
+    fn new(ceiling: u32) -> Result<PriorityCeilingMutex, AudioThreadPriorityError> {
+        let error = |function: &str, rv: libc::c_int| {
+            AudioThreadPriorityError::new_with_inner(
+                function,
+                Box::new(OSError::from_raw_os_error(rv)),
+            )
+        };
+        let mutex = Box::new(UnsafeCell::new(unsafe {
+            std::mem::zeroed::<libc::pthread_mutex_t>()
+        }));
+        unsafe {
+            let mut attr = std::mem::zeroed::<libc::pthread_mutexattr_t>();
+            let rv = libc::pthread_mutexattr_init(&mut attr);
+            if rv != 0 {
+                return Err(error("pthread_mutexattr_init", rv));
+            }
+            let mut rv = libc::pthread_mutexattr_setprotocol(&mut attr, libc::PTHREAD_PRIO_PROTECT);
+            if rv != 0 {
+                libc::pthread_mutexattr_destroy(&mut attr);
+                return Err(error("pthread_mutexattr_setprotocol", rv));
+            }
+            rv = pthread_mutexattr_setprioceiling(&mut attr, ceiling as libc::c_int);
+            if rv != 0 {
+                libc::pthread_mutexattr_destroy(&mut attr);
+                return Err(error("pthread_mutexattr_setprioceiling", rv));
+            }
+            rv = libc::pthread_mutex_init(mutex.get(), &attr);
+            libc::pthread_mutexattr_destroy(&mut attr);
+            if rv != 0 {
+                return Err(error("pthread_mutex_init", rv));
+            }
+        }
+        Ok(PriorityCeilingMutex { mutex })
+    }
+}
+
+impl Drop for PriorityCeilingMutex {
+    fn drop(&mut self) {
+        unsafe { libc::pthread_mutex_destroy(self.mutex.get()) };
+    }
+}
+
+/// The mutex created by `RtPriorityHandle::set_priority_ceiling`, locked by
+/// `RtPriorityHandle::lock_priority_ceiling_mutex`. It is unlocked on drop.
+pub struct PriorityCeilingGuard<'a> {
+    mutex: &'a PriorityCeilingMutex,
+    // A mutex has to be unlocked by the thread that locked it.
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+impl Drop for PriorityCeilingGuard<'_> {
+    fn drop(&mut self) {
+        unsafe { libc::pthread_mutex_unlock(self.mutex.mutex.get()) };
+    }
+}
+
+impl std::fmt::Debug for PriorityCeilingGuard<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PriorityCeilingGuard")
+            .finish_non_exhaustive()
+    }
+}
+
+/// A D-Bus connection that can be moved to the thread borrowing it from a `DbusPool`.
+struct PooledConnection(Connection);
+
+// libdbus connections are thread-safe (`dbus` initializes libdbus threading when opening a
+// connection), and a pooled connection is only used by one thread at a time.
+unsafe impl Send for PooledConnection {}
+
+/// A pool of connections to the system bus, shared by all the threads of the process, used to
+/// talk to RTKit.
+///
+/// By default, each promotion opens (and closes) its own D-Bus connection. When many threads are
+/// promoted at the same time, e.g. when a thread pool is started, it can be faster to open a few
+/// connections up front, and to reuse them. Once installed with `install`, promotions borrow a
+/// connection from the pool for the duration of the RTKit calls, and wait for a connection to be
+/// available if they are all in use.
+///
+/// The pool is not used in child processes created with `fork`: they share the sockets of the
+/// parent, and the lock of the pool could have been held by another thread when forking.
+pub struct DbusPool {
+    connections: Mutex<Vec<PooledConnection>>,
+    available: Condvar,
+    /// The process that opened the connections.
+    pid: libc::pid_t,
+}
+
+/// Replace the closed connection `connection` of a `DbusPool` by a new one.
+fn reconnect(connection: &mut PooledConnection) -> Result<(), dbus::Error> {
+    connection.0 = Connection::get_private(BusType::System)?;
+    Ok(())
+}
+
+/// The pool installed with `DbusPool::install`, if any.
+static DBUS_POOL: OnceLock<DbusPool> = OnceLock::new();
+
+impl DbusPool {
+    /// Open `size` connections to the system bus.
+    pub fn new(size: usize) -> Result<DbusPool, AudioThreadPriorityError> {
+        if size == 0 {
+            return Err(AudioThreadPriorityError::new("empty D-Bus connection pool"));
+        }
+        let connections = (0..size)
+            .map(|_| Connection::get_private(BusType::System).map(PooledConnection))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(DbusPool {
+            connections: Mutex::new(connections),
+            available: Condvar::new(),
+            pid: unsafe { libc::getpid() },
+        })
+    }
+
+    /// Use this pool for all subsequent promotions in this process. A pool can only be installed
+    /// once per process.
+    pub fn install(self) -> Result<(), AudioThreadPriorityError> {
+        DBUS_POOL
+            .set(self)
+            .map_err(|_| AudioThreadPriorityError::new("a D-Bus pool is already installed"))
+    }
+
+    /// Call `f` with a connection of the pool, waiting for one to be available.
+    ///
+    /// A connection closed by the bus, e.g. because `dbus-daemon` restarted, is replaced by a new
+    /// one: libdbus notices it on the first call that fails, and the connection is reopened before
+    /// being returned to the pool, or when it is borrowed next if that failed.
+    fn with_connection<T, E: From<dbus::Error>>(
+        &self,
+        f: impl FnOnce(&Connection) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        let mut connection = loop {
+            match connections.pop() {
+                Some(connection) => break connection,
+                None => {
+                    connections = self
+                        .available
+                        .wait(connections)
+                        .unwrap_or_else(|e| e.into_inner())
+                }
+            }
+        };
+        drop(connections);
+
+        let r = if connection.0.is_connected() || reconnect(&mut connection).is_ok() {
+            f(&connection.0)
+        } else {
+            Err(E::from(dbus::Error::new_custom(
+                "org.freedesktop.DBus.Error.Disconnected",
+                "could not reconnect to the system bus",
+            )))
+        };
+        if !connection.0.is_connected() {
+            if let Err(e) = reconnect(&mut connection) {
+                warn!("Could not reconnect to the system bus: {}", e);
+            }
+        }
+
+        self.connections
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(connection);
+        self.available.notify_one();
+        r
+    }
+}
+
+/// A secret shared by a process promoting threads of other processes and these processes, used
+/// to sign and check `IpcToken`s.
+///
+/// The secret is created by the promoting process, and passed to the other processes as a memfd
+/// (see `to_fd`), e.g. with `SCM_RIGHTS` on a Unix socket. It is never written to disk.
+///
+/// All the processes holding the secret share the same key: a token only proves that it was
+/// created by one of them, not by the process it names. Any process the secret is sent to can
+/// get any thread of any process promoted, so it should only be sent to processes trusted with
+/// that. A new secret can't be installed once one has been, so a leaked secret stays valid for
+/// the lifetime of the promoting process.
+#[cfg(feature = "secure-ipc")]
+pub struct IpcSecret {
+    key: [u8; 32],
+}
+
+/// The secret installed with `IpcSecret::install`, if any.
+#[cfg(feature = "secure-ipc")]
+static IPC_SECRET: OnceLock<IpcSecret> = OnceLock::new();
+
+#[cfg(feature = "secure-ipc")]
+impl IpcSecret {
+    /// Create a random secret.
+    pub fn new() -> Result<IpcSecret, AudioThreadPriorityError> {
+        let mut key = [0u8; 32];
+        fill_random(&mut key)?;
+        Ok(IpcSecret { key })
+    }
+
+    /// Write the secret to a new memfd, to be sent to another process with `SCM_RIGHTS`. The
+    /// caller owns the returned file descriptor.
+    pub fn to_fd(&self) -> Result<RawFd, AudioThreadPriorityError> {
+        let fd = unsafe {
+            libc::memfd_create(
+                b"audio_thread_priority_ipc_secret\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "memfd_create",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        let written =
+            unsafe { libc::write(fd, self.key.as_ptr() as *const libc::c_void, self.key.len()) };
+        if written != self.key.len() as isize {
+            let error = OSError::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "write",
+                Box::new(error),
+            ));
+        }
+        Ok(fd)
+    }
+
+    /// Read a secret from a file descriptor created with `to_fd`, e.g. received with
+    /// `SCM_RIGHTS`. `fd` is not closed.
+    pub fn from_fd(fd: RawFd) -> Result<IpcSecret, AudioThreadPriorityError> {
+        let mut key = [0u8; 32];
+        let read = unsafe { libc::pread(fd, key.as_mut_ptr() as *mut libc::c_void, key.len(), 0) };
+        if read < 0 {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                "pread",
+                Box::new(OSError::last_os_error()),
+            ));
+        }
+        if read != key.len() as isize {
+            return Err(AudioThreadPriorityError::new("truncated IPC secret"));
+        }
+        Ok(IpcSecret { key })
+    }
+
+    /// Use this secret to check the tokens of all subsequent promotions in this process. Once it
+    /// is installed, promoting a thread of another process fails if the request has no token, see
+    /// `RtPriorityRequest::with_ipc_token`. A secret can only be installed once per process.
+    pub fn install(self) -> Result<(), AudioThreadPriorityError> {
+        IPC_SECRET
+            .set(self)
+            .map_err(|_| AudioThreadPriorityError::new("an IPC secret is already installed"))
+    }
+
+    /// Sign the thread described by `thread_info`, to be sent along with its thread info to the
+    /// process promoting it.
+    // kernel_pid_t is not 64 bits on all platforms.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn create_token(&self, thread_info: &RtPriorityThreadInfoInternal) -> IpcToken {
+        let mut token = IpcToken {
+            pid: thread_info.pid,
+            thread_id: thread_info.thread_id as i64,
+            timestamp_ns: monotonic_now_ns(),
+            mac: [0; 32],
+        };
+        token.mac = self.mac(&token).finalize().into_bytes().into();
+        token
+    }
+
+    /// The HMAC-SHA256 of the pid, thread id and timestamp of `token`.
+    fn mac(&self, token: &IpcToken) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(&token.serialize()[..IPC_TOKEN_SIZE - 32]);
+        mac
+    }
+}
+
+/// Proof that a thread info has been sent by a process that knows the `IpcSecret` of the process
+/// promoting the thread, see `RtPriorityHandle::validate_ipc_token`.
+#[cfg(feature = "secure-ipc")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpcToken {
+    /// The PID of the process containing the thread.
+    pub pid: i32,
+    /// The system-wide thread id of the thread.
+    pub thread_id: i64,
+    /// When the token was created, in nanoseconds of `CLOCK_MONOTONIC`.
+    pub timestamp_ns: u64,
+    /// The HMAC-SHA256 of the other fields.
+    pub mac: [u8; 32],
+}
+
+#[cfg(feature = "secure-ipc")]
+impl IpcToken {
+    /// Serialize a token to a byte buffer, in native byte order.
+    pub fn serialize(&self) -> [u8; IPC_TOKEN_SIZE] {
+        let mut bytes = [0u8; IPC_TOKEN_SIZE];
+        bytes[0..4].copy_from_slice(&self.pid.to_ne_bytes());
+        bytes[4..12].copy_from_slice(&self.thread_id.to_ne_bytes());
+        bytes[12..20].copy_from_slice(&self.timestamp_ns.to_ne_bytes());
+        bytes[20..].copy_from_slice(&self.mac);
+        bytes
+    }
+
+    /// Get a token from a byte buffer written by `serialize`.
+    pub fn deserialize(bytes: [u8; IPC_TOKEN_SIZE]) -> IpcToken {
+        let mut mac = [0u8; 32];
+        mac.copy_from_slice(&bytes[20..]);
+        IpcToken {
+            pid: i32::from_ne_bytes(bytes[0..4].try_into().unwrap()),
+            thread_id: i64::from_ne_bytes(bytes[4..12].try_into().unwrap()),
+            timestamp_ns: u64::from_ne_bytes(bytes[12..20].try_into().unwrap()),
+            mac,
+        }
+    }
+}
+
+/// A W3C Trace Context (https://www.w3.org/TR/trace-context/), identifying the span of a
+/// real-time promotion, see `RtPriorityHandle::trace_context`.
+#[cfg(feature = "otel")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RtTraceContext {
+    /// The id of the trace the span belongs to.
+    pub trace_id: [u8; 16],
+    /// The id of the span.
+    pub span_id: [u8; 8],
+    /// The trace flags, `1` if the trace is sampled.
+    pub flags: u8,
+}
+
+#[cfg(feature = "otel")]
+impl RtTraceContext {
+    /// The `traceparent` header for this context, e.g.
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`, to propagate it to other
+    /// processes or to an OpenTelemetry SDK.
+    pub fn to_traceparent(&self) -> String {
+        let hex = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        };
+        format!(
+            "00-{}-{}-{:02x}",
+            hex(&self.trace_id),
+            hex(&self.span_id),
+            self.flags
+        )
+    }
+
+    /// Parse a version 00 `traceparent` header.
+    pub fn from_traceparent(traceparent: &str) -> Result<RtTraceContext, AudioThreadPriorityError> {
+        let error =
+            || AudioThreadPriorityError::new(&format!("invalid traceparent {}", traceparent));
+        let parse = |hex: &str, bytes: &mut [u8]| {
+            // from_str_radix also accepts a leading sign.
+            if hex.len() != bytes.len() * 2 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+                return Err(error());
+            }
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2).ok_or_else(error)?, 16)
+                    .map_err(|_| error())?;
+            }
+            Ok(())
+        };
+        let fields: Vec<&str> = traceparent.trim().split('-').collect();
+        if fields.len() != 4 || fields[0] != "00" {
+            return Err(error());
+        }
+        let mut context = RtTraceContext {
+            trace_id: [0; 16],
+            span_id: [0; 8],
+            flags: 0,
+        };
+        parse(fields[1], &mut context.trace_id)?;
+        parse(fields[2], &mut context.span_id)?;
+        let mut flags = [0u8];
+        parse(fields[3], &mut flags)?;
+        context.flags = flags[0];
+        // All-zero ids are invalid.
+        if context.trace_id == [0; 16] || context.span_id == [0; 8] {
+            return Err(error());
+        }
+        Ok(context)
+    }
+
+    /// A new span, child of `parent` if any, or the root span of a new sampled trace otherwise.
+    fn new_span(
+        parent: Option<&RtTraceContext>,
+    ) -> Result<RtTraceContext, AudioThreadPriorityError> {
+        let mut context = match parent {
+            Some(parent) => *parent,
+            None => {
+                let mut trace_id = [0u8; 16];
+                fill_random(&mut trace_id)?;
+                RtTraceContext {
+                    trace_id,
+                    span_id: [0; 8],
+                    flags: 1,
+                }
+            }
+        };
+        fill_random(&mut context.span_id)?;
+        Ok(context)
+    }
+}
+
+/// The span of a promotion, ended when the thread is demoted, or when the handle is dropped
+/// if it isn't demoted.
+#[cfg(feature = "otel")]
+struct TraceSpan {
+    context: RtTraceContext,
+    started_at: Instant,
+    /// The promoted thread.
+    thread_id: kernel_pid_t,
+    ended: AtomicBool,
+}
+
+#[cfg(feature = "otel")]
+impl TraceSpan {
+    /// Log the end of the span, the first time this is called.
+    fn end(&self, reason: &str) {
+        if self.ended.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        info!(
+            "Span {} ended after {}us: thread {} {}",
+            self.context.to_traceparent(),
+            self.started_at.elapsed().as_micros(),
+            self.thread_id,
+            reason
+        );
+    }
+}
+
+#[cfg(feature = "otel")]
+impl Drop for TraceSpan {
+    fn drop(&mut self) {
+        self.end("handle dropped without demotion");
+    }
+}
+
+/// A connection to RTKit, with the `MaxRealtimePriority` and `RTTimeUSecMax` properties read
+/// once, to promote threads without opening a connection and querying the properties each time.
+/// A session can be shared between threads, e.g. in an `Arc`: promotions made through the same
+/// session are serialized.
+///
+/// The properties are not updated if the RTKit configuration changes during the lifetime of the
+/// session.
+pub struct RtkitSession {
+    connection: Mutex<PooledConnection>,
+    max_realtime_priority: u32,
+    rttime_usec_max: u64,
+}
+
+impl RtkitSession {
+    /// Open a connection to the system bus, and read the properties of RTKit.
+    pub fn new() -> Result<RtkitSession, AudioThreadPriorityError> {
+        let connection = Connection::get_private(BusType::System)?;
+        let (max_prio, max_rttime) = {
+            let p = Props::new(
+                &connection,
+                RTKIT.name,
+                RTKIT.path,
+                RTKIT.interface,
+                DBUS_SOCKET_TIMEOUT,
+            );
+            (
+                item_as_i64(p.get("MaxRealtimePriority")?)?,
+                item_as_i64(p.get("RTTimeUSecMax")?)?,
+            )
+        };
+        if max_prio < 0 {
+            return Err(AudioThreadPriorityError::new(
+                "invalid negative MaxRealtimePriority",
+            ));
+        }
+        if max_rttime < 0 {
+            return Err(AudioThreadPriorityError::new(
+                "invalid negative RTTimeUSecMax",
+            ));
+        }
+        Ok(RtkitSession {
+            connection: Mutex::new(PooledConnection(connection)),
+            max_realtime_priority: max_prio as u32,
+            rttime_usec_max: max_rttime as u64,
+        })
+    }
+
+    /// The `MaxRealtimePriority` property of RTKit, when the session was opened.
+    pub fn max_realtime_priority(&self) -> u32 {
+        self.max_realtime_priority
+    }
+
+    /// The `RTTimeUSecMax` property of RTKit, when the session was opened, in microseconds.
+    pub fn rttime_usec_max(&self) -> u64 {
+        self.rttime_usec_max
+    }
+
+    /// Promote a thread (possibly in another process) to real-time, like
+    /// `promote_thread_to_real_time_with_request`, using this session.
+    pub fn promote_thread(
+        &self,
+        thread_info: RtPriorityThreadInfoInternal,
+        request: &RtPriorityRequest,
+    ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+        if request.audio_samplerate_hz == 0 {
+            return Err(AudioThreadPriorityError::new("sample rate is zero"));
+        }
+        let handle = promote_thread_with_service(&RTKIT, Some(self), thread_info, request)?;
+        #[cfg(debug_assertions)]
+        if let Err(e) = handle.assert_invariants() {
+            warn!("Inconsistent handle after promotion: {}", e);
+        }
+        Ok(handle)
+    }
+
+    /// Like `rtkit_set_realtime_in_group`, on the connection of this session.
+    fn set_realtime(
+        &self,
+        thread: u64,
+        pid: u64,
+        prio: u32,
+        group: Option<&str>,
+    ) -> Result<(), Box<dyn Error>> {
+        let m = rtkit_set_realtime_message(&RTKIT, thread, pid, prio, group)?;
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        connection
+            .0
+            .send_with_reply_and_block(m, DBUS_SOCKET_TIMEOUT)?;
+        Ok(())
+    }
+}
+
+thread_local! {
+    /// The D-Bus connections opened by this thread, kept to be reused by later calls, with the
+    /// process they were opened in.
+    static DBUS_CONNECTIONS: RefCell<Vec<(libc::pid_t, BusType, Connection)>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+/// Call `f` with a connection to `bus`, borrowed from the installed `DbusPool` for the system bus,
+/// or otherwise from the connections previously opened by the calling thread, opening one if
+/// needed.
+fn with_dbus_connection<T, E: From<dbus::Error>>(
+    bus: BusType,
+    f: impl FnOnce(&Connection) -> Result<T, E>,
+) -> Result<T, E> {
+    if let Some(pool) = DBUS_POOL.get() {
+        if bus == BusType::System && pool.pid == unsafe { libc::getpid() } {
+            return pool.with_connection(f);
+        }
+    }
+
+    // Take the connection out of the cache while it's in use, in case `f` needs another one.
+    let pid = unsafe { libc::getpid() };
+    let cached = DBUS_CONNECTIONS.with(|connections| {
+        let mut connections = connections.borrow_mut();
+        // After a fork, the child shares the sockets of the parent: RTKit would check the
+        // credentials of the parent, and both processes would write to the same stream. Closing
+        // them could also flush messages of the parent, leak them instead.
+        for (_, _, connection) in connections.extract_if(.., |(p, _, _)| *p != pid) {
+            std::mem::forget(connection);
+        }
+        let index = connections.iter().position(|(_, b, _)| *b == bus)?;
+        Some(connections.swap_remove(index).2)
+    });
+    // libdbus notices that a connection has been closed on the next I/O, checking is cheaper than
+    // a round-trip to the bus.
+    let connection = match cached {
+        Some(connection) if connection.is_connected() => connection,
+        _ => Connection::get_private(bus)?,
+    };
+    let r = f(&connection);
+    if connection.is_connected() {
+        DBUS_CONNECTIONS.with(|connections| connections.borrow_mut().push((pid, bus, connection)));
+    }
+    r
+}
+
+fn rtkit_set_realtime(
+    service: &RealtimeService,
+    thread: u64,
+    pid: u64,
+    prio: u32,
+) -> Result<(), Box<dyn Error>> {
+    rtkit_set_realtime_in_group(service, thread, pid, prio, None)
+}
+
+/// Like `rtkit_set_realtime`, passing `group` to RTKit as the `X-Group` entry of an extra `a{sv}`
+/// argument, see `RtPriorityRequest::for_pam_group`.
+fn rtkit_set_realtime_in_group(
+    service: &RealtimeService,
+    thread: u64,
+    pid: u64,
+    prio: u32,
+    group: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let m = rtkit_set_realtime_message(service, thread, pid, prio, group)?;
+    with_dbus_connection(service.bus, |c| -> Result<(), Box<dyn Error>> {
+        c.send_with_reply_and_block(m, DBUS_SOCKET_TIMEOUT)?;
+        Ok(())
+    })
+}
+
+/// The `MakeThreadRealtime` or `MakeThreadRealtimeWithPID` call sent by
+/// `rtkit_set_realtime_in_group`. The `a{sv}` argument is only added when `group` is set: stock
+/// RTKit rejects it, only a patched RTKit accepts it.
+fn rtkit_set_realtime_message(
+    service: &RealtimeService,
+    thread: u64,
+    pid: u64,
+    prio: u32,
+    group: Option<&str>,
+) -> Result<Message, Box<dyn Error>> {
+    let mut m = if service.has_make_thread_realtime && unsafe { libc::getpid() as u64 } == pid {
         let mut m = Message::new_method_call(
-            "org.freedesktop.RealtimeKit1",
-            "/org/freedesktop/RealtimeKit1",
-            "org.freedesktop.RealtimeKit1",
+            service.name,
+            service.path,
+            service.interface,
             "MakeThreadRealtime",
         )?;
         m.append_items(&[thread.into(), prio.into()]);
         m
     } else {
         let mut m = Message::new_method_call(
-            "org.freedesktop.RealtimeKit1",
-            "/org/freedesktop/RealtimeKit1",
-            "org.freedesktop.RealtimeKit1",
+            service.name,
+            service.path,
+            service.interface,
             "MakeThreadRealtimeWithPID",
         )?;
         m.append_items(&[pid.into(), thread.into(), prio.into()]);
         m
     };
-    let c = Connection::get_private(BusType::System)?;
-    c.send_with_reply_and_block(m, DBUS_SOCKET_TIMEOUT)?;
-    Ok(())
+    // Without a group, the call has the signature stock RTKit expects.
+    if let Some(group) = group {
+        let metadata = MessageItem::from_dict::<dbus::Error, _>(std::iter::once(Ok((
+            "X-Group".to_owned(),
+            MessageItem::Str(group.to_owned()),
+        ))))?;
+        m.append_items(&[metadata]);
+    }
+    Ok(m)
 }
 
 /// Returns the maximum priority, maximum real-time time slice, and the current real-time time
 /// slice for this process.
-fn get_limits() -> Result<(i64, u64, libc::rlimit), AudioThreadPriorityError> {
-    let c = Connection::get_private(BusType::System)?;
-
-    let p = Props::new(
-        &c,
-        "org.freedesktop.RealtimeKit1",
-        "/org/freedesktop/RealtimeKit1",
-        "org.freedesktop.RealtimeKit1",
-        DBUS_SOCKET_TIMEOUT,
-    );
+fn get_limits(
+    service: &RealtimeService,
+) -> Result<(i64, u64, libc::rlimit), AudioThreadPriorityError> {
+    let (max_prio, max_rttime) = with_dbus_connection(service.bus, |c| {
+        let p = Props::new(
+            c,
+            service.name,
+            service.path,
+            service.interface,
+            DBUS_SOCKET_TIMEOUT,
+        );
+        Ok::<_, AudioThreadPriorityError>((
+            item_as_i64(p.get("MaxRealtimePriority")?)?,
+            item_as_i64(p.get("RTTimeUSecMax")?)?,
+        ))
+    })?;
     let mut current_limit = libc::rlimit {
         rlim_cur: 0,
         rlim_max: 0,
     };
 
-    let max_prio = item_as_i64(p.get("MaxRealtimePriority")?)?;
     if max_prio < 0 {
         return Err(AudioThreadPriorityError::new(
             "invalid negative MaxRealtimePriority",
         ));
     }
 
-    let max_rttime = item_as_i64(p.get("RTTimeUSecMax")?)?;
     if max_rttime < 0 {
         return Err(AudioThreadPriorityError::new(
             "invalid negative RTTimeUSecMax",
@@ -152,56 +7848,242 @@ fn get_limits() -> Result<(i64, u64, libc::rlimit), AudioThreadPriorityError> {
     Ok((max_prio, (max_rttime as u64), current_limit))
 }
 
-fn set_limits(request: u64, max: u64) -> Result<(), AudioThreadPriorityError> {
-    // Set a soft limit to the limit requested, to be able to handle going over the limit using
-    // SIGXCPU. Set the hard limit to the maximum slice to prevent getting SIGKILL.
-    #[allow(clippy::useless_conversion)]
-    let new_limit = libc::rlimit {
-        rlim_cur: request
-            .try_into()
-            .map_err(|_| AudioThreadPriorityError::new("setrlimit"))?,
-        rlim_max: max
-            .try_into()
-            .map_err(|_| AudioThreadPriorityError::new("setrlimit"))?,
-    };
-    if unsafe { libc::setrlimit(libc::RLIMIT_RTTIME, &new_limit) } < 0 {
-        return Err(AudioThreadPriorityError::new_with_inner(
-            "setrlimit",
-            Box::new(OSError::last_os_error()),
-        ));
+#[cfg(any(feature = "cat", feature = "rdt"))]
+const RESCTRL_ROOT: &str = "/sys/fs/resctrl";
+
+/// The resctrl group of an Intel CAT class of service, following the naming used by `pqos`: the
+/// default class of service is the root group, the others are groups named `COS<n>`.
+#[cfg(feature = "cat")]
+fn cat_group_path(cos_id: u32) -> PathBuf {
+    if cos_id == 0 {
+        PathBuf::from(RESCTRL_ROOT)
+    } else {
+        Path::new(RESCTRL_ROOT).join(format!("COS{}", cos_id))
     }
+}
 
-    Ok(())
+#[cfg(any(feature = "cat", feature = "rdt"))]
+fn cat_group_contains(group: &Path, tid: kernel_pid_t) -> Result<bool, AudioThreadPriorityError> {
+    let tasks = fs::read_to_string(group.join("tasks"))
+        .map_err(|e| AudioThreadPriorityError::new_with_inner("resctrl tasks", Box::new(e)))?;
+    Ok(tasks.lines().any(|line| line.trim().parse() == Ok(tid)))
+}
+
+/// Find the resctrl group `tid` currently belongs to.
+#[cfg(any(feature = "cat", feature = "rdt"))]
+fn cat_group_of(tid: kernel_pid_t) -> Result<PathBuf, AudioThreadPriorityError> {
+    let entries = fs::read_dir(RESCTRL_ROOT)
+        .map_err(|e| AudioThreadPriorityError::new_with_inner("resctrl", Box::new(e)))?;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| AudioThreadPriorityError::new_with_inner("resctrl", Box::new(e)))?
+            .path();
+        // Skip `info`, `mon_data`, `mon_groups` and regular files, that aren't groups.
+        if path.is_dir() && path.join("tasks").exists() && cat_group_contains(&path, tid)? {
+            return Ok(path);
+        }
+    }
+    // Threads that aren't in any other group are in the root group.
+    Ok(PathBuf::from(RESCTRL_ROOT))
+}
+
+#[cfg(feature = "cat")]
+fn cat_move_to_group(group: &Path, tid: kernel_pid_t) -> Result<(), AudioThreadPriorityError> {
+    fs::write(group.join("tasks"), tid.to_string()).map_err(|e| {
+        AudioThreadPriorityError::new_with_inner("could not write resctrl tasks", Box::new(e))
+    })
+}
+
+/// The hard `RLIMIT_RTTIME` limit of the calling process, in microseconds.
+fn rttime_hard_limit_us() -> Result<u64, AudioThreadPriorityError> {
+    // rlim_t is not 64 bits on all platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(rttime_limit()?.rlim_max as u64)
+}
+
+/// Whether the system runs on battery, i.e. a power supply is discharging.
+fn on_battery() -> bool {
+    let supplies = match fs::read_dir("/sys/class/power_supply") {
+        Ok(supplies) => supplies,
+        Err(_) => return false,
+    };
+    supplies.filter_map(|entry| entry.ok()).any(|entry| {
+        fs::read_to_string(entry.path().join("status")).is_ok_and(|s| s.trim() == "Discharging")
+    })
 }
 
 pub fn promote_current_thread_to_real_time_internal(
-    audio_buffer_frames: u32,
-    audio_samplerate_hz: u32,
+    request: &RtPriorityRequest,
 ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+    if let Some(min_stack_bytes) = request.min_stack_bytes {
+        enforce_current_thread_stack_size(min_stack_bytes)?;
+    }
     let thread_info = get_current_thread_info_internal()?;
-    promote_thread_to_real_time_internal(thread_info, audio_buffer_frames, audio_samplerate_hz)
+    let mut handle = promote_thread_to_real_time_internal(thread_info, request)?;
+    if request.cache_warm_size_bytes > 0 {
+        warm_caches(request.cache_warm_size_bytes);
+    }
+    if request.measure_initial_jitter {
+        handle.initial_jitter_p99_us = Some(measure_scheduling_jitter_p99_us());
+    }
+    if request.budget_timer {
+        match BudgetTimer::new(handle.budget_us) {
+            Ok(timer) => handle.budget_timer = Some(timer),
+            Err(e) => warn!("Could not create the budget timer: {}", e),
+        }
+    }
+    Ok(handle)
+}
+
+/// The 1-minute load average, divided by the number of online CPUs.
+fn load_average_per_cpu() -> Result<f64, AudioThreadPriorityError> {
+    let loadavg = fs::read_to_string("/proc/loadavg")
+        .map_err(|e| AudioThreadPriorityError::new_with_inner("/proc/loadavg", Box::new(e)))?;
+    let load: f64 = loadavg
+        .split_whitespace()
+        .next()
+        .and_then(|load| load.parse().ok())
+        .ok_or_else(|| AudioThreadPriorityError::new("could not parse /proc/loadavg"))?;
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    Ok(load / cmp::max(cpus, 1) as f64)
+}
+
+/// Touch `size` bytes of memory, one cache line at a time, sequentially and then in a random
+/// order, so that the CPU caches are primed.
+fn warm_caches(size: usize) {
+    const CACHE_LINE_SIZE: usize = 64;
+    let mut buffer = vec![0u8; size];
+    for i in (0..size).step_by(CACHE_LINE_SIZE) {
+        buffer[i] = buffer[i].wrapping_add(1);
+    }
+    let lines = size.div_ceil(CACHE_LINE_SIZE);
+    // xorshift, this only needs to defeat the hardware prefetcher.
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    for _ in 0..lines {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let i = (state % lines as u64) as usize * CACHE_LINE_SIZE;
+        buffer[i] = buffer[i].wrapping_add(1);
+    }
+    std::hint::black_box(&buffer);
 }
 
 pub fn demote_current_thread_from_real_time_internal(
+    mut rt_priority_handle: RtPriorityHandleInternal,
+) -> Result<(), AudioThreadPriorityError> {
+    let thread_id = unsafe { libc::syscall(libc::SYS_gettid) };
+    assert!(
+        thread_id == rt_priority_handle.thread_info.thread_id,
+        "demoting thread {} with a handle for thread {}",
+        thread_id,
+        rt_priority_handle.thread_info.thread_id
+    );
+    // The pthread id can be stale if the handle has been reassigned, the thread id is enough to
+    // identify the thread.
+    let pthread_id = unsafe { libc::pthread_self() };
+    if pthread_id != rt_priority_handle.thread_info.pthread_id {
+        warn!(
+            "pthread id mismatch when demoting thread {}, using the current one.",
+            thread_id
+        );
+        rt_priority_handle.thread_info.pthread_id = pthread_id;
+    }
+
+    demote_with_force_internal(rt_priority_handle)
+}
+
+/// Demote the thread `rt_priority_handle` refers to, without checking that it is the calling
+/// thread.
+pub fn demote_with_force_internal(
     rt_priority_handle: RtPriorityHandleInternal,
 ) -> Result<(), AudioThreadPriorityError> {
-    assert!(unsafe { libc::pthread_self() } == rt_priority_handle.thread_info.pthread_id);
+    demote_handle(&rt_priority_handle)
+}
 
-    let param = unsafe { std::mem::zeroed::<libc::sched_param>() };
+/// Demote the thread `rt_priority_handle` refers to, and undo what was done when promoting it.
+/// If this fails, the thread is still real-time, and nothing has been undone.
+fn demote_handle(
+    rt_priority_handle: &RtPriorityHandleInternal,
+) -> Result<(), AudioThreadPriorityError> {
+    // The pthread id is stale if the thread has exited, and 0 for handles that were not created
+    // in this process: only the thread id is used. The scheduling is reset first, so that the
+    // thread isn't left real-time if restoring something else fails. The threads started by
+    // `freeze_thread_priority` are stopped first, so that they don't promote it again.
+    let mut demoted = rt_priority_handle
+        .demoted
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    *demoted = true;
+    if let Err(e) = reset_scheduler(rt_priority_handle.thread_info.thread_id) {
+        *demoted = false;
+        return Err(e);
+    }
+    drop(demoted);
+    #[cfg(feature = "prometheus-text")]
+    DEMOTION_COUNT.fetch_add(1, Ordering::Relaxed);
 
-    if unsafe {
-        libc::pthread_setschedparam(
-            rt_priority_handle.thread_info.pthread_id,
-            rt_priority_handle.thread_info.policy,
-            &param,
-        )
-    } < 0
-    {
-        return Err(AudioThreadPriorityError::new_with_inner(
-            "could not demote thread",
-            Box::new(OSError::last_os_error()),
-        ));
+    // The sockets could have been closed, or their fds reused, since the promotion.
+    for &(fd, priority) in &rt_priority_handle.original_socket_priorities {
+        if let Err(e) = set_socket_priority(fd, priority) {
+            warn!("Could not restore the priority of socket {}: {}", fd, e);
+        }
+    }
+
+    #[cfg(feature = "cat")]
+    if let Some(group) = &rt_priority_handle.previous_cat_group {
+        if let Err(e) = cat_move_to_group(group, rt_priority_handle.thread_info.thread_id) {
+            warn!("Could not restore the CAT class of service: {}", e);
+        }
     }
+
+    #[cfg(feature = "numa")]
+    if let Some(cpus) = &rt_priority_handle.original_affinity {
+        if let Err(e) = set_thread_affinity(rt_priority_handle.thread_info.thread_id, cpus) {
+            warn!("Could not restore the CPU affinity: {}", e);
+        }
+    }
+
+    #[cfg(feature = "numa")]
+    if let Some(util_max) = rt_priority_handle.original_util_clamp_max {
+        let tid = rt_priority_handle.thread_info.thread_id as libc::pid_t;
+        if let Err(e) = set_util_clamp_max(tid, util_max) {
+            warn!("Could not restore the utilization clamp: {}", e);
+        }
+    }
+
+    #[cfg(feature = "kdump-support")]
+    if let Err(e) = fs::remove_file(kdump_annotation_path(
+        rt_priority_handle.thread_info.pid,
+        rt_priority_handle.thread_info.thread_id as u64,
+    )) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Could not remove the kdump annotation: {}", e);
+        }
+    }
+
+    if rt_priority_handle.memory_locked_by_promotion && unsafe { libc::munlockall() } < 0 {
+        warn!("Could not unlock the memory: {}", OSError::last_os_error());
+    }
+
+    #[cfg(feature = "audit")]
+    if let Some(path) = &rt_priority_handle.audit_log_path {
+        audit_log(path, "demote", rt_priority_handle);
+    }
+
+    #[cfg(feature = "statsd")]
+    if let Some((addr, prefix)) = &rt_priority_handle.statsd {
+        statsd_send(addr, prefix, &["rt_demotion_count:1|c".to_string()]);
+    }
+
+    #[cfg(feature = "gdb-support")]
+    gdb_register_handle(rt_priority_handle, false);
+
+    #[cfg(feature = "otel")]
+    if let Some(span) = &rt_priority_handle.trace_span {
+        span.end("demoted");
+    }
+
     Ok(())
 }
 
@@ -209,17 +8091,43 @@ pub fn demote_current_thread_from_real_time_internal(
 pub fn demote_thread_from_real_time_internal(
     thread_info: RtPriorityThreadInfoInternal,
 ) -> Result<(), AudioThreadPriorityError> {
-    let param = unsafe { std::mem::zeroed::<libc::sched_param>() };
+    if !process_is_alive(thread_info.pid) {
+        return Err(AudioThreadPriorityError::new(&format!(
+            "process {} is not alive",
+            thread_info.pid
+        )));
+    }
 
-    // https://github.com/rust-lang/libc/issues/1511
-    const SCHED_RESET_ON_FORK: libc::c_int = 0x40000000;
+    let param = unsafe { std::mem::zeroed::<libc::sched_param>() };
 
-    if unsafe {
+    let rv = unsafe {
         libc::pthread_setschedparam(
             thread_info.pthread_id,
             libc::SCHED_OTHER | SCHED_RESET_ON_FORK,
             &param,
         )
+    };
+    match rv {
+        0 => Ok(()),
+        // E.g. when the process has dropped CAP_SYS_NICE since the promotion.
+        libc::EPERM => force_demote(thread_info.thread_id),
+        _ => Err(AudioThreadPriorityError::new_with_inner(
+            "could not demote thread",
+            Box::new(OSError::from_raw_os_error(rv)),
+        )),
+    }
+}
+
+/// Reset the scheduling policy of thread `tid` to `SCHED_OTHER` with `sched_setscheduler`, which
+/// only needs the thread id.
+fn reset_scheduler(tid: kernel_pid_t) -> Result<(), AudioThreadPriorityError> {
+    let param = unsafe { std::mem::zeroed::<libc::sched_param>() };
+    if unsafe {
+        libc::sched_setscheduler(
+            tid as libc::pid_t,
+            libc::SCHED_OTHER | SCHED_RESET_ON_FORK,
+            &param,
+        )
     } < 0
     {
         return Err(AudioThreadPriorityError::new_with_inner(
@@ -230,19 +8138,54 @@ pub fn demote_thread_from_real_time_internal(
     Ok(())
 }
 
+/// Demote thread `tid` with `sched_setscheduler`, which doesn't need a pthread id. If that isn't
+/// permitted either, the nice value of the thread is still reset with `setpriority`, but the
+/// thread keeps its real-time policy, and an error is returned.
+fn force_demote(tid: kernel_pid_t) -> Result<(), AudioThreadPriorityError> {
+    let e = match reset_scheduler(tid) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    warn!(
+        "Could not demote thread {} ({}), resetting its nice value anyway.",
+        tid, e
+    );
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, 0) } < 0 {
+        warn!(
+            "Could not reset the nice value of thread {}: {}",
+            tid,
+            OSError::last_os_error()
+        );
+    }
+    Err(e)
+}
+
 /// Get the current thread information, as an opaque struct, that can be serialized and sent
 /// accross processes. This is enough to capture the current state of the scheduling policy, and
 /// an identifier to have another thread promoted to real-time.
+///
+/// In case of error, the location of the caller is part of the error message.
+#[track_caller]
 pub fn get_current_thread_info_internal(
 ) -> Result<RtPriorityThreadInfoInternal, AudioThreadPriorityError> {
-    let thread_id = unsafe { libc::syscall(libc::SYS_gettid) };
+    let caller = std::panic::Location::caller();
+    let mut thread_id = unsafe { libc::syscall(libc::SYS_gettid) };
+    if thread_id < 0 {
+        // gettid can only fail when blocked by a seccomp filter returning an error.
+        thread_id = try_without_gettid().map_err(|e| {
+            AudioThreadPriorityError::new_with_inner(
+                &format!("gettid (called from {})", caller),
+                Box::new(e),
+            )
+        })?;
+    }
     let pthread_id = unsafe { libc::pthread_self() };
     let mut param = unsafe { std::mem::zeroed::<libc::sched_param>() };
     let mut policy = 0;
 
     if unsafe { libc::pthread_getschedparam(pthread_id, &mut policy, &mut param) } < 0 {
         return Err(AudioThreadPriorityError::new_with_inner(
-            "pthread_getschedparam",
+            &format!("pthread_getschedparam (called from {})", caller),
             Box::new(OSError::last_os_error()),
         ));
     }
@@ -257,6 +8200,21 @@ pub fn get_current_thread_info_internal(
     })
 }
 
+/// Get the thread id of the calling thread without the `gettid` system call, for sandboxes whose
+/// seccomp filter blocks it: `/proc/thread-self` is a symbolic link to `<pid>/task/<tid>`, and
+/// reading it only needs `readlink`.
+fn try_without_gettid() -> Result<kernel_pid_t, OSError> {
+    let link = fs::read_link("/proc/thread-self")?;
+    link.file_name()
+        .and_then(|tid| tid.to_str()?.parse().ok())
+        .ok_or_else(|| {
+            OSError::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid /proc/thread-self link {}", link.display()),
+            )
+        })
+}
+
 /// This set the RLIMIT_RTTIME resource to something other than "unlimited". It's necessary for the
 /// rtkit request to succeed, and needs to hapen in the child. We can't get the real limit here,
 /// because we don't have access to DBUS, so it is hardcoded to 200ms, which is the default in the
@@ -265,51 +8223,368 @@ pub fn set_real_time_hard_limit_internal(
     audio_buffer_frames: u32,
     audio_samplerate_hz: u32,
 ) -> Result<(), AudioThreadPriorityError> {
-    let buffer_frames = if audio_buffer_frames > 0 {
-        audio_buffer_frames
-    } else {
-        // 50ms slice. This "ought to be enough for anybody".
-        audio_samplerate_hz / 20
-    };
-    let budget_us = buffer_frames as u64 * 1_000_000 / audio_samplerate_hz as u64;
+    if audio_samplerate_hz == 0 {
+        return Err(AudioThreadPriorityError::new("sample rate is zero"));
+    }
+    set_real_time_limit(
+        &RTKIT,
+        buffer_duration_us(audio_buffer_frames, audio_samplerate_hz),
+    )?;
+    Ok(())
+}
 
+/// Set RLIMIT_RTTIME as described in `set_real_time_hard_limit_internal`, with a soft limit of
+/// `budget_us`, capped to what RTKit allows. Returns the soft limit that has been set, in
+/// microseconds.
+fn set_real_time_limit(
+    service: &RealtimeService,
+    budget_us: u64,
+) -> Result<u64, AudioThreadPriorityError> {
     // It's only necessary to set RLIMIT_RTTIME to something when in the child, skip it if it's a
     // remoting call.
-    let (_, max_rttime, _) = get_limits()?;
+    let (_, max_rttime, _) = get_limits(service)?;
+    set_real_time_limit_with_max(budget_us, max_rttime)
+}
 
+/// Like `set_real_time_limit`, with the `RTTimeUSecMax` property of the service already known.
+fn set_real_time_limit_with_max(
+    budget_us: u64,
+    max_rttime: u64,
+) -> Result<u64, AudioThreadPriorityError> {
     // Only take what we need, or cap at the system limit, no further.
     let rttime_request = cmp::min(budget_us, max_rttime);
     set_limits(rttime_request, max_rttime)?;
 
-    Ok(())
+    Ok(rttime_request)
 }
 
 /// Promote a thread (possibly in another process) identified by its tid, to real-time.
 pub fn promote_thread_to_real_time_internal(
     thread_info: RtPriorityThreadInfoInternal,
-    audio_buffer_frames: u32,
-    audio_samplerate_hz: u32,
+    request: &RtPriorityRequest,
+) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+    let handle = promote_thread_with_service(&RTKIT, None, thread_info, request)?;
+    #[cfg(debug_assertions)]
+    if let Err(e) = handle.assert_invariants() {
+        warn!("Inconsistent handle after promotion: {}", e);
+    }
+    Ok(handle)
+}
+
+/// Promote a thread (possibly in another process) identified by its tid, to real-time, via the
+/// realtime XDG Desktop Portal. This falls back to RTKit on the system bus if the portal is not
+/// available.
+#[cfg(feature = "pipewire-portal")]
+pub fn promote_via_pipewire_portal_internal(
+    thread_info: RtPriorityThreadInfoInternal,
+    request: &RtPriorityRequest,
+) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+    match promote_thread_with_service(&REALTIME_PORTAL, None, thread_info, request) {
+        Ok(handle) => Ok(handle),
+        Err(e) => {
+            warn!(
+                "Could not promote via the realtime portal ({}), trying RTKit.",
+                e
+            );
+            promote_thread_with_service(&RTKIT, None, thread_info, request)
+        }
+    }
+}
+
+/// Where `RtPriorityHandle::to_kdump_annotation` writes the annotation of a thread: in the
+/// runtime directory of the user, that only the user can write to.
+#[cfg(feature = "kdump-support")]
+fn kdump_annotation_path(pid: libc::pid_t, tid: u64) -> PathBuf {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(format!("/run/user/{}", unsafe { libc::geteuid() })));
+    runtime_dir.join(format!("audio_rt_{}_{}", pid, tid))
+}
+
+/// Check everything `promote_thread_with_service` checks, without promoting the thread nor
+/// changing `RLIMIT_RTTIME`.
+fn simulate_promotion(service: &RealtimeService, request: &RtPriorityRequest) -> DryRunResult {
+    let mut result = DryRunResult {
+        would_succeed: false,
+        effective_priority: RT_PRIO_DEFAULT,
+        effective_budget_us: 0,
+        limiting_factor: None,
+    };
+    if request.audio_samplerate_hz == 0 {
+        result.limiting_factor = Some("sample rate is zero".to_string());
+        return result;
+    }
+    if let Some(threshold) = request.max_load {
+        match load_average_per_cpu() {
+            Ok(load) if load > threshold => {
+                result.limiting_factor = Some(format!(
+                    "system overloaded: load {:.2} above {:.2}",
+                    load, threshold
+                ));
+                return result;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                result.limiting_factor = Some(format!("load average: {}", e));
+                return result;
+            }
+        }
+    }
+    let budget_us = match request_budget_us(request) {
+        Ok(budget_us) => budget_us,
+        Err(e) => {
+            result.limiting_factor = Some(format!("budget: {}", e));
+            return result;
+        }
+    };
+    result.effective_budget_us = budget_us;
+    let (max_prio, max_rttime, limits) = match get_limits(service) {
+        Ok(limits) => limits,
+        Err(e) => {
+            result.limiting_factor = Some(format!("RTKit limits: {}", e));
+            return result;
+        }
+    };
+    if max_prio < RT_PRIO_DEFAULT as i64 {
+        // RTKit refuses priorities above its maximum.
+        result.effective_priority = max_prio as u32;
+        result.limiting_factor = Some(format!(
+            "priority {} above MaxRealtimePriority {}",
+            RT_PRIO_DEFAULT, max_prio
+        ));
+        return result;
+    }
+    // Raising the hard limit requires CAP_SYS_RESOURCE.
+    #[allow(clippy::useless_conversion)]
+    let hard_limit: u64 = limits.rlim_max.try_into().unwrap_or(u64::MAX);
+    if limits.rlim_max != libc::RLIM_INFINITY
+        && hard_limit < max_rttime
+        && unsafe { libc::geteuid() } != 0
+    {
+        result.limiting_factor = Some(format!(
+            "RLIMIT_RTTIME hard limit {}us below RTTimeUSecMax {}us",
+            hard_limit, max_rttime
+        ));
+        return result;
+    }
+    result.would_succeed = true;
+    if budget_us > max_rttime {
+        result.effective_budget_us = max_rttime;
+        result.limiting_factor = Some(format!(
+            "budget {}us clamped to RTTimeUSecMax {}us",
+            budget_us, max_rttime
+        ));
+    }
+    result
+}
+
+/// Promote a thread via `service`, or via `session` if any, that is then a session of `service`.
+fn promote_thread_with_service(
+    service: &RealtimeService,
+    session: Option<&RtkitSession>,
+    thread_info: RtPriorityThreadInfoInternal,
+    request: &RtPriorityRequest,
 ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+    // The public functions check this too, but not all the callers of this function go through
+    // them.
+    if request.audio_samplerate_hz == 0 {
+        return Err(AudioThreadPriorityError::new("sample rate is zero"));
+    }
+    if let Some(threshold) = request.max_load {
+        let load = load_average_per_cpu()?;
+        if load > threshold {
+            return Err(AudioThreadPriorityError::new(&format!(
+                "system overloaded: load {:.2} above {:.2}",
+                load, threshold
+            )));
+        }
+    }
+    if request.dry_run {
+        return Err(AudioThreadPriorityError::new(&format!(
+            "dry run, nothing changed: {}",
+            simulate_promotion(service, request)
+        )));
+    }
     let RtPriorityThreadInfoInternal { pid, thread_id, .. } = thread_info;
 
-    let handle = RtPriorityHandleInternal { thread_info };
+    #[cfg(feature = "secure-ipc")]
+    match &request.ipc_token {
+        Some(token) => {
+            RtPriorityHandleInternal::validate_ipc_token(token)?;
+            // kernel_pid_t is not 64 bits on all platforms.
+            #[allow(clippy::unnecessary_cast)]
+            if token.pid != pid || token.thread_id != thread_id as i64 {
+                return Err(AudioThreadPriorityError::new(
+                    "the IPC token is for another thread",
+                ));
+            }
+        }
+        None if IPC_SECRET.get().is_some() && pid != unsafe { libc::getpid() } => {
+            return Err(AudioThreadPriorityError::new(
+                "an IPC token is required to promote a thread of another process",
+            ));
+        }
+        None => {}
+    }
+
+    let mut handle = RtPriorityHandleInternal::new(thread_info);
+
+    handle.latency_target_us =
+        buffer_duration_us(request.audio_buffer_frames, request.audio_samplerate_hz);
+    handle.dmb_on_entry = request.dmb_on_entry;
+    handle.request = Some(request.clone());
+    let max_rttime = match session {
+        Some(session) => session.rttime_usec_max,
+        None => get_limits(service)?.1,
+    };
+    let original_rttime_limit = rttime_limit()?;
+    handle.budget_us = set_real_time_limit_with_max(request_budget_us(request)?, max_rttime)?;
+
+    #[cfg(feature = "cat")]
+    if let Some(cos_id) = request.cat_cos_id {
+        let r = cat_group_of(thread_id).and_then(|previous_group| {
+            cat_move_to_group(&cat_group_path(cos_id), thread_id)?;
+            Ok(previous_group)
+        });
+        match r {
+            Ok(previous_group) => handle.previous_cat_group = Some(previous_group),
+            Err(e) => {
+                restore_rttime_limit(&original_rttime_limit);
+                return Err(e);
+            }
+        }
+    }
 
-    set_real_time_hard_limit_internal(audio_buffer_frames, audio_samplerate_hz)?;
+    // This is only used for diagnostics, don't fail the promotion if /proc is not available.
+    handle.major_faults_at_promotion = major_faults(&thread_info).unwrap_or(0);
+    handle.migrations_at_promotion = cpu_migrations(&thread_info).unwrap_or(0);
+    // Only available with CONFIG_SCHED_DEBUG.
+    handle.extended_info_at_promotion = ExtendedSchedulerInfo::read(&thread_info).ok();
+    handle.affinity_at_promotion = get_thread_affinity(thread_id).ok();
+    #[cfg(feature = "numa")]
+    {
+        handle.original_affinity = handle.affinity_at_promotion.clone();
+    }
+    handle.priority = RT_PRIO_DEFAULT;
+    for &(fd, priority) in &request.socket_priorities {
+        let original = get_socket_priority(fd);
+        let r = original.and_then(|original| {
+            set_socket_priority(fd, priority)?;
+            handle.original_socket_priorities.push((fd, original));
+            Ok(())
+        });
+        if let Err(e) = r {
+            restore_socket_priorities(&handle);
+            #[cfg(feature = "cat")]
+            if let Some(group) = &handle.previous_cat_group {
+                if let Err(e) = cat_move_to_group(group, thread_id) {
+                    warn!("Could not restore the CAT class of service: {}", e);
+                }
+            }
+            restore_rttime_limit(&original_rttime_limit);
+            return Err(e);
+        }
+    }
 
-    let r = rtkit_set_realtime(thread_id as u64, pid as u64, RT_PRIO_DEFAULT);
+    let (rtkit_pid, rtkit_thread_id) = if request.in_container {
+        container_namespace_ids(&thread_info)?
+    } else {
+        (pid as u64, thread_id as u64)
+    };
+    let r = match session {
+        Some(session) => session.set_realtime(
+            rtkit_thread_id,
+            rtkit_pid,
+            handle.priority,
+            request.pam_group.as_deref(),
+        ),
+        None => rtkit_set_realtime_in_group(
+            service,
+            rtkit_thread_id,
+            rtkit_pid,
+            handle.priority,
+            request.pam_group.as_deref(),
+        ),
+    };
 
     match r {
-        Ok(_) => Ok(handle),
-        Err(e) => {
-            let (_, _, limits) = get_limits()?;
-            if limits.rlim_cur != libc::RLIM_INFINITY
-                && unsafe { libc::setrlimit(libc::RLIMIT_RTTIME, &limits) } < 0
+        Ok(_) => {
+            handle.promoted_at = Some(Instant::now());
+            #[cfg(feature = "prometheus-text")]
+            PROMOTION_COUNT.fetch_add(1, Ordering::Relaxed);
+            // This is done once the thread is promoted, so that there is nothing to unlock if the
+            // promotion fails. `VmLck` is for the whole process: memory that was already locked
+            // is left locked on demotion.
+            if request.memory_lock {
+                handle.memory_lock_requested = true;
+                let was_locked = task_status_field(&thread_info, "VmLck").unwrap_or(0) > 0;
+                if pid != unsafe { libc::getpid() } {
+                    warn!("Can't lock the memory of process {}.", pid);
+                } else if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } < 0 {
+                    warn!("mlockall failed: {}", OSError::last_os_error());
+                } else {
+                    handle.memory_locked_by_promotion = !was_locked;
+                }
+            }
+            #[cfg(feature = "audit")]
+            if let Some(path) = &request.audit_log_path {
+                audit_log(path, "promote", &handle);
+                handle.audit_log_path = Some(path.clone());
+            }
+            #[cfg(feature = "statsd")]
+            if let Some((addr, prefix)) = &request.statsd {
+                statsd_send(
+                    addr,
+                    prefix,
+                    &[
+                        "rt_promotions:1|c".to_string(),
+                        format!("effective_priority:{}|g", handle.priority),
+                    ],
+                );
+                handle.statsd = request.statsd.clone();
+            }
+            // This is only used for diagnostics, and often not permitted.
+            #[cfg(feature = "perf-events")]
             {
-                return Err(AudioThreadPriorityError::new_with_inner(
-                    "setrlimit",
-                    Box::new(OSError::last_os_error()),
-                ));
+                handle.cache_counters = CacheCounters::open(thread_id).ok();
+            }
+            #[cfg(feature = "gdb-support")]
+            gdb_register_handle(&handle, true);
+            #[cfg(feature = "otel")]
+            match RtTraceContext::new_span(request.trace_parent.as_ref()) {
+                Ok(context) => {
+                    info!(
+                        "Span {} started: thread {} promoted",
+                        context.to_traceparent(),
+                        thread_id
+                    );
+                    handle.trace_span = Some(TraceSpan {
+                        context,
+                        started_at: Instant::now(),
+                        thread_id,
+                        ended: AtomicBool::new(false),
+                    });
+                }
+                Err(e) => warn!("Could not create the promotion span: {}", e),
             }
+            Ok(handle)
+        }
+        Err(e) => {
+            #[cfg(feature = "statsd")]
+            if let Some((addr, prefix)) = &request.statsd {
+                statsd_send(addr, prefix, &["rt_promotion_failures:1|c".to_string()]);
+            }
+            restore_socket_priorities(&handle);
+
+            #[cfg(feature = "cat")]
+            if let Some(group) = &handle.previous_cat_group {
+                if let Err(e) = cat_move_to_group(group, thread_id) {
+                    warn!("Could not restore the CAT class of service: {}", e);
+                }
+            }
+
+            restore_rttime_limit(&original_rttime_limit);
             Err(AudioThreadPriorityError::new_with_inner(
                 "Thread promotion error",
                 e,
@@ -317,3 +8592,482 @@ pub fn promote_thread_to_real_time_internal(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A handle for the calling thread, that isn't promoted.
+    fn current_thread_handle() -> RtPriorityHandleInternal {
+        let mut handle = RtPriorityHandleInternal::new(get_current_thread_info_internal().unwrap());
+        handle.priority = 10;
+        handle.budget_us = 5000;
+        handle
+    }
+
+    #[cfg(feature = "bincode-layout")]
+    #[test]
+    fn test_bincode_round_trip() {
+        let handle = current_thread_handle();
+        let bytes = handle.to_bincode_bytes().unwrap();
+        assert_eq!(bytes.len(), BINCODE_HANDLE_SIZE);
+        assert_eq!(&bytes[0..4], &handle.thread_info.pid.to_le_bytes());
+        let read = RtPriorityHandleInternal::from_bincode_bytes(&bytes).unwrap();
+        assert_eq!(read.as_raw_handle(), handle.as_raw_handle());
+        assert!(RtPriorityHandleInternal::from_bincode_bytes(&bytes[1..]).is_err());
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn test_trace_context() {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = RtTraceContext::from_traceparent(traceparent).unwrap();
+        assert_eq!(context.trace_id[0], 0x4b);
+        assert_eq!(context.span_id[7], 0xb7);
+        assert_eq!(context.flags, 1);
+        assert_eq!(context.to_traceparent(), traceparent);
+
+        let child = RtTraceContext::new_span(Some(&context)).unwrap();
+        assert_eq!(child.trace_id, context.trace_id);
+        assert_eq!(
+            RtTraceContext::from_traceparent(&child.to_traceparent()).unwrap(),
+            child
+        );
+
+        for invalid in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-+0f067aa0ba902b7-01",
+        ] {
+            assert!(
+                RtTraceContext::from_traceparent(invalid).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    // Needs RTKit, and a single-threaded process: run with `--ignored --test-threads=1`.
+    #[test]
+    #[ignore]
+    fn test_fork() {
+        let handle = current_thread_handle();
+        match handle.fork().unwrap() {
+            RtForkResult::Parent { parent, child } => {
+                assert_eq!(parent.thread_info.pid, unsafe { libc::getpid() });
+                assert_ne!(child.thread_info.pid, parent.thread_info.pid);
+                assert_eq!(child.priority, parent.priority);
+                let mut status = 0;
+                assert_eq!(
+                    unsafe { libc::waitpid(child.thread_info.pid, &mut status, 0) },
+                    child.thread_info.pid
+                );
+                assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+            }
+            RtForkResult::Child(child) => {
+                let promoted = child.thread_info.pid == unsafe { libc::getpid() }
+                    && unsafe { libc::sched_getscheduler(0) } & !SCHED_RESET_ON_FORK
+                        == libc::SCHED_RR;
+                unsafe { libc::_exit(if promoted { 0 } else { 2 }) };
+            }
+        }
+    }
+
+    // Forks: run with `--ignored --test-threads=1`.
+    #[test]
+    #[ignore]
+    fn test_fork_failure() {
+        let mut handle = current_thread_handle();
+        // Not a valid real-time priority, so the promotion of the child fails.
+        handle.priority = 100;
+        let error = match handle.fork() {
+            Ok(_) => panic!("the promotion of the child succeeded"),
+            Err(e) => e,
+        };
+        assert!(error.to_string().contains("could not promote"), "{}", error);
+        // The child has been reaped.
+        assert_eq!(
+            unsafe { libc::waitpid(-1, std::ptr::null_mut(), libc::WNOHANG) },
+            -1
+        );
+        assert_eq!(OSError::last_os_error().raw_os_error(), Some(libc::ECHILD));
+    }
+
+    #[test]
+    fn test_measure_dma_transfer_jitter() {
+        let handle = current_thread_handle();
+        let timer = handle.request_dedicated_timer_interrupt(2000).unwrap();
+        let stats = handle.measure_dma_transfer_jitter(timer.fd(), 5).unwrap();
+        // The timer isn't readable between its expirations.
+        assert!(stats.min_us >= 1000, "{:?}", stats);
+    }
+
+    #[test]
+    fn test_preemption_model_from_uname() {
+        assert_eq!(
+            preemption_model_from_uname("6.1.0-18-rt-amd64", "#1 SMP PREEMPT_RT Debian"),
+            KernelPreemptionModel::Realtime
+        );
+        assert_eq!(
+            preemption_model_from_uname("5.15.0-91-generic", "#101-Ubuntu SMP PREEMPT Tue"),
+            KernelPreemptionModel::Full
+        );
+        assert_eq!(
+            preemption_model_from_uname("6.8.0-40-generic", "#40-Ubuntu SMP PREEMPT_DYNAMIC Fri"),
+            KernelPreemptionModel::None
+        );
+        assert_eq!(
+            preemption_model_from_uname("6.1.0-18-amd64", "#1 SMP Debian 6.1.76-1"),
+            KernelPreemptionModel::None
+        );
+    }
+
+    #[test]
+    fn test_innermost_namespace_id() {
+        let status = "Name:\taudio\nTgid:\t4321\nPid:\t4325\nPPid:\t1\n\
+                      NStgid:\t4321\t17\nNSpid:\t4325\t21\t3\n";
+        assert_eq!(innermost_namespace_id(status, "NSpid"), Some(3));
+        assert_eq!(innermost_namespace_id(status, "NStgid"), Some(17));
+        assert_eq!(innermost_namespace_id("NSpid:\t42\n", "NSpid"), Some(42));
+        assert_eq!(innermost_namespace_id(status, "NSsid"), None);
+    }
+
+    #[test]
+    fn test_rtkit_set_realtime_message() {
+        let signatures = |group| {
+            rtkit_set_realtime_message(&RTKIT, 2, 1, 10, group)
+                .unwrap()
+                .get_items()
+                .iter()
+                .map(|item| item.signature().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(signatures(None), ["t", "t", "u"]);
+        assert_eq!(signatures(Some("audio")), ["t", "t", "u", "a{sv}"]);
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11\n").unwrap(),
+            vec![0, 1, 2, 3, 8, 10, 11]
+        );
+        assert_eq!(parse_cpu_list("5").unwrap(), vec![5]);
+        assert_eq!(parse_cpu_list("").unwrap(), Vec::<usize>::new());
+        for invalid in ["a", "1-", "-1", "1-b", "0,,x"] {
+            assert!(parse_cpu_list(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_json_escape() {
+        assert_eq!(json_escape("audio"), "audio");
+        assert_eq!(json_escape("a\"b\\c\nd"), "a\\\"b\\\\c\\u000ad");
+    }
+
+    #[test]
+    fn test_to_json_line() {
+        let report = RtReport {
+            thread_id: 1,
+            pid: 2,
+            label: "audio \"out\"".to_string(),
+            effective_priority: 10,
+            effective_budget_us: 5000,
+            time_since_promotion: None,
+            is_still_realtime: true,
+            involuntary_context_switches: 3,
+            major_faults: 0,
+            jitter_p99_us: Some(42),
+        };
+        assert_eq!(
+            report.to_json_line(),
+            "{\"thread_id\":1,\"pid\":2,\"label\":\"audio \\\"out\\\"\",\"effective_priority\":10,\
+             \"priority_band\":\"High (Real-time audio rendering)\",\"effective_budget_us\":5000,\
+             \"time_since_promotion_us\":null,\"is_still_realtime\":true,\
+             \"involuntary_context_switches\":3,\"major_faults\":0,\"jitter_p99_us\":42}"
+        );
+    }
+
+    #[test]
+    fn test_request_budget_us() {
+        // 10ms buffers.
+        let request = RtPriorityRequest::new(480, 48000);
+        assert_eq!(request_budget_us(&request).unwrap(), 10000);
+        // The default is 50ms buffers.
+        assert_eq!(
+            request_budget_us(&RtPriorityRequest::new(0, 48000)).unwrap(),
+            50000
+        );
+        // The I/O latency minus 20% of a buffer.
+        assert_eq!(
+            request_budget_us(&request.clone().with_io_latency_ms(20.0)).unwrap(),
+            18000
+        );
+        assert!(request_budget_us(&request.clone().with_io_latency_ms(1.0)).is_err());
+        assert!(request_budget_us(&request.with_io_latency_ms(f64::NAN)).is_err());
+    }
+
+    #[test]
+    fn test_priority_band_from_priority() {
+        for (priority, band) in [
+            (0, PriorityBand::Low),
+            (3, PriorityBand::Low),
+            (4, PriorityBand::Medium),
+            (8, PriorityBand::Medium),
+            (9, PriorityBand::High),
+            (15, PriorityBand::High),
+            (16, PriorityBand::Critical),
+            (20, PriorityBand::Critical),
+            (21, PriorityBand::Maximum),
+            (99, PriorityBand::Maximum),
+        ] {
+            assert_eq!(PriorityBand::from_priority(priority), band, "{}", priority);
+        }
+    }
+
+    #[test]
+    fn test_compute_audio_buffer_budget_us() {
+        let mut handle = current_thread_handle();
+        let format = AudioFormat {
+            sample_rate: 48000,
+            buffer_frames: 480,
+            channels: 2,
+            bit_depth: 16,
+        };
+        // 10ms, minus a 2ms margin, minus 1920 bytes at 100 bytes per microsecond.
+        assert_eq!(handle.compute_audio_buffer_budget_us(&format), 7980);
+        assert_eq!(
+            handle.compute_audio_buffer_budget_us(&AudioFormat {
+                sample_rate: 0,
+                ..format
+            }),
+            0
+        );
+        handle.set_budget_safety_fraction(1.0);
+        assert_eq!(handle.compute_audio_buffer_budget_us(&format), 0);
+    }
+
+    #[test]
+    fn test_atomic_handle_round_trip() {
+        let handle = current_thread_handle();
+        let atomic = AtomicRtPriorityHandle::new(&handle);
+        assert_eq!(atomic.snapshot(), handle.as_raw_handle());
+        atomic.set_priority_and_budget(20, 1000);
+        let raw = atomic.snapshot();
+        assert_eq!((raw.priority, raw.budget_us), (20, 1000));
+        atomic.store(&handle);
+        assert_eq!(atomic.snapshot(), handle.as_raw_handle());
+        assert_eq!(
+            atomic.serialize_atomic_snapshot(),
+            handle.serialize_atomic_snapshot()
+        );
+    }
+
+    #[test]
+    fn test_budget_as_frames() {
+        let mut handle = current_thread_handle();
+        assert_eq!(handle.budget_as_frames(48000), 240);
+        handle.budget_us = u64::MAX;
+        assert_eq!(handle.budget_as_frames(48000), u32::MAX);
+    }
+
+    #[test]
+    fn test_compute_missed_deadline_probability() {
+        // 5ms budget.
+        let handle = current_thread_handle();
+        let distribution = LatencyHistogram::new();
+        assert_eq!(
+            handle.compute_missed_deadline_probability(&distribution),
+            0.0
+        );
+        for _ in 0..99 {
+            distribution.record(100);
+        }
+        distribution.record(100_000);
+        assert_eq!(
+            handle.compute_missed_deadline_probability(&distribution),
+            0.01
+        );
+        // The bucket from 4096us to 8192us is assumed to be uniform, so 6000us has a
+        // (8192 - 5000) / 4096 chance to be over budget.
+        let distribution = LatencyHistogram::new();
+        distribution.record(6000);
+        assert_eq!(
+            handle.compute_missed_deadline_probability(&distribution),
+            (8192.0 - 5000.0) / 4096.0
+        );
+    }
+
+    #[test]
+    fn test_parse_smaps_locked_regions() {
+        let smaps = "\
+00400000-0040b000 r-xp 00000000 08:01 1234                       /usr/bin/my app
+Size:                 44 kB
+Locked:               44 kB
+7f0000000000-7f0000021000 rw-p 00000000 00:00 0
+Size:                132 kB
+Locked:               12 kB
+7f0000021000-7f0000042000 rw-p 00000000 00:00 0
+Size:                132 kB
+Locked:                0 kB
+7ffd00000000-7ffd00021000 rw-p 00000000 00:00 0                          [stack]
+Size:                132 kB
+Locked:              132 kB
+";
+        assert_eq!(
+            parse_smaps_locked_regions(smaps).unwrap(),
+            vec![
+                LockedRegion {
+                    start: 0x400000,
+                    end: 0x40b000,
+                    size_kb: 44,
+                    path: Some("/usr/bin/my app".to_string()),
+                },
+                LockedRegion {
+                    start: 0x7f0000000000,
+                    end: 0x7f0000021000,
+                    size_kb: 12,
+                    path: None,
+                },
+                LockedRegion {
+                    start: 0x7ffd00000000,
+                    end: 0x7ffd00021000,
+                    size_kb: 132,
+                    path: Some("[stack]".to_string()),
+                },
+            ]
+        );
+        assert!(parse_smaps_locked_regions("Locked: many kB\n").is_err());
+    }
+
+    #[test]
+    fn test_latency_histogram_buckets() {
+        let histogram = LatencyHistogram::new();
+        for (duration_us, bucket) in [
+            (0, 0),
+            (1, 0),
+            (2, 1),
+            (3, 2),
+            (4, 2),
+            (5, 3),
+            (1 << 30, 30),
+            ((1 << 30) + 1, 31),
+            (u64::MAX / 2, 31),
+        ] {
+            let before = histogram.buckets[bucket].load(Ordering::Relaxed);
+            histogram.record(duration_us);
+            assert_eq!(
+                histogram.buckets[bucket].load(Ordering::Relaxed),
+                before + 1,
+                "{}us",
+                duration_us
+            );
+        }
+        assert_eq!(histogram.count(), 9);
+    }
+
+    #[test]
+    fn test_latency_histogram_percentile() {
+        let histogram = LatencyHistogram::new();
+        assert_eq!(histogram.percentile(50.0), 0);
+        for _ in 0..90 {
+            histogram.record(100);
+        }
+        for _ in 0..9 {
+            histogram.record(1000);
+        }
+        histogram.record(1 << 40);
+        assert_eq!(histogram.percentile(0.0), 128);
+        assert_eq!(histogram.percentile(50.0), 128);
+        assert_eq!(histogram.percentile(90.0), 128);
+        assert_eq!(histogram.percentile(99.0), 1024);
+        assert_eq!(histogram.percentile(100.0), u64::MAX);
+    }
+
+    #[test]
+    fn test_latency_histogram_prometheus_text() {
+        let histogram = LatencyHistogram::new();
+        histogram.record(1);
+        histogram.record(3);
+        histogram.record(1 << 40);
+        let text = histogram.to_prometheus_text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "# HELP audio_callback_latency_us Latency of the audio callbacks, in microseconds."
+        );
+        assert_eq!(lines[1], "# TYPE audio_callback_latency_us histogram");
+        assert_eq!(lines[2], "audio_callback_latency_us_bucket{le=\"1\"} 1");
+        assert_eq!(lines[3], "audio_callback_latency_us_bucket{le=\"2\"} 1");
+        assert_eq!(lines[4], "audio_callback_latency_us_bucket{le=\"4\"} 2");
+        assert_eq!(
+            lines[2 + LATENCY_HISTOGRAM_BUCKETS - 2],
+            "audio_callback_latency_us_bucket{le=\"1073741824\"} 2"
+        );
+        assert_eq!(
+            lines[2 + LATENCY_HISTOGRAM_BUCKETS - 1],
+            "audio_callback_latency_us_bucket{le=\"+Inf\"} 3"
+        );
+        assert_eq!(
+            lines[2 + LATENCY_HISTOGRAM_BUCKETS],
+            format!("audio_callback_latency_us_sum {}", 4 + (1u64 << 40))
+        );
+        assert_eq!(
+            lines[3 + LATENCY_HISTOGRAM_BUCKETS],
+            "audio_callback_latency_us_count 3"
+        );
+        assert_eq!(lines.len(), 4 + LATENCY_HISTOGRAM_BUCKETS);
+    }
+
+    #[test]
+    fn test_mmap_round_trip() {
+        // u64 for the alignment.
+        let mut shm = [0u64; MMAP_HANDLE_SIZE / 8];
+        let ptr = shm.as_mut_ptr() as *mut u8;
+        assert!(unsafe { RtPriorityHandleInternal::deserialize_from_mmap(ptr, 0) }.is_err());
+
+        let handle = current_thread_handle();
+        for _ in 0..2 {
+            assert_eq!(
+                unsafe { handle.serialize_for_mmap(ptr, 0) },
+                MMAP_HANDLE_SIZE
+            );
+            let read = unsafe { RtPriorityHandleInternal::deserialize_from_mmap(ptr, 0) }.unwrap();
+            assert_eq!(read.as_raw_handle(), handle.as_raw_handle());
+        }
+        assert_eq!(unsafe { handle.serialize_for_mmap(ptr, 1) }, 0);
+
+        // A record being written is rejected.
+        let sequence = unsafe { &*(ptr.add(28) as *const AtomicU32) };
+        sequence.fetch_add(1, Ordering::Relaxed);
+        assert!(unsafe { RtPriorityHandleInternal::deserialize_from_mmap(ptr, 0) }.is_err());
+
+        let bytes = handle.serialize_atomic_snapshot();
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, MMAP_HANDLE_SIZE) };
+        let read = unsafe { RtPriorityHandleInternal::deserialize_from_mmap(ptr, 0) }.unwrap();
+        assert_eq!(read.as_raw_handle(), handle.as_raw_handle());
+    }
+
+    #[cfg(feature = "secure-ipc")]
+    #[test]
+    fn test_ipc_token() {
+        let handle = current_thread_handle();
+        // The secret can only be installed once per process.
+        let _ = IpcSecret::new().unwrap().install();
+        let secret = IPC_SECRET.get().unwrap();
+        let token = secret.create_token(&handle.thread_info);
+        RtPriorityHandleInternal::validate_ipc_token(&token).unwrap();
+        let forged = IpcToken {
+            thread_id: token.thread_id + 1,
+            ..token
+        };
+        assert!(RtPriorityHandleInternal::validate_ipc_token(&forged).is_err());
+        let mut tampered = token;
+        tampered.mac[0] ^= 1;
+        assert!(RtPriorityHandleInternal::validate_ipc_token(&tampered).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/* Promotion of the current thread with sched_setscheduler, without RTKit. This doesn't need D-Bus,
+ * and is built on Linux whether or not the dbus feature is enabled. */
+
+extern crate libc;
+
+use std::convert::TryInto;
+use std::fs;
+use std::io::Error as OSError;
+
+use log::warn;
+
+use crate::AudioThreadPriorityError;
+use crate::RtPriorityRequest;
+
+// https://github.com/rust-lang/libc/issues/1511
+pub(crate) const SCHED_RESET_ON_FORK: libc::c_int = 0x40000000;
+/// The real-time priority threads are promoted to with `set_sched_fifo`.
+pub(crate) const RT_PRIO_DEFAULT: u32 = 10;
+/// Fraction of the duration of an audio buffer kept as a safety margin when the real-time budget
+/// is derived from the I/O latency, see `RtPriorityRequest::with_io_latency_ms`.
+pub(crate) const IO_LATENCY_SAFETY_MARGIN: f64 = 0.2;
+
+/// A thread promoted by `promote_current_thread`, and what to restore when demoting it.
+#[cfg(not(feature = "dbus"))]
+#[derive(Debug)]
+pub(crate) struct DirectPromotion {
+    tid: libc::pid_t,
+    original_rttime_limit: libc::rlimit,
+}
+
+/// Promote the current thread to `SCHED_FIFO` with `sched_setscheduler`, with a soft
+/// `RLIMIT_RTTIME` derived from `request`, and set its timer slack to 1ns. `RLIMIT_RTTIME` is
+/// restored if the promotion fails.
+#[cfg(not(feature = "dbus"))]
+pub(crate) fn promote_current_thread(
+    request: &RtPriorityRequest,
+) -> Result<DirectPromotion, AudioThreadPriorityError> {
+    if request.audio_samplerate_hz == 0 {
+        return Err(AudioThreadPriorityError::new("sample rate is zero"));
+    }
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+    let original_rttime_limit = rttime_limit()?;
+    #[allow(clippy::useless_conversion)]
+    let max_rttime: u64 = original_rttime_limit.rlim_max.into();
+    set_limits(request_budget_us(request)?.min(max_rttime), max_rttime)?;
+    if let Err(e) = set_sched_fifo(tid, RT_PRIO_DEFAULT) {
+        restore_rttime_limit(&original_rttime_limit);
+        return Err(e);
+    }
+    set_min_timer_slack();
+    Ok(DirectPromotion {
+        tid,
+        original_rttime_limit,
+    })
+}
+
+/// Undo `promote_current_thread`: switch the thread back to `SCHED_OTHER`, and restore
+/// `RLIMIT_RTTIME`.
+#[cfg(not(feature = "dbus"))]
+pub(crate) fn demote(promotion: &DirectPromotion) -> Result<(), AudioThreadPriorityError> {
+    let param = unsafe { std::mem::zeroed::<libc::sched_param>() };
+    if unsafe {
+        libc::sched_setscheduler(
+            promotion.tid,
+            libc::SCHED_OTHER | SCHED_RESET_ON_FORK,
+            &param,
+        )
+    } < 0
+    {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "sched_setscheduler",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    restore_rttime_limit(&promotion.original_rttime_limit);
+    Ok(())
+}
+
+/// Switch thread `tid` to `SCHED_FIFO` at `priority` with `sched_setscheduler`. If this isn't
+/// permitted, the error message contains the `setcap` command to grant `CAP_SYS_NICE` to the
+/// executable.
+pub(crate) fn set_sched_fifo(
+    tid: libc::pid_t,
+    priority: u32,
+) -> Result<(), AudioThreadPriorityError> {
+    let param = libc::sched_param {
+        sched_priority: priority as libc::c_int,
+    };
+    if unsafe { libc::sched_setscheduler(tid, libc::SCHED_FIFO | SCHED_RESET_ON_FORK, &param) } < 0
+    {
+        let error = OSError::last_os_error();
+        if error.raw_os_error() == Some(libc::EPERM) {
+            return Err(AudioThreadPriorityError::new_with_inner(
+                &format!("sched_setscheduler: {}", setcap_hint()),
+                Box::new(error),
+            ));
+        }
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "sched_setscheduler",
+            Box::new(error),
+        ));
+    }
+    Ok(())
+}
+
+/// Set the timer slack of the calling thread to 1ns, for more precise wake-ups. Failures are
+/// logged.
+pub(crate) fn set_min_timer_slack() {
+    if unsafe { libc::prctl(libc::PR_SET_TIMERSLACK, 1 as libc::c_ulong) } < 0 {
+        warn!(
+            "Could not set the timer slack: {}",
+            OSError::last_os_error()
+        );
+    }
+}
+
+/// A hint on how to grant the current executable the capability needed to promote threads
+/// without RTKit.
+pub(crate) fn setcap_hint() -> String {
+    let exe = fs::read_link("/proc/self/exe")
+        .map(|exe| exe.display().to_string())
+        .unwrap_or_else(|_| "/path/to/binary".to_string());
+    format!(
+        "To use direct RT promotion, run: sudo setcap cap_sys_nice=eip {}",
+        exe
+    )
+}
+
+/// The `RLIMIT_RTTIME` limits of the calling process.
+pub(crate) fn rttime_limit() -> Result<libc::rlimit, AudioThreadPriorityError> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_RTTIME, &mut limit) } < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "getrlimit",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok(limit)
+}
+
+/// Restore the `RLIMIT_RTTIME` limits of the calling process to `limit`, after a failed
+/// promotion. Failures are logged.
+pub(crate) fn restore_rttime_limit(limit: &libc::rlimit) {
+    if unsafe { libc::setrlimit(libc::RLIMIT_RTTIME, limit) } < 0 {
+        warn!(
+            "Could not restore RLIMIT_RTTIME: {}",
+            OSError::last_os_error()
+        );
+    }
+}
+
+pub(crate) fn set_limits(request: u64, max: u64) -> Result<(), AudioThreadPriorityError> {
+    // Set a soft limit to the limit requested, to be able to handle going over the limit using
+    // SIGXCPU. Set the hard limit to the maximum slice to prevent getting SIGKILL.
+    #[allow(clippy::useless_conversion)]
+    let new_limit = libc::rlimit {
+        rlim_cur: request
+            .try_into()
+            .map_err(|_| AudioThreadPriorityError::new("setrlimit"))?,
+        rlim_max: max
+            .try_into()
+            .map_err(|_| AudioThreadPriorityError::new("setrlimit"))?,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_RTTIME, &new_limit) } < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            "setrlimit",
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+
+    Ok(())
+}
+
+/// The duration of an audio buffer, in microseconds.
+pub(crate) fn buffer_duration_us(audio_buffer_frames: u32, audio_samplerate_hz: u32) -> u64 {
+    let buffer_frames = if audio_buffer_frames > 0 {
+        audio_buffer_frames
+    } else {
+        // 50ms slice. This "ought to be enough for anybody".
+        audio_samplerate_hz / 20
+    };
+    buffer_frames as u64 * 1_000_000 / audio_samplerate_hz as u64
+}
+
+/// The real-time budget to request for a promotion, in microseconds.
+///
+/// This is the duration of an audio buffer, or, if the I/O latency is known, the I/O latency minus
+/// a safety margin of 20% of the duration of an audio buffer.
+pub(crate) fn request_budget_us(
+    request: &RtPriorityRequest,
+) -> Result<u64, AudioThreadPriorityError> {
+    let buffer_us = buffer_duration_us(request.audio_buffer_frames, request.audio_samplerate_hz);
+    let io_latency_ms = match request.io_latency_ms {
+        Some(io_latency_ms) => io_latency_ms,
+        None => return Ok(buffer_us),
+    };
+    let safety_margin_us = buffer_us as f64 * IO_LATENCY_SAFETY_MARGIN;
+    let budget_us = io_latency_ms * 1000.0 - safety_margin_us;
+    if budget_us.is_nan() || budget_us < 1.0 {
+        return Err(AudioThreadPriorityError::new(&format!(
+            "I/O latency of {}ms too short for a {}us safety margin",
+            io_latency_ms, safety_margin_us
+        )));
+    }
+    Ok(budget_us as u64)
+}
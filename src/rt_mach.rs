@@ -1,5 +1,7 @@
 use crate::mach_sys::*;
 use crate::AudioThreadPriorityError;
+use crate::PriorityClass;
+use crate::RtPriorityRequest;
 use libc::{pthread_self, pthread_t};
 use log::info;
 use mach::kern_return::{kern_return_t, KERN_SUCCESS};
@@ -57,6 +59,11 @@ impl RtPriorityHandleInternal {
             },
         }
     }
+
+    /// A handle only exists for a thread that has been promoted, and is consumed on demotion.
+    pub fn priority_class(&self) -> PriorityClass {
+        PriorityClass::Realtime
+    }
 }
 
 pub fn demote_current_thread_from_real_time_internal(
@@ -83,9 +90,13 @@ pub fn demote_current_thread_from_real_time_internal(
 }
 
 pub fn promote_current_thread_to_real_time_internal(
-    audio_buffer_frames: u32,
-    audio_samplerate_hz: u32,
+    request: &RtPriorityRequest,
 ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+    let RtPriorityRequest {
+        audio_buffer_frames,
+        audio_samplerate_hz,
+        ..
+    } = *request;
     let mut rt_priority_handle = RtPriorityHandleInternal::new();
 
     let buffer_frames = if audio_buffer_frames > 0 {
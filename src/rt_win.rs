@@ -2,18 +2,68 @@ use windows_sys::s;
 use windows_sys::Win32::Foundation::GetLastError;
 use windows_sys::Win32::Foundation::FALSE;
 use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::Media::{timeBeginPeriod, timeEndPeriod, TIMERR_NOERROR};
 use windows_sys::Win32::System::Threading::{
     AvRevertMmThreadCharacteristics, AvSetMmThreadCharacteristicsA,
 };
 
 use crate::AudioThreadPriorityError;
+use crate::PriorityClass;
+use crate::RtPriorityRequest;
 
-use log::info;
+use log::{info, warn};
+
+/// Timer resolution requested when `RtPriorityRequest::high_resolution_timer` is set.
+const HIGH_RESOLUTION_TIMER_PERIOD_MS: u32 = 1;
+
+/// A request for a minimum system timer resolution, via `timeBeginPeriod`. The request is
+/// cancelled with `timeEndPeriod` when this is dropped.
+///
+/// The timer resolution is a system-wide setting: while any process holds a request for a 1ms
+/// period, the timer interrupt fires more often for the whole system, which prevents the CPU from
+/// staying in low-power states and has a noticeable impact on battery life. Only hold a request for
+/// as long as low-latency audio is being processed.
+#[derive(Debug)]
+pub struct WindowsTimerResolutionGuard {
+    period_ms: u32,
+}
+
+impl WindowsTimerResolutionGuard {
+    /// Request a system timer resolution of `period_ms` milliseconds.
+    pub fn request(
+        period_ms: u32,
+    ) -> Result<WindowsTimerResolutionGuard, AudioThreadPriorityError> {
+        let rv = unsafe { timeBeginPeriod(period_ms) };
+        if rv != TIMERR_NOERROR {
+            return Err(AudioThreadPriorityError::new(&format!(
+                "Unable to set the timer resolution to {}ms ({:?})",
+                period_ms, rv
+            )));
+        }
+
+        info!("timer resolution set to {}ms.", period_ms);
+
+        Ok(WindowsTimerResolutionGuard { period_ms })
+    }
+}
+
+impl Drop for WindowsTimerResolutionGuard {
+    fn drop(&mut self) {
+        let rv = unsafe { timeEndPeriod(self.period_ms) };
+        if rv != TIMERR_NOERROR {
+            warn!(
+                "Unable to restore the timer resolution after {}ms ({:?})",
+                self.period_ms, rv
+            );
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct RtPriorityHandleInternal {
     mmcss_task_index: u32,
     task_handle: HANDLE,
+    timer_resolution: Option<WindowsTimerResolutionGuard>,
 }
 
 impl RtPriorityHandleInternal {
@@ -21,8 +71,15 @@ impl RtPriorityHandleInternal {
         RtPriorityHandleInternal {
             mmcss_task_index,
             task_handle,
+            timer_resolution: None,
         }
     }
+
+    /// A handle only exists for a thread that has been registered with MMCSS, and is consumed on
+    /// demotion.
+    pub fn priority_class(&self) -> PriorityClass {
+        PriorityClass::Realtime
+    }
 }
 
 pub fn demote_current_thread_from_real_time_internal(
@@ -45,13 +102,22 @@ pub fn demote_current_thread_from_real_time_internal(
 }
 
 pub fn promote_current_thread_to_real_time_internal(
-    _audio_buffer_frames: u32,
-    _audio_samplerate_hz: u32,
+    request: &RtPriorityRequest,
 ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+    // Request the timer resolution first, it is released if registering with MMCSS fails.
+    let timer_resolution = if request.high_resolution_timer {
+        Some(WindowsTimerResolutionGuard::request(
+            HIGH_RESOLUTION_TIMER_PERIOD_MS,
+        )?)
+    } else {
+        None
+    };
+
     let mut task_index = 0u32;
 
     let handle = unsafe { AvSetMmThreadCharacteristicsA(s!("Audio"), &mut task_index) };
-    let handle = RtPriorityHandleInternal::new(task_index, handle);
+    let mut handle = RtPriorityHandleInternal::new(task_index, handle);
+    handle.timer_resolution = timer_resolution;
 
     if handle.task_handle == 0 {
         return Err(AudioThreadPriorityError::new(&format!(
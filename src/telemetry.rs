@@ -0,0 +1,128 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+
+/// A wait-free single-producer, single-consumer ring buffer, to report telemetry from a real-time
+/// thread without taking locks or allocating.
+///
+/// Samples are written with `write_lock_free_sample` on the real-time thread, and read with
+/// `drain_samples` on another thread. Only one thread can write (resp. drain) at a time: a
+/// concurrent write (resp. drain) is dropped (resp. yields nothing) instead of blocking.
+pub struct RtTelemetryBuffer<T: Copy> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Index of the next slot to write, only modified by the producer.
+    head: AtomicUsize,
+    /// Index of the next slot to read, only modified by the consumer.
+    tail: AtomicUsize,
+    producing: AtomicBool,
+    consuming: AtomicBool,
+}
+
+// The producer and the consumer never access the same slot at the same time, and only one
+// producer and one consumer can be active at a time.
+unsafe impl<T: Copy + Send> Sync for RtTelemetryBuffer<T> {}
+unsafe impl<T: Copy + Send> Send for RtTelemetryBuffer<T> {}
+
+impl<T: Copy> RtTelemetryBuffer<T> {
+    /// Create a buffer that can hold up to `capacity` samples that haven't been drained yet.
+    ///
+    /// This allocates, and should not be called on a real-time thread.
+    pub fn new(capacity: usize) -> RtTelemetryBuffer<T> {
+        // One slot is always kept empty to tell a full buffer from an empty one.
+        let slots = (0..capacity + 1)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect();
+        RtTelemetryBuffer {
+            slots,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            producing: AtomicBool::new(false),
+            consuming: AtomicBool::new(false),
+        }
+    }
+
+    /// The maximum number of samples the buffer can hold.
+    pub fn capacity(&self) -> usize {
+        self.slots.len() - 1
+    }
+
+    /// Write a sample, without blocking. This is meant to be called from the real-time thread.
+    ///
+    /// Returns `false` if the sample was dropped, because the buffer is full or because another
+    /// thread is writing at the same time.
+    pub fn write_lock_free_sample(&self, value: T) -> bool {
+        if self.producing.swap(true, Ordering::Acquire) {
+            return false;
+        }
+        let head = self.head.load(Ordering::Relaxed);
+        let next = (head + 1) % self.slots.len();
+        let written = if next == self.tail.load(Ordering::Relaxed) {
+            false
+        } else {
+            // Make sure the consumer is done reading the slot before overwriting it.
+            fence(Ordering::Acquire);
+            unsafe { (*self.slots[head].get()).as_mut_ptr().write(value) };
+            // Make sure the sample is visible before the consumer sees the new head.
+            fence(Ordering::Release);
+            self.head.store(next, Ordering::Relaxed);
+            true
+        };
+        self.producing.store(false, Ordering::Release);
+        written
+    }
+
+    /// Read the samples written so far, in order. This is meant to be called from a non-real-time
+    /// thread.
+    ///
+    /// The samples are removed from the buffer as the iterator advances. If another thread is
+    /// draining the buffer at the same time, the iterator yields nothing.
+    pub fn drain_samples(&self) -> impl Iterator<Item = T> + '_ {
+        let active = !self.consuming.swap(true, Ordering::Acquire);
+        Drain {
+            buffer: self,
+            active,
+        }
+    }
+}
+
+struct Drain<'a, T: Copy> {
+    buffer: &'a RtTelemetryBuffer<T>,
+    /// Whether this iterator is the only consumer.
+    active: bool,
+}
+
+impl<'a, T: Copy> Iterator for Drain<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if !self.active {
+            return None;
+        }
+        let buffer = self.buffer;
+        let tail = buffer.tail.load(Ordering::Relaxed);
+        if tail == buffer.head.load(Ordering::Relaxed) {
+            return None;
+        }
+        // Make sure the sample written by the producer is visible.
+        fence(Ordering::Acquire);
+        let value = unsafe { (*buffer.slots[tail].get()).as_ptr().read() };
+        // Make sure the slot has been read before the producer sees the new tail.
+        fence(Ordering::Release);
+        buffer
+            .tail
+            .store((tail + 1) % buffer.slots.len(), Ordering::Relaxed);
+        Some(value)
+    }
+}
+
+impl<'a, T: Copy> Drop for Drain<'a, T> {
+    fn drop(&mut self) {
+        if self.active {
+            self.buffer.consuming.store(false, Ordering::Release);
+        }
+    }
+}